@@ -0,0 +1,541 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use common::SecretString;
+use crypto::gpg::Signature;
+use serde_json::{Value, json};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use transport::ChatEvent;
+
+use crate::PgpStatus;
+
+/// On-disk layout of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per message
+    JsonLines,
+    /// mboxrd, one mail per message
+    Mbox,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "jsonl" | "json" => Some(Format::JsonLines),
+            "mbox" => Some(Format::Mbox),
+            _ => None,
+        }
+    }
+
+    /// Guess from the file extension, JSON lines unless it's `.mbox`
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("mbox") => Format::Mbox,
+            _ => Format::JsonLines,
+        }
+    }
+}
+
+/// Decrypt result stored next to an archived PGP message
+#[derive(Debug, Clone)]
+pub struct PgpRecord {
+    pub id: String,
+    pub status: PgpStatus,
+    pub signer: Option<Signature>,
+    pub plaintext: Option<SecretString>,
+}
+
+/// An archived message, the original content is always kept
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub message: ChatEvent,
+    /// One per encrypted block, in message order
+    pub pgp: Vec<PgpRecord>,
+}
+
+/// Decrypt the PGP blocks of `messages` (concurrently) into records
+pub async fn records(
+    messages: &[ChatEvent],
+    gpg: &crypto::gpg_async::Gpg,
+    secrets: &crypto::gpg_async::Secrets,
+) -> Vec<Record> {
+    let jobs: Vec<Vec<_>> = messages
+        .iter()
+        .map(|m| {
+            crypto::armored_blocks(&m.content)
+                .filter(|a| a.kind == crypto::ArmorKind::Message)
+                .map(|crypto::Armored { index, block, .. }| {
+                    let gpg = gpg.clone();
+                    let secrets = secrets.clone();
+                    (
+                        crypto::pgp_block_id(m.message_id, index),
+                        tokio::spawn(async move { gpg.decrypt(&block, &secrets).await }),
+                    )
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut out = Vec::with_capacity(messages.len());
+    for (m, jobs) in messages.iter().zip(jobs) {
+        let mut pgp = Vec::with_capacity(jobs.len());
+        for (id, job) in jobs {
+            let res = job
+                .await
+                .unwrap_or_else(|e| Err(crypto::gpg::DecryptError::Io(e.to_string())));
+            let status = PgpStatus::of(&res);
+            let (signer, plaintext) = match res {
+                Ok(d) => (d.signature, Some(d.plaintext)),
+                Err(_) => (None, None),
+            };
+            pgp.push(PgpRecord {
+                id,
+                status,
+                signer,
+                plaintext,
+            });
+        }
+        out.push(Record {
+            message: m.clone(),
+            pgp,
+        });
+    }
+    out
+}
+
+/// Write `records` to a new file at `path`, readable by the owner only.
+/// Plaintext is left out unless `with_plaintext`.
+pub fn write(path: &Path, format: Format, records: &[Record], with_plaintext: bool) -> Result<()> {
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut f = opts
+        .open(path)
+        .map_err(|e| anyhow!("Failed to create {}: {e}", path.display()))?;
+
+    let mut out = SecretString::default();
+    for r in records {
+        match format {
+            Format::JsonLines => {
+                let _ = writeln!(out, "{}", json_record(r, with_plaintext));
+            }
+            Format::Mbox => out.push_str(&mbox_record(r, with_plaintext)),
+        }
+    }
+
+    f.write_all(out.as_bytes())
+        .and_then(|_| f.sync_all())
+        .map_err(|e| anyhow!("Failed to write {}: {e}", path.display()))
+}
+
+/// Messages stored in an archive, in file order
+pub fn read(path: &Path) -> Result<Vec<ChatEvent>> {
+    let text =
+        fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {e}", path.display()))?;
+
+    if text.starts_with("From ") {
+        read_mbox(&text)
+    } else {
+        read_json_lines(&text)
+    }
+}
+
+fn utc(unix: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(unix, 0).unwrap_or_default()
+}
+
+fn json_record(r: &Record, with_plaintext: bool) -> Value {
+    let m = &r.message;
+    json!({
+        "v": 1,
+        // ids as strings, they don't fit in a JSON double
+        "message_id": m.message_id.to_string(),
+        "channel_id": m.channel_id.to_string(),
+        "author_id": m.author_id.to_string(),
+        "author": m.author,
+        "timestamp": utc(m.timestamp).to_rfc3339(),
+        "content": m.content,
+        "pgp": r.pgp.iter().map(|p| json!({
+            "id": p.id,
+            "status": p.status.as_str(),
+            "signature": p.signer.as_ref().map(|s| json!({
+                "valid": matches!(s, Signature::Good { .. }),
+                "key_id": s.key_id(),
+                "signer": s.signer(),
+            })),
+            "plaintext": p.plaintext.as_deref().filter(|_| with_plaintext),
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn read_json_lines(text: &str) -> Result<Vec<ChatEvent>> {
+    fn id(v: &Value, key: &str, line: usize) -> Result<u64> {
+        match &v[key] {
+            Value::String(s) => s.parse().ok(),
+            Value::Number(n) => n.as_u64(),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("line {line}: missing or bad {key}"))
+    }
+
+    let mut out = Vec::new();
+    for (i, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let n = i + 1;
+        let v: Value =
+            serde_json::from_str(line).map_err(|e| anyhow!("line {n}: not JSON: {e}"))?;
+
+        let timestamp = v["timestamp"]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.timestamp())
+            .ok_or_else(|| anyhow!("line {n}: missing or bad timestamp"))?;
+
+        out.push(ChatEvent {
+            message_id: id(&v, "message_id", n)?,
+            channel_id: id(&v, "channel_id", n)?,
+            author_id: id(&v, "author_id", n).unwrap_or(0),
+            author: v["author"].as_str().unwrap_or("").to_string(),
+            content: v["content"]
+                .as_str()
+                .ok_or_else(|| anyhow!("line {n}: missing content"))?
+                .to_string(),
+            timestamp,
+            mentions: Vec::new(),
+        });
+    }
+    Ok(out)
+}
+
+/// mboxrd quoting: `From ` lines, quoted or not, get one more `>`
+fn mbox_escape(body: &str) -> String {
+    body.split('\n')
+        .map(|l| match l.trim_start_matches('>').starts_with("From ") {
+            true => format!(">{l}"),
+            false => l.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn mbox_unescape(body: &str) -> String {
+    body.split('\n')
+        .map(
+            |l| match l.starts_with('>') && l.trim_start_matches('>').starts_with("From ") {
+                true => &l[1..],
+                false => l,
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn mbox_record(r: &Record, with_plaintext: bool) -> SecretString {
+    let m = &r.message;
+    let at = utc(m.timestamp);
+
+    let mut s = SecretString::new(format!(
+        "From pgp-disc {}\n",
+        at.format("%a %b %e %H:%M:%S %Y")
+    ));
+    s.push_str(&format!("Message-ID: <{}@discord>\n", m.message_id));
+    s.push_str(&format!("Date: {}\n", at.to_rfc2822()));
+    s.push_str(&format!(
+        "From: \"{}\" <{}@discord>\n",
+        m.author.replace(['"', '\\'], ""),
+        m.author_id
+    ));
+    s.push_str(&format!("X-Discord-Channel: {}\n", m.channel_id));
+    s.push_str("MIME-Version: 1.0\n");
+
+    let plaintext: Vec<&SecretString> = r
+        .pgp
+        .iter()
+        .filter_map(|p| p.plaintext.as_ref())
+        .filter(|_| with_plaintext)
+        .collect();
+
+    for p in &r.pgp {
+        s.push_str(&format!("X-PGP-Id: {}\n", p.id));
+        s.push_str(&format!("X-PGP-Status: {}\n", p.status.as_str()));
+        if let Some(sig) = &p.signer {
+            let kind = match sig {
+                Signature::Good { .. } => "good",
+                Signature::Bad { .. } => "bad",
+                Signature::UnknownKey { .. } => "unknown-key",
+            };
+            s.push_str(&format!(
+                "X-PGP-Signature: {kind} {} {}\n",
+                sig.key_id(),
+                sig.signer()
+            ));
+        }
+    }
+
+    if plaintext.is_empty() {
+        s.push_str("Content-Type: text/plain; charset=utf-8\n\n");
+        s.push_str(&mbox_escape(&m.content));
+    } else {
+        let boundary = format!("pgp-disc-{}", m.message_id);
+        s.push_str(&format!(
+            "Content-Type: multipart/mixed; boundary=\"{boundary}\"\n\n"
+        ));
+        let parts = std::iter::once(("original", m.content.as_str()))
+            .chain(plaintext.iter().map(|pt| ("decrypted", pt.as_str())));
+        for (desc, body) in parts {
+            let body = SecretString::new(mbox_escape(body));
+            let _ = write!(
+                s,
+                "--{boundary}\nContent-Type: text/plain; charset=utf-8\n\
+                 Content-Description: {desc}\n\n{}\n",
+                body.as_str()
+            );
+        }
+        s.push_str(&format!("--{boundary}--"));
+    }
+
+    // end of the last line, then the blank line before the next `From `
+    s.push_str("\n\n");
+    s
+}
+
+fn read_mbox(text: &str) -> Result<Vec<ChatEvent>> {
+    let mut mails: Vec<String> = Vec::new();
+    for line in text.split_inclusive('\n') {
+        if line.starts_with("From ") {
+            mails.push(String::new());
+        } else if let Some(m) = mails.last_mut() {
+            m.push_str(line);
+        }
+    }
+
+    let mut out = Vec::new();
+    for (i, mail) in mails.iter().enumerate() {
+        let n = i + 1;
+        let (head, body) = mail
+            .split_once("\n\n")
+            .ok_or_else(|| anyhow!("message {n}: no header/body separator"))?;
+        let body = body.strip_suffix("\n\n").unwrap_or(body);
+
+        let header = |name: &str| {
+            head.lines().find_map(|l| {
+                let (k, v) = l.split_once(':')?;
+                k.eq_ignore_ascii_case(name).then(|| v.trim())
+            })
+        };
+        let angle_id = |name: &str| -> Option<u64> {
+            let v = header(name)?;
+            let inner = &v[v.find('<')? + 1..];
+            inner[..inner.find('@')?].parse().ok()
+        };
+
+        let message_id =
+            angle_id("Message-ID").ok_or_else(|| anyhow!("message {n}: bad Message-ID"))?;
+        let author = header("From")
+            .and_then(|f| f.split('<').next())
+            .map(|a| a.trim().trim_matches('"').to_string())
+            .unwrap_or_default();
+
+        let content = match header("Content-Type")
+            .and_then(|ct| ct.split("boundary=\"").nth(1))
+            .and_then(|b| b.split('"').next())
+        {
+            None => body.to_string(),
+            Some(boundary) => {
+                let first = format!("--{boundary}\n");
+                let part = body
+                    .strip_prefix(&first)
+                    .and_then(|p| p.split(&format!("\n--{boundary}")).next())
+                    .ok_or_else(|| anyhow!("message {n}: bad multipart body"))?;
+                part.split_once("\n\n")
+                    .map(|(_, b)| b.to_string())
+                    .unwrap_or_default()
+            }
+        };
+
+        out.push(ChatEvent {
+            message_id,
+            channel_id: header("X-Discord-Channel")
+                .and_then(|c| c.parse().ok())
+                .ok_or_else(|| anyhow!("message {n}: bad X-Discord-Channel"))?,
+            author_id: angle_id("From").unwrap_or(0),
+            author,
+            content: mbox_unescape(&content),
+            timestamp: header("Date")
+                .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+                .map(|d| d.timestamp())
+                .ok_or_else(|| anyhow!("message {n}: bad Date"))?,
+            mentions: Vec::new(),
+        });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(message_id: u64, content: &str) -> ChatEvent {
+        ChatEvent {
+            message_id,
+            channel_id: 10,
+            author_id: 7,
+            author: "bob".to_string(),
+            content: content.to_string(),
+            timestamp: 1_700_000_000 + message_id as i64,
+            mentions: Vec::new(),
+        }
+    }
+
+    fn decrypted(id: &str, plaintext: &str) -> PgpRecord {
+        PgpRecord {
+            id: id.to_string(),
+            status: PgpStatus::Decrypted,
+            signer: None,
+            plaintext: Some(SecretString::new(plaintext.to_string())),
+        }
+    }
+
+    fn fields(m: &ChatEvent) -> (u64, u64, u64, &str, &str, i64) {
+        (
+            m.message_id,
+            m.channel_id,
+            m.author_id,
+            &m.author,
+            &m.content,
+            m.timestamp,
+        )
+    }
+
+    /// `records` written in `format` and read back, with the file text
+    fn round_trip(
+        format: Format,
+        records: &[Record],
+        with_plaintext: bool,
+    ) -> (Vec<ChatEvent>, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive");
+        write(&path, format, records, with_plaintext).unwrap();
+        (read(&path).unwrap(), fs::read_to_string(&path).unwrap())
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        let records = vec![
+            Record {
+                message: message(1, "hello\n  \"quoted\" ✓"),
+                pgp: Vec::new(),
+            },
+            Record {
+                message: message(u64::MAX, "-----BEGIN PGP MESSAGE-----"),
+                pgp: vec![decrypted("abc", "secret")],
+            },
+        ];
+
+        let (read, text) = round_trip(Format::JsonLines, &records, false);
+        assert_eq!(read.len(), 2);
+        for (r, m) in records.iter().zip(&read) {
+            assert_eq!(fields(&r.message), fields(m));
+        }
+        assert!(!text.contains("secret"));
+
+        let (_, text) = round_trip(Format::JsonLines, &records, true);
+        assert!(text.contains("\"plaintext\":\"secret\""));
+    }
+
+    #[test]
+    fn mbox_escapes_from_lines() {
+        let content = "From the start\nhi\n>From quoted\n>>From twice\nFrom";
+        assert_eq!(
+            mbox_escape(content),
+            ">From the start\nhi\n>>From quoted\n>>>From twice\nFrom"
+        );
+        assert_eq!(mbox_unescape(&mbox_escape(content)), content);
+
+        let records = vec![
+            Record {
+                message: message(1, content),
+                pgp: Vec::new(),
+            },
+            Record {
+                message: message(2, "second"),
+                pgp: Vec::new(),
+            },
+        ];
+        let (read, text) = round_trip(Format::Mbox, &records, false);
+        assert_eq!(text.matches("\nFrom pgp-disc ").count(), 1);
+        assert_eq!(read.len(), 2);
+        for (r, m) in records.iter().zip(&read) {
+            assert_eq!(fields(&r.message), fields(m));
+        }
+    }
+
+    #[test]
+    fn multipart_keeps_the_original_and_every_plaintext() {
+        let records = vec![Record {
+            message: message(1, "two blocks"),
+            pgp: vec![decrypted("a", "first"), decrypted("b", "From second")],
+        }];
+
+        let (read, text) = round_trip(Format::Mbox, &records, true);
+        assert!(text.contains("multipart/mixed"));
+        assert_eq!(text.matches("Content-Description: decrypted").count(), 2);
+        assert!(text.contains("\n>From second\n"));
+        assert_eq!(text.matches("X-PGP-Id: ").count(), 2);
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].content, "two blocks");
+
+        // without plaintext it's a single part
+        let (_, text) = round_trip(Format::Mbox, &records, false);
+        assert!(!text.contains("multipart"));
+        assert!(!text.contains("first"));
+    }
+
+    #[test]
+    fn malformed_input_names_the_line() {
+        let ok = r#"{"message_id":"1","channel_id":"2","timestamp":"2024-01-01T00:00:00Z","content":"x"}"#;
+        assert_eq!(read_json_lines(&format!("\n{ok}\n\n")).unwrap().len(), 1);
+
+        for (text, err) in [
+            (format!("{ok}\nnot json"), "line 2: not JSON"),
+            (
+                r#"{"channel_id":"2","timestamp":"2024-01-01T00:00:00Z","content":"x"}"#.into(),
+                "line 1: missing or bad message_id",
+            ),
+            (
+                ok.replace("2024-01-01T00:00:00Z", "yesterday"),
+                "line 1: missing or bad timestamp",
+            ),
+            (
+                ok.replace(r#","content":"x""#, ""),
+                "line 1: missing content",
+            ),
+        ] {
+            let e = read_json_lines(&text).unwrap_err().to_string();
+            assert!(e.starts_with(err), "{e}");
+        }
+
+        let e = read_mbox("From pgp-disc\nMessage-ID: <1@discord>\n")
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("message 1: no header/body separator"), "{e}");
+    }
+
+    #[test]
+    fn write_never_replaces_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.jsonl");
+        fs::write(&path, "kept").unwrap();
+        assert!(write(&path, Format::JsonLines, &[], false).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept");
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::{Result, anyhow};
+
+/// What input history keeps of `line`: the message of `pgp send`,
+/// `session send` and `group send` is replaced with `<redacted>`, flags
+/// and ids stay
+pub fn history_entry(line: &str) -> String {
+    let mut args = Args::new(line);
+    let (cmd, sub) = match (args.next(), args.next()) {
+        (Ok(Some(cmd)), Ok(Some(sub))) => (cmd, sub),
+        _ => return line.to_string(),
+    };
+    match (cmd.as_str(), sub.as_str()) {
+        ("pgp", "send") => skip_send_flags(&mut args),
+        ("session", "send") => {
+            let _ = args.next();
+        }
+        ("group", "send") => {}
+        _ => return line.to_string(),
+    }
+
+    let kept = line[..line.len() - args.rest.len()].trim_end();
+    match args.rest.trim().is_empty() {
+        true => kept.to_string(),
+        false => format!("{kept} <redacted>"),
+    }
+}
+
+fn skip_send_flags(args: &mut Args) {
+    while let Some(flag) = args.peek_flag() {
+        match flag.as_str() {
+            "-r" | "-R" => {
+                let _ = args.next();
+                let _ = args.next();
+            }
+            "--symmetric" | "-c" | "--throw-keyids" | "--pad" | "-p" | "--compact" | "-z"
+            | "--code" | "-b" | "--" => {
+                let _ = args.next();
+            }
+            _ => break,
+        }
+        if flag == "--" {
+            break;
+        }
+    }
+}
+
+/// Shell-like argument reader over a command line.
+///
+/// Words support single quotes, double quotes and backslash escapes.
+/// Message payloads are taken verbatim with [`Args::rest`], so spacing,
+/// tabs and quotes inside them are never touched.
+#[derive(Clone, Copy, Debug)]
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self { rest: line }
+    }
+
+    /// Next word, unquoted and unescaped
+    pub fn next(&mut self) -> Result<Option<String>> {
+        let s = self.rest.trim_start();
+        if s.is_empty() {
+            self.rest = s;
+            return Ok(None);
+        }
+
+        let mut word = String::new();
+        let mut chars = s.char_indices();
+        let mut end = s.len();
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                c if c.is_whitespace() => {
+                    end = i;
+                    break;
+                }
+                '\'' => loop {
+                    match chars.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => word.push(c),
+                        None => return Err(anyhow!("Unterminated ' quote")),
+                    }
+                },
+                '"' => loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => word.push(c),
+                            None => return Err(anyhow!("Unterminated \" quote")),
+                        },
+                        Some((_, c)) => word.push(c),
+                        None => return Err(anyhow!("Unterminated \" quote")),
+                    }
+                },
+                '\\' => match chars.next() {
+                    Some((_, c)) => word.push(c),
+                    None => return Err(anyhow!("Trailing backslash")),
+                },
+                c => word.push(c),
+            }
+        }
+
+        self.rest = &s[end..];
+        Ok(Some(word))
+    }
+
+    /// Next word without consuming it
+    pub fn peek(&self) -> Result<Option<String>> {
+        let mut probe = *self;
+        probe.next()
+    }
+
+    /// Next word if it's a flag, i.e. starts with `-` as typed. Anything
+    /// else ends the flags without being tokenized, so a message like
+    /// `it's fine` is still taken verbatim by [`Args::rest`].
+    pub fn peek_flag(&self) -> Option<String> {
+        if !self.rest.trim_start().starts_with('-') {
+            return None;
+        }
+        self.peek().ok().flatten()
+    }
+
+    /// Next word or a usage error
+    pub fn expect(&mut self, usage: &str) -> Result<String> {
+        self.next()?.ok_or_else(|| anyhow!("{usage}"))
+    }
+
+    /// Everything after the next separator, exactly as typed
+    pub fn rest(self) -> &'a str {
+        let s = self.rest;
+        match s.chars().next() {
+            Some(c) if c.is_whitespace() => &s[c.len_utf8()..],
+            _ => s,
+        }
+    }
+
+    /// A single (possibly quoted) value, or the trimmed remainder
+    /// when it spans several words, e.g. `Alice Smith`
+    pub fn value(self) -> Result<String> {
+        let mut probe = self;
+        let first = probe.next()?;
+        if probe.next()?.is_none() {
+            return Ok(first.unwrap_or_default());
+        }
+        Ok(self.rest.trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_rest(line: &str) -> Result<&str> {
+        let mut args = Args::new(line);
+        args.next()?;
+        args.next()?;
+        while let Some(flag) = args.peek_flag() {
+            match flag.as_str() {
+                "-r" => {
+                    args.next()?;
+                    args.expect("usage")?;
+                }
+                "--pad" => {
+                    args.next()?;
+                }
+                "--" => {
+                    args.next()?;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        Ok(args.rest())
+    }
+
+    #[test]
+    fn words_and_quotes() {
+        let mut args = Args::new(r#"a 'b c' "d \"e\"" f\ g"#);
+        assert_eq!(args.next().unwrap().as_deref(), Some("a"));
+        assert_eq!(args.next().unwrap().as_deref(), Some("b c"));
+        assert_eq!(args.next().unwrap().as_deref(), Some(r#"d "e""#));
+        assert_eq!(args.next().unwrap().as_deref(), Some("f g"));
+        assert_eq!(args.next().unwrap(), None);
+        assert!(Args::new("it's").next().is_err());
+    }
+
+    #[test]
+    fn apostrophe_in_first_word_is_sent_verbatim() {
+        assert_eq!(send_rest("pgp send it's fine").unwrap(), "it's fine");
+        assert_eq!(
+            send_rest("pgp send --pad don't  \"quote\" me").unwrap(),
+            "don't  \"quote\" me"
+        );
+        assert_eq!(
+            send_rest("pgp send -r 'Alice Smith' it's fine").unwrap(),
+            "it's fine"
+        );
+        assert_eq!(
+            send_rest("pgp send -- -r isn't a flag").unwrap(),
+            "-r isn't a flag"
+        );
+        // a dash word that isn't a flag starts the message
+        assert_eq!(send_rest("pgp send -that's all").unwrap(), "-that's all");
+    }
+
+    #[test]
+    fn flags_only_start_with_a_dash() {
+        assert_eq!(Args::new("  it's").peek_flag(), None);
+        assert_eq!(Args::new("word -r").peek_flag(), None);
+        assert_eq!(Args::new(" --pad x").peek_flag().as_deref(), Some("--pad"));
+    }
+
+    #[test]
+    fn history_redacts_messages_with_quotes() {
+        assert_eq!(history_entry("pgp send it's fine"), "pgp send <redacted>");
+        assert_eq!(
+            history_entry("pgp send -r alice --pad it's fine"),
+            "pgp send -r alice --pad <redacted>"
+        );
+        assert_eq!(
+            history_entry("session send 0123 don't"),
+            "session send 0123 <redacted>"
+        );
+        assert_eq!(history_entry("group send 'quoted"), "group send <redacted>");
+        assert_eq!(history_entry("keys"), "keys");
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{Result, anyhow};
+use common::SecretString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Editor from `$VISUAL` / `$EDITOR`
+pub fn editor() -> Option<String> {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|v| std::env::var(v).ok())
+        .find(|e| !e.trim().is_empty())
+}
+
+/// Private directory for drafts, preferring memory-backed filesystems
+fn private_dir() -> Result<PathBuf> {
+    let base = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|p| p.is_dir())
+        .or_else(|| Some(PathBuf::from("/dev/shm")).filter(|p| p.is_dir()))
+        .unwrap_or_else(std::env::temp_dir);
+
+    let dir = base.join(format!("pgp-disc-{}", std::process::id()));
+
+    let mut b = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        b.mode(0o700);
+    }
+    b.create(&dir)
+        .map_err(|e| anyhow!("Failed to create {}: {e}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// Overwrite a file with zeros before unlinking it
+fn wipe_file(path: &Path) {
+    if let Ok(meta) = fs::metadata(path)
+        && let Ok(mut f) = fs::OpenOptions::new().write(true).open(path)
+    {
+        let zeros = vec![0u8; meta.len() as usize];
+        let _ = f.write_all(&zeros);
+        let _ = f.sync_all();
+    }
+    let _ = fs::remove_file(path);
+}
+
+/// Wipe everything the editor left behind (swap, backup files) and the dir
+fn wipe_dir(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for e in entries.flatten() {
+            let p = e.path();
+            if p.is_dir() {
+                wipe_dir(&p);
+            } else {
+                wipe_file(&p);
+            }
+        }
+    }
+    let _ = fs::remove_dir(dir);
+}
+
+/// Open `editor` on an empty draft and return its content.
+/// `None` if the draft was left empty.
+pub fn edit(editor: &str) -> Result<Option<SecretString>> {
+    let dir = private_dir()?;
+    let res = edit_in(&dir, editor);
+    wipe_dir(&dir);
+    res
+}
+
+fn edit_in(dir: &Path, editor: &str) -> Result<Option<SecretString>> {
+    let path = dir.join("message.txt");
+
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(&path)
+        .map_err(|e| anyhow!("Failed to create draft: {e}"))?;
+
+    // through sh so `$EDITOR` may carry arguments (e.g. "code -w")
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&path)
+        .status()
+        .map_err(|e| anyhow!("Failed to run editor: {e}"))?;
+
+    if !status.success() {
+        return Err(anyhow!("Editor exited with {status}, message discarded"));
+    }
+
+    let mut text = SecretString::new(
+        fs::read_to_string(&path).map_err(|e| anyhow!("Failed to read draft: {e}"))?,
+    );
+
+    // editors terminate the last line, that newline isn't part of the message
+    if text.ends_with('\n') {
+        text.pop();
+        if text.ends_with('\r') {
+            text.pop();
+        }
+    }
+
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(text))
+}
@@ -0,0 +1,178 @@
+use chrono::{DateTime, Local};
+use common::{DecryptPolicy, SecretString};
+use crypto::ArmorKind;
+use crypto::gpg::{PublicKey, Signature};
+
+use crate::groups::GroupInfo;
+use crate::search::Hit;
+use crate::sessions::SessionInfo;
+use crate::{CapturedPgp, PgpStatus};
+
+/// What commands and incoming messages produce, before any formatting.
+/// Turned into text by a [`crate::render::Renderer`].
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connected {
+        channel_id: u64,
+    },
+    IncomingPlain {
+        at: DateTime<Local>,
+        channel_id: u64,
+        author: String,
+        content: String,
+    },
+    IncomingPgp {
+        at: DateTime<Local>,
+        channel_id: u64,
+        author: String,
+        id: String,
+        status: PgpStatus,
+        signer: Option<Signature>,
+        plaintext: Option<SecretString>,
+        /// Decrypted, but the plaintext waits for `pgp show`
+        hidden: bool,
+        /// Our key that opened it, when the recipients were hidden
+        hidden_to: Option<String>,
+        /// Armor problem that kept the block from gpg
+        error: Option<String>,
+    },
+    /// Cleartext signed, `text` is `None` when it couldn't be verified
+    IncomingSigned {
+        at: DateTime<Local>,
+        channel_id: u64,
+        author: String,
+        id: String,
+        signer: Option<Signature>,
+        text: Option<String>,
+        error: Option<String>,
+    },
+    /// A key or detached signature posted in chat
+    IncomingArmor {
+        at: DateTime<Local>,
+        channel_id: u64,
+        author: String,
+        id: String,
+        kind: ArmorKind,
+    },
+    /// A message of a forward-secret session, `id` is its block id
+    IncomingSession {
+        at: DateTime<Local>,
+        channel_id: u64,
+        author: String,
+        id: String,
+        session: String,
+        plaintext: Option<SecretString>,
+        error: Option<String>,
+    },
+    /// Result of an explicit `pgp decrypt` or `pgp show`
+    Decrypt {
+        id: String,
+        status: PgpStatus,
+        signer: Option<Signature>,
+        plaintext: Option<SecretString>,
+        /// Our key that opened it, when the recipients were hidden
+        hidden_to: Option<String>,
+        /// Seconds until the plaintext is cleared from the view
+        hide_after: Option<u64>,
+    },
+    Sent {
+        channel_id: u64,
+    },
+    /// Clearsigned message sent
+    Signed {
+        channel_id: u64,
+    },
+    /// `to` is the recipient uid, `None` for passphrase encryption
+    SentPgp {
+        channel_id: u64,
+        to: Option<String>,
+        /// Key id left out of the message
+        hidden: bool,
+    },
+    SentSession {
+        channel_id: u64,
+        session: String,
+        to: String,
+    },
+    Sessions(Vec<SessionInfo>),
+    /// Encrypted under the group key of `epoch`
+    SentGroup {
+        channel_id: u64,
+        group: String,
+        epoch: u32,
+        members: usize,
+    },
+    /// `group show`, `None` when the channel has no group
+    Group {
+        channel_id: u64,
+        info: Option<GroupInfo>,
+    },
+    Exported {
+        name: String,
+        value: String,
+        detail: Option<String>,
+    },
+    Unset {
+        name: String,
+        detail: Option<String>,
+    },
+    Exports {
+        channel_id: u64,
+        recipient: Option<String>,
+        passphrase: bool,
+        passphrase_ttl: Option<u64>,
+        auto_decrypt: DecryptPolicy,
+        decrypt_rate: u32,
+        ephemeral: Option<u64>,
+    },
+    ContactAdded {
+        alias: String,
+        fpr: String,
+    },
+    ContactRemoved {
+        alias: String,
+    },
+    Contacts(Vec<(String, String)>),
+    KeyList(Vec<PublicKey>),
+    SecretKeys {
+        gpg_version: String,
+        fprs: Vec<String>,
+    },
+    PgpList(Vec<CapturedPgp>),
+    SearchResults {
+        query: String,
+        hits: Vec<Hit>,
+    },
+    ArchiveExported {
+        path: String,
+        messages: usize,
+        encrypted: usize,
+        plaintext: bool,
+    },
+    ArchiveImported {
+        path: String,
+        messages: usize,
+    },
+    Loading {
+        fetched: usize,
+        requested: usize,
+    },
+    Help,
+    Info(String),
+    Warning(String),
+    Error(String),
+}
+
+impl Event {
+    /// Channel the event belongs to, `None` for session output
+    pub fn channel_id(&self) -> Option<u64> {
+        match self {
+            Event::IncomingPlain { channel_id, .. }
+            | Event::IncomingPgp { channel_id, .. }
+            | Event::IncomingSigned { channel_id, .. }
+            | Event::IncomingArmor { channel_id, .. }
+            | Event::IncomingSession { channel_id, .. } => Some(*channel_id),
+            _ => None,
+        }
+    }
+}
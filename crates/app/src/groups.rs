@@ -0,0 +1,274 @@
+use anyhow::{Result, anyhow};
+use common::SecretString;
+use crypto::group::GroupKey;
+use std::collections::HashMap;
+
+use crate::{ids, recipient};
+
+/// Keys of earlier epochs kept, so messages from before a rotation still open
+const OLD_KEYS: usize = 8;
+
+/// A channel's group as shown by `group show`
+#[derive(Debug, Clone)]
+pub struct GroupInfo {
+    pub gid: String,
+    pub epoch: u32,
+    pub members: Vec<String>,
+    /// Who distributed the current key
+    pub from: String,
+}
+
+/// The member of `members` that `query` names: a fingerprint suffix, or
+/// whatever picks a key for `pgp send -r`. Keys that since expired still match.
+pub fn find_member(
+    query: &str,
+    members: &[String],
+    keys: &[crypto::gpg::PublicKey],
+    contacts: &recipient::Contacts,
+) -> Result<String> {
+    let q = query.trim().trim_start_matches("0x").to_uppercase();
+    let mut found: Vec<&String> = members
+        .iter()
+        .filter(|m| q.len() >= 8 && m.ends_with(&q))
+        .collect();
+    if found.is_empty() {
+        found = recipient::candidates(query, keys, contacts)
+            .into_iter()
+            .filter_map(|k| members.iter().find(|m| m.eq_ignore_ascii_case(&k.fpr)))
+            .collect();
+    }
+    found.dedup();
+    match found.as_slice() {
+        [] => Err(anyhow!(
+            "'{query}' isn't a member of this group (see: group show)"
+        )),
+        [m] => Ok(m.to_string()),
+        _ => Err(anyhow!(
+            "'{query}' is ambiguous, use a longer fingerprint (see: group show)"
+        )),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Group {
+    key: GroupKey,
+    from: String,
+    // newest first
+    older: Vec<SecretString>,
+}
+
+/// Group keys by channel, in memory only
+#[derive(Debug, Clone, Default)]
+pub struct Groups {
+    by_channel: HashMap<u64, Group>,
+}
+
+impl Groups {
+    pub fn key(&self, channel_id: u64) -> Option<&GroupKey> {
+        self.by_channel.get(&channel_id).map(|g| &g.key)
+    }
+
+    /// Make `key` the channel's current group key
+    pub fn set(&mut self, channel_id: u64, key: GroupKey, from: &str) {
+        let older = match self.by_channel.remove(&channel_id) {
+            Some(g) if g.key.gid == key.gid => {
+                let mut older = g.older;
+                older.insert(0, g.key.passphrase());
+                older.truncate(OLD_KEYS);
+                older
+            }
+            _ => Vec::new(),
+        };
+        self.by_channel.insert(
+            channel_id,
+            Group {
+                key,
+                from: from.to_string(),
+                older,
+            },
+        );
+    }
+
+    /// A distribution signed by `signer_fpr`. Only a member of the group
+    /// can rotate its key or replace it with another group. `None` when
+    /// the key is already current, like our own coming back. Two keys for
+    /// the same epoch, from members rotating at once, are an error for
+    /// the user to see: both are kept and the lower key id becomes current
+    /// on every member's side.
+    pub fn received(
+        &mut self,
+        channel_id: u64,
+        key: GroupKey,
+        signer_fpr: &str,
+        signer: &str,
+    ) -> Result<Option<String>> {
+        if !key.is_member(signer_fpr) {
+            return Err(anyhow!(
+                "Group key from {signer} ignored, they aren't one of its members"
+            ));
+        }
+        if let Some(current) = self.key(channel_id) {
+            let same_epoch = current.gid == key.gid && current.epoch == key.epoch;
+            if same_epoch && current.key_id() == key.key_id() {
+                return Ok(None);
+            }
+            if !current.is_member(signer_fpr) {
+                return Err(anyhow!(
+                    "Group key from {signer} ignored, they aren't in this channel's group"
+                ));
+            }
+            if current.gid == key.gid && key.epoch < current.epoch {
+                return Err(anyhow!(
+                    "Group key from {signer} ignored, epoch {} is older than {}",
+                    key.epoch,
+                    current.epoch
+                ));
+            }
+            if same_epoch {
+                return Err(self.conflict(channel_id, key, signer));
+            }
+        }
+
+        let note = format!(
+            "Group {} key epoch {} from {signer}, {} members (group show)",
+            ids::short(&key.gid),
+            key.epoch,
+            key.members.len()
+        );
+        self.set(channel_id, key, signer);
+        Ok(Some(note))
+    }
+
+    /// Settle a second key for the current epoch
+    fn conflict(&mut self, channel_id: u64, key: GroupKey, signer: &str) -> anyhow::Error {
+        let Some(g) = self.by_channel.get_mut(&channel_id) else {
+            return anyhow!("No group in this channel");
+        };
+        let (gid, epoch) = (ids::short(&key.gid).to_string(), key.epoch);
+        let kept = match key.key_id() < g.key.key_id() {
+            true => {
+                let from = signer.to_string();
+                self.set(channel_id, key, &from);
+                from
+            }
+            false => {
+                g.older.insert(0, key.passphrase());
+                g.older.truncate(OLD_KEYS);
+                g.from.clone()
+            }
+        };
+        anyhow!(
+            "Group {gid} got two keys for epoch {epoch}, {signer} rotated at the same time; \
+             using the one from {kept}, messages under either still open"
+        )
+    }
+
+    /// Passphrases of the channel's group, the current key first
+    pub fn passphrases(&self, channel_id: u64) -> Vec<SecretString> {
+        self.by_channel
+            .get(&channel_id)
+            .map(|g| {
+                std::iter::once(g.key.passphrase())
+                    .chain(g.older.iter().cloned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn info(&self, channel_id: u64) -> Option<GroupInfo> {
+        self.by_channel.get(&channel_id).map(|g| GroupInfo {
+            gid: g.key.gid.clone(),
+            epoch: g.key.epoch,
+            members: g.key.members.clone(),
+            from: g.from.clone(),
+        })
+    }
+
+    /// Forget the channel's group and all its keys
+    pub fn leave(&mut self, channel_id: u64) -> bool {
+        self.by_channel.remove(&channel_id).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.by_channel.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "AAAA1111";
+    const B: &str = "BBBB2222";
+    const OUTSIDER: &str = "CCCC3333";
+
+    fn members() -> Vec<String> {
+        vec![A.to_string(), B.to_string()]
+    }
+
+    fn has_key(g: &Groups, key: &GroupKey) -> bool {
+        g.passphrases(1).iter().any(|p| **p == *key.passphrase())
+    }
+
+    #[test]
+    fn own_key_coming_back_is_quiet() {
+        let key = GroupKey::new(members());
+        let mut g = Groups::default();
+        g.set(1, key.clone(), "you");
+        assert!(g.received(1, key, A, "a").unwrap().is_none());
+    }
+
+    #[test]
+    fn only_members_distribute() {
+        let key = GroupKey::new(members());
+        let mut g = Groups::default();
+        assert!(g.received(1, key.clone(), OUTSIDER, "c").is_err());
+        assert!(g.key(1).is_none());
+
+        assert!(g.received(1, key.clone(), B, "b").unwrap().is_some());
+        assert_eq!(g.info(1).unwrap().from, "b");
+
+        // a member of some other group can't take over the channel
+        let other = GroupKey::new(vec![OUTSIDER.to_string()]);
+        assert!(g.received(1, other, OUTSIDER, "c").is_err());
+        assert_eq!(g.key(1).unwrap().gid, key.gid);
+    }
+
+    #[test]
+    fn rotation_keeps_older_keys() {
+        let key = GroupKey::new(members());
+        let next = key.rotate(members());
+        let mut g = Groups::default();
+        g.set(1, key.clone(), "you");
+        assert!(g.received(1, next.clone(), B, "b").unwrap().is_some());
+        assert_eq!(g.key(1).unwrap().epoch, 2);
+        assert_eq!(*g.passphrases(1)[0], *next.passphrase());
+        assert!(has_key(&g, &key));
+
+        assert!(g.received(1, key, B, "b").is_err());
+        assert_eq!(g.key(1).unwrap().epoch, 2);
+    }
+
+    #[test]
+    fn concurrent_rotations_settle_on_the_same_key() {
+        let base = GroupKey::new(members());
+        let (ka, kb) = (base.rotate(members()), base.rotate(members()));
+
+        let mut ga = Groups::default();
+        ga.set(1, base.clone(), "you");
+        ga.set(1, ka.clone(), "you");
+        let mut gb = Groups::default();
+        gb.set(1, base.clone(), "you");
+        gb.set(1, kb.clone(), "you");
+
+        assert!(ga.received(1, kb.clone(), B, "b").is_err());
+        assert!(gb.received(1, ka.clone(), A, "a").is_err());
+
+        let (ga_key, gb_key) = (ga.key(1).unwrap(), gb.key(1).unwrap());
+        assert_eq!(ga_key.key_id(), gb_key.key_id());
+        assert_eq!(ga_key.key_id(), ka.key_id().min(kb.key_id()));
+        for g in [&ga, &gb] {
+            assert!(has_key(g, &ka) && has_key(g, &kb) && has_key(g, &base));
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use anyhow::{Result, anyhow};
+use common::{HistoryMode, SecretString};
+use crypto::gpg::DecryptError;
+use crypto::gpg_async::{EncryptOpts, Gpg, Secrets};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::cmdline;
+
+/// Entries kept in the file, oldest go first
+const MAX_ENTRIES: usize = 1000;
+
+/// Where earlier versions kept the history, in the working directory
+/// and unredacted
+pub const LEGACY_FILE: &str = ".pgp-disc.history";
+
+/// Command history file, owner-only and optionally encrypted to our own key
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    // fingerprint the file is encrypted to, plain lines when `None`
+    encrypt_to: Option<String>,
+    gpg: Gpg,
+    // the UI threads save through the main runtime
+    rt: tokio::runtime::Handle,
+}
+
+impl History {
+    /// `None` when history is turned off. Call from within the runtime
+    /// that gpg runs on.
+    pub fn from_config(
+        cfg: &common::Config,
+        secret_keys: &[String],
+        gpg: &Gpg,
+    ) -> Result<Option<Self>> {
+        let encrypt_to = match cfg.history {
+            HistoryMode::Off => return Ok(None),
+            HistoryMode::Plain => None,
+            HistoryMode::Encrypted => Some(
+                cfg.history_key
+                    .clone()
+                    .or_else(|| secret_keys.first().cloned())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "PGP_DISC_HISTORY=encrypted needs a secret key or PGP_DISC_HISTORY_KEY"
+                        )
+                    })?,
+            ),
+        };
+        let path = match &cfg.history_file {
+            Some(p) => p.clone(),
+            None => state_dir()
+                .ok_or_else(|| anyhow!("No XDG_STATE_HOME or HOME, set PGP_DISC_HISTORY_FILE"))?
+                .join("pgp-disc")
+                .join(if encrypt_to.is_some() {
+                    "history.asc"
+                } else {
+                    "history"
+                }),
+        };
+        Ok(Some(Self {
+            path,
+            encrypt_to,
+            gpg: gpg.clone(),
+            rt: tokio::runtime::Handle::current(),
+        }))
+    }
+
+    /// Saved entries, oldest first. When the file is encrypted to a locked
+    /// key, `ask` is asked for its passphrase.
+    pub async fn load(&self, ask: impl FnOnce() -> Option<SecretString>) -> Result<Vec<String>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("Failed to read {}: {e}", self.path.display())),
+        };
+
+        // by content, so switching modes keeps the old entries
+        let lines = if text.starts_with("-----BEGIN PGP MESSAGE-----") {
+            let mut secrets = Secrets::default();
+            let mut res = self.gpg.decrypt(&text, &secrets).await;
+            if let Err(DecryptError::KeyLocked { .. }) = res
+                && let Some(pass) = ask()
+            {
+                secrets.key_passphrase = Some(pass);
+                res = self.gpg.decrypt(&text, &secrets).await;
+            }
+            let d = res.map_err(|e| anyhow!("Failed to decrypt {}: {e}", self.path.display()))?;
+            d.plaintext.lines().map(str::to_string).collect()
+        } else {
+            text.lines().map(str::to_string).collect()
+        };
+        Ok(lines)
+    }
+
+    /// Move the entries of an earlier version's history file at `legacy`
+    /// in front of `saved`, redacted, and delete it once they're stored
+    /// here. How many entries were moved.
+    pub async fn migrate(&self, legacy: &Path, saved: &mut Vec<String>) -> Result<usize> {
+        let text = match fs::read_to_string(legacy) {
+            Ok(t) => SecretString::new(t),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(anyhow!("Failed to read {}: {e}", legacy.display())),
+        };
+
+        let mut entries: Vec<String> = legacy_entries(&text)
+            .iter()
+            .map(|e| cmdline::history_entry(e))
+            .collect();
+        let moved = entries.len();
+        entries.append(saved);
+        *saved = entries;
+
+        self.store(saved.iter().map(String::as_str)).await?;
+        fs::remove_file(legacy)
+            .map_err(|e| anyhow!("Failed to remove {}: {e}", legacy.display()))?;
+        Ok(moved)
+    }
+
+    /// Replace the file with `entries`. Blocks on gpg, call it from the
+    /// UI threads only.
+    pub fn save<'a>(&self, entries: impl IntoIterator<Item = &'a str>) -> Result<()> {
+        self.rt.block_on(self.store(entries))
+    }
+
+    async fn store<'a>(&self, entries: impl IntoIterator<Item = &'a str>) -> Result<()> {
+        let entries: Vec<&str> = entries.into_iter().collect();
+        let mut text = common::SecretString::default();
+        for e in &entries[entries.len().saturating_sub(MAX_ENTRIES)..] {
+            text.push_str(e);
+            text.push('\n');
+        }
+
+        let data = match &self.encrypt_to {
+            Some(fpr) => {
+                self.gpg
+                    .encrypt_to_recipient(fpr, &text, EncryptOpts::default())
+                    .await?
+            }
+            None => text.to_string(),
+        };
+        write_private(&self.path, data.as_bytes())
+            .map_err(|e| anyhow!("Failed to write {}: {e}", self.path.display()))
+    }
+}
+
+/// Entries of a rustyline history file, `#V2` escapes undone
+fn legacy_entries(text: &str) -> Vec<SecretString> {
+    let unescape = |line: &str| {
+        let mut out = SecretString::default();
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) => {
+                    out.push('\n');
+                    chars.next();
+                }
+                ('\\', Some('\\')) => {
+                    out.push('\\');
+                    chars.next();
+                }
+                _ => out.push(c),
+            }
+        }
+        out
+    };
+
+    let v2 = text.starts_with("#V2");
+    text.lines()
+        .skip(v2 as usize)
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| match v2 {
+            true => unescape(l),
+            false => SecretString::new(l.to_string()),
+        })
+        .collect()
+}
+
+fn state_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))
+}
+
+/// Write through a 0600 temp file and rename, so the file is never
+/// readable by others, not even for a moment
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        let mut b = fs::DirBuilder::new();
+        b.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            b.mode(0o700);
+        }
+        b.create(dir)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    let _ = fs::remove_file(&tmp);
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut f = opts.open(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(history: HistoryMode, history_file: Option<PathBuf>) -> common::Config {
+        common::Config {
+            token: SecretString::default(),
+            channel_id: 1,
+            gpg_timeout_secs: 5,
+            gpg_jobs: 1,
+            notify: common::NotifyMode::Off,
+            auto_decrypt: common::DecryptPolicy::Always,
+            decrypt_rate: 0,
+            ephemeral_secs: 0,
+            history,
+            history_file,
+            history_key: None,
+            pad: false,
+        }
+    }
+
+    fn plain(path: PathBuf, rt: &tokio::runtime::Runtime) -> History {
+        History {
+            path,
+            encrypt_to: None,
+            gpg: Gpg::default(),
+            rt: rt.handle().clone(),
+        }
+    }
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn written_for_the_owner_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state/pgp-disc/history");
+        write_private(&path, b"one\n").unwrap();
+        write_private(&path, b"two\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            assert_eq!(mode(&path), 0o600);
+            assert_eq!(mode(path.parent().unwrap()), 0o700);
+        }
+    }
+
+    #[tokio::test]
+    async fn path_under_xdg_state_home() {
+        let dir = tempfile::tempdir().unwrap();
+        // SAFETY: no other test reads or sets XDG_STATE_HOME
+        unsafe { std::env::set_var("XDG_STATE_HOME", dir.path()) };
+        let gpg = Gpg::default();
+
+        let h = History::from_config(&config(HistoryMode::Plain, None), &[], &gpg)
+            .unwrap()
+            .unwrap();
+        assert_eq!(h.path, dir.path().join("pgp-disc/history"));
+        let h = History::from_config(
+            &config(HistoryMode::Encrypted, None),
+            &["AAAA".to_string()],
+            &gpg,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(h.path, dir.path().join("pgp-disc/history.asc"));
+        assert_eq!(h.encrypt_to.as_deref(), Some("AAAA"));
+
+        // relative paths don't count, HOME is the fallback
+        unsafe { std::env::set_var("XDG_STATE_HOME", "relative/state") };
+        match std::env::var_os("HOME") {
+            Some(home) => {
+                assert_eq!(state_dir(), Some(PathBuf::from(home).join(".local/state")))
+            }
+            None => assert_eq!(state_dir(), None),
+        }
+        unsafe { std::env::remove_var("XDG_STATE_HOME") };
+
+        // the file setting wins, off is off
+        let file = dir.path().join("elsewhere");
+        let h = History::from_config(&config(HistoryMode::Plain, Some(file.clone())), &[], &gpg)
+            .unwrap()
+            .unwrap();
+        assert_eq!(h.path, file);
+        assert!(
+            History::from_config(&config(HistoryMode::Off, None), &[], &gpg)
+                .unwrap()
+                .is_none()
+        );
+        assert!(History::from_config(&config(HistoryMode::Encrypted, None), &[], &gpg).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let h = plain(dir.path().join("history"), &rt);
+
+        assert!(rt.block_on(h.load(|| None)).unwrap().is_empty());
+        let entries: Vec<String> = (0..MAX_ENTRIES + 5).map(|i| format!("cmd {i}")).collect();
+        h.save(entries.iter().map(String::as_str)).unwrap();
+
+        let loaded = rt.block_on(h.load(|| None)).unwrap();
+        assert_eq!(loaded.len(), MAX_ENTRIES);
+        assert_eq!(loaded.first().map(String::as_str), Some("cmd 5"));
+        assert_eq!(loaded.last(), entries.last());
+    }
+
+    #[test]
+    fn encrypted_file_that_wont_decrypt_is_an_error() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let h = plain(dir.path().join("history.asc"), &rt);
+        fs::write(
+            &h.path,
+            "-----BEGIN PGP MESSAGE-----\n\nAAAA\n-----END PGP MESSAGE-----\n",
+        )
+        .unwrap();
+
+        // never read as plain lines, saving them would lose the history
+        let e = rt.block_on(h.load(|| None)).unwrap_err().to_string();
+        assert!(e.starts_with("Failed to decrypt"), "{e}");
+    }
+
+    #[test]
+    fn legacy_file_moves_in_redacted() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let h = plain(dir.path().join("history"), &rt);
+        let legacy = dir.path().join(LEGACY_FILE);
+        fs::write(
+            &legacy,
+            "#V2\nkeys\npgp send -r bob meet at noon\nsend a\\\\b \\\\o/\n",
+        )
+        .unwrap();
+
+        let mut saved = vec!["load 10".to_string()];
+        assert_eq!(rt.block_on(h.migrate(&legacy, &mut saved)).unwrap(), 3);
+        assert_eq!(
+            saved,
+            [
+                "keys",
+                "pgp send -r bob <redacted>",
+                "send a\\b \\o/",
+                "load 10"
+            ]
+        );
+        assert!(!legacy.exists());
+        assert_eq!(rt.block_on(h.load(|| None)).unwrap(), saved);
+
+        // nothing to move the next time
+        assert_eq!(rt.block_on(h.migrate(&legacy, &mut saved)).unwrap(), 0);
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{Result, anyhow};
+
+/// Characters of a block id shown on screen, JSON and archives keep all 16
+pub const SHORT: usize = 8;
+
+/// Shortest prefix taken on the command line
+const MIN_PREFIX: usize = 4;
+
+/// `id` as shown on screen
+pub fn short(id: &str) -> &str {
+    id.char_indices().nth(SHORT).map_or(id, |(i, _)| &id[..i])
+}
+
+/// The id among `ids` that `prefix` is the start of, like git takes
+/// abbreviated commit hashes
+pub fn resolve<'a>(prefix: &str, ids: impl IntoIterator<Item = &'a str>) -> Result<String> {
+    resolve_among(prefix, ids, "captured PGP message")
+}
+
+/// [`resolve`] for ids of other things, `what` names them in errors
+pub fn resolve_among<'a>(
+    prefix: &str,
+    ids: impl IntoIterator<Item = &'a str>,
+    what: &str,
+) -> Result<String> {
+    let prefix = prefix.to_lowercase();
+    if prefix.len() < MIN_PREFIX {
+        return Err(anyhow!(
+            "Id prefix too short, give at least {MIN_PREFIX} characters"
+        ));
+    }
+
+    let mut found: Vec<&str> = ids
+        .into_iter()
+        .filter(|id| id.starts_with(&prefix))
+        .collect();
+    found.sort_unstable();
+    found.dedup();
+    match found.as_slice() {
+        [] => Err(anyhow!("No {what} with id={prefix}")),
+        [id] => Ok(id.to_string()),
+        _ => Err(anyhow!(
+            "Id {prefix} is ambiguous, could be: {}",
+            found.join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_cuts_on_char_boundaries() {
+        assert_eq!(short("0123456789abcdef"), "01234567");
+        assert_eq!(short("abc"), "abc");
+        assert_eq!(short("日日日日日日日日日"), "日日日日日日日日");
+    }
+
+    #[test]
+    fn unique_prefix() {
+        let ids = ["0123456789abcdef", "fedcba9876543210"];
+        assert_eq!(resolve("0123", ids).unwrap(), ids[0]);
+        assert_eq!(resolve("FEDCBA98", ids).unwrap(), ids[1]);
+        assert_eq!(resolve(ids[0], ids).unwrap(), ids[0]);
+        // the same id listed twice is still one message
+        assert_eq!(resolve("0123", [ids[0], ids[0]]).unwrap(), ids[0]);
+    }
+
+    #[test]
+    fn ambiguous_prefix_lists_the_candidates() {
+        let ids = ["abcd000011112222", "abcd999988887777", "ffff000011112222"];
+        let err = resolve("abcd", ids).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Id abcd is ambiguous, could be: abcd000011112222, abcd999988887777"
+        );
+        assert_eq!(resolve("abcd9", ids).unwrap(), ids[1]);
+    }
+
+    #[test]
+    fn unknown_or_short_ids() {
+        let ids = ["0123456789abcdef"];
+        assert_eq!(
+            resolve("9999", ids).unwrap_err().to_string(),
+            "No captured PGP message with id=9999"
+        );
+        assert_eq!(
+            resolve_among("9999", ids, "session")
+                .unwrap_err()
+                .to_string(),
+            "No session with id=9999"
+        );
+        assert!(
+            resolve("012", ids)
+                .unwrap_err()
+                .to_string()
+                .contains("too short")
+        );
+        assert!(resolve("0123", []).is_err());
+    }
+
+    #[test]
+    fn every_block_of_a_message_has_its_own_id() {
+        let ids: Vec<String> = (0..3).map(|i| crypto::pgp_block_id(42, i)).collect();
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+        for id in &ids {
+            let found = resolve(short(id), ids.iter().map(String::as_str)).unwrap();
+            assert_eq!(&found, id);
+        }
+        // and another message's first block doesn't clash with this one's
+        assert_ne!(crypto::pgp_block_id(43, 0), ids[0]);
+    }
+}
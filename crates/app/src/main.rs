@@ -1,5 +1,5 @@
 use anyhow::{Result, anyhow};
-use chrono::Local;
+use common::SecretString;
 use owo_colors::OwoColorize;
 use rustyline::{
     Context, Editor, Helper,
@@ -9,30 +9,295 @@ use rustyline::{
     hint::Hinter,
     validate::Validator,
 };
-use std::collections::VecDeque;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tracing_subscriber::EnvFilter;
 
+mod archive;
+mod cmdline;
+mod compose;
+mod event;
+mod groups;
+mod history;
+mod ids;
+mod notify;
+mod policy;
+mod recipient;
+mod render;
+mod search;
+mod sessions;
+mod tui;
+
+use event::Event;
+
 #[derive(Debug, Clone, Default)]
 struct SessionEnv {
     default_fpr: Option<String>,
     channel_id: Option<u64>,
+    contacts: recipient::Contacts,
+    authors: recipient::Authors,
+    // channel id -> group passphrase for symmetric messages
+    passphrases: HashMap<u64, SecretString>,
+    // secret key passphrase, kept until the instant only when a ttl is exported
+    key_passphrase: Option<(SecretString, Instant)>,
+    passphrase_ttl: Option<Duration>,
+    // shared with CliHelper for tab completion
+    recipient_hints: Arc<Mutex<Vec<String>>>,
+    auto_decrypt: policy::AutoDecrypt,
+    // plaintext stays hidden until `pgp show`, then is cleared after this long
+    ephemeral: Option<Duration>,
+    sessions: sessions::Sessions,
+    groups: groups::Groups,
 }
 
 impl SessionEnv {
     fn set_channel_id(&self, cfg: &common::Config) -> u64 {
         self.channel_id.unwrap_or(cfg.channel_id)
     }
+
+    /// Passphrases for blocks posted in `channel_id`: the one exported
+    /// for it and its group keys. Other channels' passphrases stay out,
+    /// see [`SessionEnv::secrets_any_channel`].
+    fn secrets_for(&self, channel_id: u64) -> crypto::gpg_async::Secrets {
+        let mut passphrases: Vec<SecretString> = self
+            .passphrases
+            .get(&channel_id)
+            .cloned()
+            .into_iter()
+            .collect();
+        passphrases.extend(self.groups.passphrases(channel_id));
+
+        let key_passphrase = self
+            .key_passphrase
+            .as_ref()
+            .filter(|(_, until)| Instant::now() < *until)
+            .map(|(p, _)| p.clone());
+
+        crypto::gpg_async::Secrets {
+            passphrases,
+            key_passphrase,
+        }
+    }
+
+    /// [`SessionEnv::secrets_for`] followed by every other channel's
+    /// passphrase, for `pgp decrypt --any-passphrase`
+    fn secrets_any_channel(&self, channel_id: u64) -> crypto::gpg_async::Secrets {
+        let mut secrets = self.secrets_for(channel_id);
+        secrets.passphrases.extend(
+            self.passphrases
+                .iter()
+                .filter(|(ch, _)| **ch != channel_id)
+                .map(|(_, p)| p.clone()),
+        );
+        secrets
+    }
+
+    fn cache_key_passphrase(&mut self, pass: SecretString) {
+        self.key_passphrase = self.passphrase_ttl.map(|ttl| (pass, Instant::now() + ttl));
+    }
+
+    fn refresh_recipient_hints(&self, keys: &[crypto::gpg::PublicKey]) {
+        let hints = recipient::completion_hints(keys, &self.contacts);
+        if let Ok(mut h) = self.recipient_hints.lock() {
+            *h = hints;
+        }
+    }
+}
+
+/// Decrypt state of a captured PGP block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PgpStatus {
+    Pending,
+    Decrypted,
+    NotForMe,
+    NeedsPassphrase,
+    KeyLocked,
+    Invalid,
+    Error,
+    /// Left for `pgp decrypt` by the auto-decrypt policy
+    Skipped,
+    RateLimited,
+}
+
+impl PgpStatus {
+    fn of(res: &std::result::Result<crypto::gpg::Decrypted, crypto::gpg::DecryptError>) -> Self {
+        use crypto::gpg::DecryptError;
+        match res {
+            Ok(_) => PgpStatus::Decrypted,
+            Err(DecryptError::NotForMe { .. }) => PgpStatus::NotForMe,
+            Err(DecryptError::NeedsPassphrase) => PgpStatus::NeedsPassphrase,
+            Err(DecryptError::KeyLocked { .. }) => PgpStatus::KeyLocked,
+            Err(DecryptError::InvalidMessage { .. }) => PgpStatus::Invalid,
+            Err(_) => PgpStatus::Error,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            PgpStatus::Pending => "pending",
+            PgpStatus::Decrypted => "decrypted",
+            PgpStatus::NotForMe => "not for me",
+            PgpStatus::NeedsPassphrase => "needs passphrase",
+            PgpStatus::KeyLocked => "key locked",
+            PgpStatus::Invalid => "invalid",
+            PgpStatus::Error => "decrypt error",
+            PgpStatus::Skipped => "not decrypted",
+            PgpStatus::RateLimited => "rate limited",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CapturedPgp {
+    id: String,
+    channel_id: u64,
+    author: String,
+    block: SecretString,
+    status: PgpStatus,
+}
+
+fn set_status(pgp_inbox: &mut VecDeque<CapturedPgp>, id: &str, status: PgpStatus) {
+    if let Some(c) = pgp_inbox.iter_mut().rev().find(|c| c.id == id) {
+        c.status = status;
+    }
+}
+
+/// What commands and incoming messages work on. Commands and decrypts
+/// run as tasks beside the main loop, so it's shared, and locked only
+/// between their awaits.
+#[derive(Default)]
+struct State {
+    env: SessionEnv,
+    pgp_inbox: VecDeque<CapturedPgp>,
+    log: search::MessageLog,
+}
+
+type Shared = Arc<Mutex<State>>;
+
+/// `state` locked, also after a task panicked holding it
+fn lock(state: &Shared) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Session snapshot for frontends that show state (the TUI sidebar)
+#[derive(Debug, Clone, Default)]
+struct UiStatus {
+    channel_id: u64,
+    channels: Vec<u64>,
+    passphrase_channels: Vec<u64>,
+    contacts: Vec<(String, String)>,
+    recipient: Option<String>,
+    secret_keys: Vec<String>,
+    captured: Vec<CapturedPgp>,
+    sessions: Vec<sessions::SessionInfo>,
+}
+
+impl UiStatus {
+    fn snapshot(
+        env: &SessionEnv,
+        cfg: &common::Config,
+        pgp_inbox: &VecDeque<CapturedPgp>,
+        secret_keys: &[String],
+    ) -> Self {
+        let mut channels = vec![cfg.channel_id];
+        channels.extend(env.channel_id);
+        channels.extend(env.passphrases.keys().copied());
+        channels.extend(pgp_inbox.iter().map(|c| c.channel_id));
+        channels.sort();
+        channels.dedup();
+
+        Self {
+            channel_id: env.set_channel_id(cfg),
+            channels,
+            passphrase_channels: env.passphrases.keys().copied().collect(),
+            contacts: env
+                .contacts
+                .iter()
+                .map(|(a, f)| (a.clone(), f.clone()))
+                .collect(),
+            recipient: env.default_fpr.clone(),
+            secret_keys: secret_keys.to_vec(),
+            captured: pgp_inbox.iter().cloned().collect(),
+            sessions: env.sessions.list(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 enum UiEvent {
-    Line(String),
+    Show(Event),
+    Status(Box<UiStatus>),
     Clear,
+    /// Take the revealed plaintext of a block off the screen
+    Hide {
+        id: String,
+    },
+    /// `panic`: clear everything on screen
+    Wipe,
     Exit,
 }
 
+/// Sent to the readline thread while it waits for a command to finish
+enum CliControl {
+    Secret {
+        prompt: String,
+        reply: tokio::sync::oneshot::Sender<Option<SecretString>>,
+    },
+    /// Multi-line message, from `$EDITOR` or typed lines ending with `.`
+    Compose {
+        reply: tokio::sync::oneshot::Sender<std::result::Result<Option<SecretString>, String>>,
+    },
+    /// `panic`: forget the input history
+    Wipe,
+    Done,
+}
+
+/// Lets command handlers ask for hidden input
+#[derive(Clone)]
+struct Prompter {
+    tx: mpsc::UnboundedSender<CliControl>,
+}
+
+impl Prompter {
+    async fn secret(&self, prompt: &str) -> Result<SecretString> {
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        self.tx
+            .send(CliControl::Secret {
+                prompt: prompt.to_string(),
+                reply,
+            })
+            .map_err(|_| anyhow!("input closed"))?;
+        match rx.await {
+            Ok(Some(s)) if !s.is_empty() => Ok(s),
+            _ => Err(anyhow!("No passphrase entered")),
+        }
+    }
+
+    /// Compose a message with formatting kept as typed
+    async fn compose(&self) -> Result<SecretString> {
+        let (reply, rx) = tokio::sync::oneshot::channel();
+        self.tx
+            .send(CliControl::Compose { reply })
+            .map_err(|_| anyhow!("input closed"))?;
+        match rx.await {
+            Ok(Ok(Some(msg))) => Ok(msg),
+            Ok(Ok(None)) => Err(anyhow!("Empty message, nothing sent")),
+            Ok(Err(e)) => Err(anyhow!(e)),
+            Err(_) => Err(anyhow!("input closed")),
+        }
+    }
+
+    fn wipe(&self) {
+        let _ = self.tx.send(CliControl::Wipe);
+    }
+
+    fn done(&self) {
+        let _ = self.tx.send(CliControl::Done);
+    }
+}
+
 #[derive(Clone)]
 struct CliHelper {
     commands: Arc<Vec<&'static str>>,
@@ -40,6 +305,14 @@ struct CliHelper {
     pgp_send_flags: Arc<Vec<&'static str>>,
     export_sub: Arc<Vec<&'static str>>,
     export_unset: Arc<Vec<&'static str>>,
+    contact_sub: Arc<Vec<&'static str>>,
+    archive_sub: Arc<Vec<&'static str>>,
+    session_sub: Arc<Vec<&'static str>>,
+    group_sub: Arc<Vec<&'static str>>,
+    recipients: Arc<Mutex<Vec<String>>>,
+    // short ids of captured blocks, from the status snapshots
+    pgp_ids: Arc<Mutex<Vec<String>>>,
+    session_ids: Arc<Mutex<Vec<String>>>,
 }
 
 impl Helper for CliHelper {}
@@ -61,7 +334,49 @@ impl Completer for CliHelper {
         let before = &line[..pos];
         let parts: Vec<&str> = before.split_whitespace().collect();
 
-        let choices: &[&'static str] = match parts.as_slice() {
+        let start = before
+            .rfind(char::is_whitespace)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let token = &before[start..];
+
+        // completed words only, excluding the one being typed
+        let done = if token.is_empty() {
+            &parts[..]
+        } else {
+            &parts[..parts.len().saturating_sub(1)]
+        };
+
+        let hints;
+        let choices: &[String] = match done {
+            ["pgp", "send", .., "-r" | "-R"]
+            | ["export", "recipient"]
+            | ["session", "start"]
+            | ["group", "create" | "add", ..]
+            | ["group", "rm"] => {
+                hints = self
+                    .recipients
+                    .lock()
+                    .map(|r| r.clone())
+                    .unwrap_or_default();
+                &hints
+            }
+            ["pgp", "decrypt" | "show"] => {
+                hints = self.pgp_ids.lock().map(|r| r.clone()).unwrap_or_default();
+                &hints
+            }
+            ["session", "accept" | "send" | "end"] => {
+                hints = self
+                    .session_ids
+                    .lock()
+                    .map(|r| r.clone())
+                    .unwrap_or_default();
+                &hints
+            }
+            _ => &[],
+        };
+
+        let statics: &[&'static str] = match parts.as_slice() {
             // TODO: this
             [] => &self.commands,
 
@@ -74,18 +389,21 @@ impl Completer for CliHelper {
             ["export", "unset"] => &self.export_unset,
             ["export", _] => &self.export_sub,
 
+            ["contact"] | ["contact", _] => &self.contact_sub,
+            ["archive"] | ["archive", _] => &self.archive_sub,
+            ["session"] | ["session", _] => &self.session_sub,
+            ["group"] | ["group", _] => &self.group_sub,
+
             _ => &self.commands,
         };
 
-        let start = before
-            .rfind(char::is_whitespace)
-            .map(|i| i + 1)
-            .unwrap_or(0);
-        let token = &before[start..];
-
         let mut out = Vec::new();
-        for &c in choices {
-            if c.starts_with(token) {
+        let all = choices
+            .iter()
+            .map(String::as_str)
+            .chain(statics.iter().copied().filter(|_| choices.is_empty()));
+        for c in all {
+            if c.to_lowercase().starts_with(&token.to_lowercase()) {
                 out.push(Pair {
                     display: c.to_string(),
                     replacement: c.to_string(),
@@ -118,26 +436,78 @@ impl UiPrinter {
             }
         }
     }
+
+    /// A screen change, if the output can make it
+    fn print_screen(&mut self, s: Option<String>) {
+        if let Some(s) = s {
+            self.print_line(&s);
+        }
+    }
 }
 
-fn spawn_cli_thread() -> (
+fn spawn_cli_thread(
+    recipients: Arc<Mutex<Vec<String>>>,
+    renderer: Box<dyn render::Renderer>,
+    history: Option<history::History>,
+    saved: Vec<String>,
+) -> (
     mpsc::UnboundedSender<UiEvent>,
-    mpsc::UnboundedReceiver<String>,
+    mpsc::UnboundedReceiver<SecretString>,
+    Prompter,
 ) {
     let (ui_tx, ui_rx) = mpsc::unbounded_channel::<UiEvent>();
-    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel::<String>();
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel::<SecretString>();
+    let notes = ui_tx.clone();
+    let (ctl_tx, mut ctl_rx) = mpsc::unbounded_channel::<CliControl>();
 
     std::thread::spawn(move || {
         let h = CliHelper {
             commands: Arc::new(vec![
-                "help", "h", "?", "me", "keys", "send", "s", "load", "pgp", "export", "quit",
-                "exit", "q", "clear",
+                "help", "h", "?", "me", "keys", "send", "s", "sign", "load", "pgp", "export",
+                "contact", "search", "archive", "session", "group", "quit", "exit", "q", "clear",
+                "panic",
+            ]),
+            pgp_sub: Arc::new(vec!["list", "send", "decrypt", "decrypt-last", "show"]),
+            pgp_send_flags: Arc::new(vec![
+                "-r",
+                "-R",
+                "--symmetric",
+                "--throw-keyids",
+                "--pad",
+                "--compact",
+                "--code",
             ]),
-            pgp_sub: Arc::new(vec!["list", "send", "decrypt", "decrypt-last"]),
-            pgp_send_flags: Arc::new(vec!["-r"]),
-            export_sub: Arc::new(vec!["recipient", "channel", "show", "unset"]),
-            export_unset: Arc::new(vec!["recipient", "channel"]),
+            export_sub: Arc::new(vec![
+                "recipient",
+                "channel",
+                "passphrase",
+                "passphrase-ttl",
+                "auto-decrypt",
+                "decrypt-rate",
+                "ephemeral",
+                "show",
+                "unset",
+            ]),
+            export_unset: Arc::new(vec![
+                "recipient",
+                "channel",
+                "passphrase",
+                "passphrase-ttl",
+                "auto-decrypt",
+                "ephemeral",
+            ]),
+            contact_sub: Arc::new(vec!["add", "rm", "link", "list"]),
+            archive_sub: Arc::new(vec!["export", "import"]),
+            session_sub: Arc::new(vec!["start", "accept", "send", "list", "end"]),
+            group_sub: Arc::new(vec![
+                "create", "add", "rm", "rotate", "send", "show", "leave",
+            ]),
+            recipients,
+            pgp_ids: Arc::new(Mutex::new(Vec::new())),
+            session_ids: Arc::new(Mutex::new(Vec::new())),
         };
+        let pgp_ids = h.pgp_ids.clone();
+        let session_ids = h.session_ids.clone();
 
         let mut rl = Editor::new().expect("rustyline editor");
         rl.set_helper(Some(h));
@@ -152,54 +522,134 @@ fn spawn_cli_thread() -> (
             let mut ui_rx = ui_rx;
             while let Some(ev) = ui_rx.blocking_recv() {
                 match ev {
-                    UiEvent::Line(s) => printer.print_line(&s),
-                    UiEvent::Clear => printer.print_line("\x1B[2J\x1B[H"),
+                    UiEvent::Show(ev) => printer.print_line(&renderer.render(&ev)),
+                    UiEvent::Status(st) => {
+                        if let Ok(mut ids) = pgp_ids.lock() {
+                            // newest first, the one most likely wanted
+                            *ids = st
+                                .captured
+                                .iter()
+                                .rev()
+                                .map(|c| ids::short(&c.id).to_string())
+                                .collect();
+                        }
+                        if let Ok(mut ids) = session_ids.lock() {
+                            *ids = st
+                                .sessions
+                                .iter()
+                                .map(|s| ids::short(&s.id).to_string())
+                                .collect();
+                        }
+                    }
+                    UiEvent::Clear => printer.print_screen(renderer.screen(render::Screen::Clear)),
+                    UiEvent::Hide { id } => {
+                        printer.print_screen(renderer.screen(render::Screen::Hide { id: &id }))
+                    }
+                    UiEvent::Wipe => printer.print_screen(renderer.screen(render::Screen::Wipe)),
                     UiEvent::Exit => {
-                        printer.print_line(&format!("{}", "exiting...".dimmed()));
+                        printer.print_line(&renderer.render(&Event::Info("exiting...".into())));
                         break;
                     }
                 }
             }
         });
 
-        let hist = ".pgp-disc.history";
-        let _ = rl.load_history(hist);
+        for entry in saved {
+            let _ = rl.add_history_entry(entry);
+        }
+
+        // sent once history is saved, main may exit right away
+        let mut quit = SecretString::new("quit".to_string());
+        // until Ctrl-C, Ctrl-D or a broken terminal
+        while let Ok(line) = rl.readline(&format!("{}", "pgp-disc> ".cyan())) {
+            // may carry a `pgp send` message
+            let line = SecretString::new(line);
+            // only leading whitespace goes, message payloads keep the rest
+            let line = SecretString::new(line.trim_start().to_string());
+            if line.trim().is_empty() {
+                continue;
+            }
+            let _ = rl.add_history_entry(cmdline::history_entry(&line));
+            if matches!(line.trim_end(), "quit" | "exit" | "q") {
+                quit = line;
+                break;
+            }
+            if cmd_tx.send(line).is_err() {
+                break;
+            }
 
-        loop {
-            match rl.readline(&format!("{}", "pgp-disc> ".cyan())) {
-                Ok(line) => {
-                    let line = line.trim().to_string();
-                    if line.is_empty() {
-                        continue;
+            // serve prompts until the command is finished
+            while let Some(ctl) = ctl_rx.blocking_recv() {
+                match ctl {
+                    CliControl::Secret { prompt, reply } => {
+                        let _ = reply.send(
+                            rpassword::prompt_password(prompt)
+                                .ok()
+                                .map(SecretString::new),
+                        );
                     }
-                    let _ = rl.add_history_entry(line.as_str());
-                    if cmd_tx.send(line.clone()).is_err() {
-                        break;
+                    CliControl::Compose { reply } => {
+                        let _ =
+                            reply.send(compose_message(&mut rl, &notes).map_err(|e| e.to_string()));
                     }
-                    match line.as_str() {
-                        "quit" | "exit" | "q" => break,
-                        _ => {}
+                    CliControl::Wipe => {
+                        let _ = rl.clear_history();
+                        if let Some(h) = &history
+                            && let Err(e) = h.save([])
+                        {
+                            tracing::warn!("{e}");
+                        }
                     }
-                }
-                Err(ReadlineError::Interrupted) => {
-                    let _ = cmd_tx.send("quit".to_string());
-                    break;
-                }
-                Err(ReadlineError::Eof) => {
-                    let _ = cmd_tx.send("quit".to_string());
-                    break;
-                }
-                Err(_) => {
-                    let _ = cmd_tx.send("quit".to_string());
-                    break;
+                    CliControl::Done => break,
                 }
             }
         }
 
-        let _ = rl.save_history(hist);
+        if let Some(h) = &history
+            && let Err(e) = h.save(rl.history().iter().map(String::as_str))
+        {
+            tracing::warn!("{e}");
+        }
+        let _ = cmd_tx.send(quit);
     });
 
-    (ui_tx, cmd_rx)
+    (ui_tx, cmd_rx, Prompter { tx: ctl_tx })
+}
+
+fn compose_message(
+    rl: &mut Editor<CliHelper, rustyline::history::DefaultHistory>,
+    ui_tx: &mpsc::UnboundedSender<UiEvent>,
+) -> Result<Option<SecretString>> {
+    if let Some(editor) = compose::editor() {
+        return compose::edit(&editor);
+    }
+
+    show(
+        ui_tx,
+        Event::Info("Compose message, end with a line containing only '.' (Ctrl-D cancels)".into()),
+    );
+
+    let mut lines: Vec<SecretString> = Vec::new();
+    loop {
+        match rl.readline(&format!("{}", "... ".dimmed())) {
+            Ok(l) if l == "." => break,
+            Ok(l) => lines.push(SecretString::new(l)),
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => return Ok(None),
+            Err(e) => return Err(anyhow!("{e}")),
+        }
+    }
+
+    let msg = SecretString::new(
+        lines
+            .iter()
+            .map(|l| l.as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    );
+    if msg.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(msg))
 }
 
 #[tokio::main]
@@ -207,49 +657,199 @@ async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
 
     let mut env = SessionEnv::default();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let use_tui = args.iter().any(|a| a == "--tui");
+
+    // --output ansi|plain|json, plain by default when NO_COLOR is set
+    let output = args
+        .iter()
+        .position(|a| a == "--output")
+        .map(|i| args.get(i + 1).cloned().unwrap_or_default())
+        .unwrap_or_else(|| match std::env::var_os("NO_COLOR") {
+            Some(_) => "plain".to_string(),
+            None => "ansi".to_string(),
+        });
+    let renderer = render::by_name(&output)
+        .ok_or_else(|| anyhow!("--output must be one of: ansi, plain, json"))?;
 
     let filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new("info,twilight_gateway=warn,twilight_http=warn"));
 
+    // log lines would tear the full-screen UI
+    let log_writer = if use_tui {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::sink)
+    } else {
+        tracing_subscriber::fmt::writer::BoxMakeWriter::new(std::io::stdout)
+    };
+
     tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_target(false)
+        .with_writer(log_writer)
         .init();
 
-    let cfg = common::Config::from_env()?;
-    let mut rx = transport::start_gateway(cfg.token.clone()).await?;
+    let cfg = Arc::new(common::Config::from_env()?);
+    env.auto_decrypt = policy::AutoDecrypt::new(cfg.auto_decrypt, cfg.decrypt_rate);
+    env.ephemeral = (cfg.ephemeral_secs > 0).then(|| Duration::from_secs(cfg.ephemeral_secs));
+    let mut rx = transport::start_gateway(cfg.token.to_string()).await?;
+
+    let gpg = crypto::gpg_async::Gpg::new(
+        std::time::Duration::from_secs(cfg.gpg_timeout_secs),
+        cfg.gpg_jobs,
+    );
+
+    if let Ok(keys) = gpg.list_public_keys().await {
+        env.refresh_recipient_hints(&keys);
+    }
+
+    let secret_keys = gpg.list_secret_fingerprints().await.unwrap_or_default();
+    env.sessions = sessions::Sessions::new(&secret_keys);
+
+    let notifier: Option<Box<dyn notify::Notifier>> = match cfg.notify {
+        common::NotifyMode::Off => None,
+        _ => match notify::DbusNotifier::connect().await {
+            Ok(n) => Some(Box::new(n)),
+            Err(e) => {
+                tracing::warn!("desktop notifications unavailable: {e}");
+                None
+            }
+        },
+    };
+    // to spot mentions and skip our own messages
+    let me = match notifier {
+        Some(_) => transport::current_user_id(&cfg.token).await.ok(),
+        None => None,
+    };
+
+    // before the UI owns the terminal, we may ask for the key passphrase
+    let mut history_notes = Vec::new();
+    let ask = || {
+        rpassword::prompt_password("Secret key passphrase for the history file: ")
+            .ok()
+            .map(SecretString::new)
+    };
+    let legacy = std::path::Path::new(history::LEGACY_FILE);
+    let (history, saved) = match history::History::from_config(&cfg, &secret_keys, &gpg)? {
+        Some(h) => match h.load(ask).await {
+            Ok(mut saved) => {
+                match h.migrate(legacy, &mut saved).await {
+                    Ok(0) => {}
+                    Ok(n) => history_notes.push(Event::Info(format!(
+                        "Moved {n} entries of {} into the history, redacted, and removed it",
+                        legacy.display()
+                    ))),
+                    Err(e) => history_notes.push(Event::Warning(format!(
+                        "{} holds unredacted history and wasn't moved: {e}",
+                        legacy.display()
+                    ))),
+                }
+                (Some(h), saved)
+            }
+            Err(e) => {
+                // saving would overwrite what couldn't be read
+                history_notes.push(Event::Warning(format!(
+                    "History not kept this session: {e}"
+                )));
+                (None, Vec::new())
+            }
+        },
+        None => (None, Vec::new()),
+    };
+    if history.is_none() && legacy.exists() {
+        history_notes.push(Event::Warning(format!(
+            "{} holds unredacted history of an earlier version, delete it",
+            legacy.display()
+        )));
+    }
 
-    let mut pgp_inbox: VecDeque<(String, String)> = VecDeque::new();
+    let (ui_tx, mut cmd_rx, prompter) = if use_tui {
+        tui::spawn_tui_thread(history, saved)
+    } else {
+        spawn_cli_thread(env.recipient_hints.clone(), renderer, history, saved)
+    };
+
+    let channel_id = env.set_channel_id(&cfg);
+    let state: Shared = Arc::new(Mutex::new(State {
+        env,
+        ..State::default()
+    }));
+    let send_status = |state: &Shared| {
+        let st = lock(state);
+        let status = UiStatus::snapshot(&st.env, &cfg, &st.pgp_inbox, &secret_keys);
+        let _ = ui_tx.send(UiEvent::Status(Box::new(status)));
+    };
+    send_status(&state);
+
+    let _ = ui_tx.send(UiEvent::Show(Event::Connected { channel_id }));
+    for note in history_notes {
+        let _ = ui_tx.send(UiEvent::Show(note));
+    }
 
-    let (ui_tx, mut cmd_rx) = spawn_cli_thread();
+    // decrypts finish in the order messages came in, while the loop
+    // below goes on reading the gateway
+    let (chat_tx, mut chat_rx) = mpsc::unbounded_channel::<(transport::ChatEvent, Vec<Event>)>();
+    let (started_tx, mut started_rx) = mpsc::unbounded_channel::<(transport::ChatEvent, Started)>();
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            while let Some((ev, started)) = started_rx.recv().await {
+                let done = resolve_chat_events(started).await;
+                let events = finish_chat_events(done, &mut lock(&state));
+                if chat_tx.send((ev, events)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
-    let _ = ui_tx.send(UiEvent::Line("discord — connected".to_string()));
-    let _ = ui_tx.send(UiEvent::Line(format!(
-        "Channel ID: {}",
-        env.set_channel_id(&cfg)
-    )));
-    let _ = ui_tx.send(UiEvent::Line("Commands: help\n".to_string()));
+    let (cmd_done_tx, mut cmd_done_rx) = mpsc::unbounded_channel();
 
     loop {
         tokio::select! {
             maybe = rx.recv() => {
                 if let Some(ev) = maybe {
-                    if ev.channel_id == env.set_channel_id(&cfg) {
-                        let lines = handle_chat_event(&ev, &mut pgp_inbox).await?;
-                        for s in lines {
-                            let _ = ui_tx.send(UiEvent::Line(s));
-                        }
+                    let mut st = lock(&state);
+                    if ev.channel_id == st.env.set_channel_id(&cfg) {
+                        let started = start_chat_events(std::slice::from_ref(&ev), true, &mut st, &gpg);
+                        let _ = started_tx.send((ev, started));
                     }
                 }
             }
 
+            Some((ev, events)) = chat_rx.recv() => {
+                if let Some(n) = &notifier {
+                    notify::message(n.as_ref(), &events, &ev, me, cfg.notify);
+                }
+                for e in events {
+                    show(&ui_tx, e);
+                }
+                send_status(&state);
+            }
+
             maybe = cmd_rx.recv() => {
                 let Some(line) = maybe else { break; };
+                let (cfg, state, gpg, prompter) =
+                    (cfg.clone(), state.clone(), gpg.clone(), prompter.clone());
+                let cmd_done_tx = cmd_done_tx.clone();
+
+                tokio::spawn(async move {
+                    // Ctrl-C while a command runs cancels it (and kills its gpg)
+                    let res = tokio::select! {
+                        res = handle_command(&line, &cfg, &state, &gpg, &prompter) => res,
+                        _ = tokio::signal::ctrl_c() => Err(anyhow!("cancelled")),
+                    };
+                    let _ = cmd_done_tx.send(res);
+                });
+            }
 
-                match handle_command(&line, &cfg, &mut env, &mut pgp_inbox).await {
-                    Ok((outcome, lines, ui_events)) => {
-                        for s in lines {
-                            let _ = ui_tx.send(UiEvent::Line(s));
+            Some(res) = cmd_done_rx.recv() => {
+                prompter.done();
+                send_status(&state);
+
+                match res {
+                    Ok((outcome, events, ui_events)) => {
+                        for ev in events {
+                            show(&ui_tx, ev);
                         }
                         for ev in ui_events {
                             let _ = ui_tx.send(ev);
@@ -260,17 +860,33 @@ async fn main() -> Result<()> {
                         }
                     }
                     Err(e) => {
-                        let _ = ui_tx.send(UiEvent::Line(render_error(&e.to_string())));
+                        let _ = ui_tx.send(UiEvent::Show(Event::Error(e.to_string())));
                     }
                 }
             }
-
         }
     }
 
     Ok(())
 }
 
+/// Show `ev`, and take revealed plaintext off the screen again when it asks to
+fn show(ui_tx: &mpsc::UnboundedSender<UiEvent>, ev: Event) {
+    if let Event::Decrypt {
+        id,
+        hide_after: Some(secs),
+        ..
+    } = &ev
+    {
+        let (ui_tx, id, secs) = (ui_tx.clone(), id.clone(), *secs);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            let _ = ui_tx.send(UiEvent::Hide { id });
+        });
+    }
+    let _ = ui_tx.send(UiEvent::Show(ev));
+}
+
 enum CmdOutcome {
     Continue,
     Quit,
@@ -279,497 +895,1594 @@ enum CmdOutcome {
 async fn handle_command(
     line: &str,
     cfg: &common::Config,
-    env: &mut SessionEnv,
-    pgp_inbox: &mut VecDeque<(String, String)>,
-) -> Result<(CmdOutcome, Vec<String>, Vec<UiEvent>)> {
-    let mut parts = line.split_whitespace();
-    let cmd = parts.next().ok_or_else(|| anyhow!("empty command"))?;
-
-    let mut out_lines: Vec<String> = Vec::new();
+    state: &Shared,
+    gpg: &crypto::gpg_async::Gpg,
+    prompter: &Prompter,
+) -> Result<(CmdOutcome, Vec<Event>, Vec<UiEvent>)> {
+    let mut args = cmdline::Args::new(line);
+    let cmd = args.next()?.ok_or_else(|| anyhow!("empty command"))?;
+
+    let mut out: Vec<Event> = Vec::new();
     let mut ui_events: Vec<UiEvent> = Vec::new();
 
-    match cmd {
+    match cmd.as_str() {
         "export" => {
-            let what = parts.next().unwrap_or("");
+            let what = args.next()?.unwrap_or_default();
 
-            match what {
+            match what.as_str() {
                 "recipient" => {
-                    let v = parts.collect::<Vec<_>>().join(" ").trim().to_string();
+                    let v = args.value()?;
                     if v.is_empty() {
-                        return Err(anyhow!("Usage: export recipient <fpr|uid>"));
+                        return Err(anyhow!("Usage: export recipient <fpr|email|name|alias>"));
                     }
-                    env.default_fpr = Some(v.clone());
-                    out_lines.push(format!("{} {}", "exported recipient =".green(), v.cyan()));
+                    let keys = gpg.list_public_keys().await?;
+                    let mut st = lock(state);
+                    let key = recipient::resolve(&v, &keys, &st.env.contacts)?;
+                    st.env.default_fpr = Some(key.fpr.clone());
+                    out.push(Event::Exported {
+                        name: "recipient".into(),
+                        value: key.fpr.clone(),
+                        detail: key.uid.clone(),
+                    });
                 }
 
                 "channel" => {
-                    let v = parts
-                        .next()
-                        .ok_or_else(|| anyhow!("Usage: export channel <channel_id>"))?;
+                    let v = args.expect("Usage: export channel <channel_id>")?;
                     let ch: u64 = v
                         .parse()
                         .map_err(|_| anyhow!("channel_id must be an integer"))?;
-                    env.channel_id = Some(ch);
-                    out_lines.push(format!(
-                        "{} {}",
-                        "exported channel =".green(),
-                        ch.to_string().cyan()
+                    lock(state).env.channel_id = Some(ch);
+                    out.push(Event::Exported {
+                        name: "channel".into(),
+                        value: ch.to_string(),
+                        detail: None,
+                    });
+                    out.push(Event::Info(
+                        "Note: now listening/sending only in this channel.".into(),
                     ));
-                    out_lines.push(
-                        "Note: now listening/sending only in this channel."
-                            .dimmed()
-                            .to_string(),
-                    );
+                }
+
+                "passphrase" => {
+                    let ch = lock(state).env.set_channel_id(cfg);
+                    let pass = prompter
+                        .secret(&format!("Passphrase for channel {ch}: "))
+                        .await?;
+                    lock(state).env.passphrases.insert(ch, pass);
+                    out.push(Event::Exported {
+                        name: "passphrase".into(),
+                        value: "(set)".into(),
+                        detail: Some(format!("for channel {ch}")),
+                    });
+                }
+
+                "passphrase-ttl" => {
+                    let v = args.expect("Usage: export passphrase-ttl <seconds>")?;
+                    let secs: u64 = v
+                        .parse()
+                        .map_err(|_| anyhow!("passphrase-ttl must be a number of seconds"))?;
+                    let mut st = lock(state);
+                    let env = &mut st.env;
+                    env.passphrase_ttl = (secs > 0).then(|| Duration::from_secs(secs));
+                    if env.passphrase_ttl.is_none() {
+                        env.key_passphrase = None;
+                    }
+                    out.push(Event::Exported {
+                        name: "passphrase-ttl".into(),
+                        value: format!("{secs}s"),
+                        detail: None,
+                    });
+                }
+
+                "auto-decrypt" => {
+                    const USAGE: &str =
+                        "Usage: export auto-decrypt <always|contacts|never> [--default]";
+                    let v = args.expect(USAGE)?;
+                    let policy = common::DecryptPolicy::parse(&v).ok_or_else(|| anyhow!(USAGE))?;
+                    let mut st = lock(state);
+                    let env = &mut st.env;
+                    let detail = match args.next()?.as_deref() {
+                        Some("--default") => {
+                            env.auto_decrypt.default = policy;
+                            "session default".to_string()
+                        }
+                        None => {
+                            let ch = env.set_channel_id(cfg);
+                            env.auto_decrypt.channels.insert(ch, policy);
+                            format!("for channel {ch}")
+                        }
+                        Some(_) => return Err(anyhow!(USAGE)),
+                    };
+                    out.push(Event::Exported {
+                        name: "auto-decrypt".into(),
+                        value: policy.as_str().into(),
+                        detail: Some(detail),
+                    });
+                }
+
+                "decrypt-rate" => {
+                    let v = args.expect("Usage: export decrypt-rate <attempts per minute>")?;
+                    let mut st = lock(state);
+                    let env = &mut st.env;
+                    env.auto_decrypt.rate = v
+                        .parse()
+                        .map_err(|_| anyhow!("decrypt-rate must be a number (0 = no limit)"))?;
+                    out.push(Event::Exported {
+                        name: "decrypt-rate".into(),
+                        value: format!("{}/min", env.auto_decrypt.rate),
+                        detail: Some("per author".into()),
+                    });
+                }
+
+                "ephemeral" => {
+                    let v = args.expect("Usage: export ephemeral <seconds>")?;
+                    let secs: u64 = v
+                        .parse()
+                        .map_err(|_| anyhow!("ephemeral must be a number of seconds"))?;
+                    lock(state).env.ephemeral = (secs > 0).then(|| Duration::from_secs(secs));
+                    out.push(Event::Exported {
+                        name: "ephemeral".into(),
+                        value: format!("{secs}s"),
+                        detail: Some("incoming plaintext hidden until pgp show".into()),
+                    });
                 }
 
                 "show" => {
-                    out_lines.push("Session exports:".bold().to_string());
-                    out_lines.push(format!(
-                        "  {} {}",
-                        "channel".dimmed(),
-                        env.set_channel_id(cfg).to_string().cyan()
-                    ));
-                    out_lines.push(format!(
-                        "  {} {}",
-                        "recipient".dimmed(),
-                        env.default_fpr.as_deref().unwrap_or("(not set)").cyan()
-                    ));
+                    let st = lock(state);
+                    let env = &st.env;
+                    let ch = env.set_channel_id(cfg);
+                    out.push(Event::Exports {
+                        channel_id: ch,
+                        recipient: env.default_fpr.clone(),
+                        passphrase: env.passphrases.contains_key(&ch),
+                        passphrase_ttl: env.passphrase_ttl.map(|t| t.as_secs()),
+                        auto_decrypt: env.auto_decrypt.policy_for(ch),
+                        decrypt_rate: env.auto_decrypt.rate,
+                        ephemeral: env.ephemeral.map(|t| t.as_secs()),
+                    });
                 }
 
                 "unset" => {
-                    let which = parts.next().unwrap_or("");
-                    match which {
+                    let which = args.next()?.unwrap_or_default();
+                    let mut st = lock(state);
+                    let env = &mut st.env;
+                    match which.as_str() {
                         "recipient" => {
                             env.default_fpr = None;
-                            out_lines.push("unset recipient".yellow().to_string());
+                            out.push(Event::Unset {
+                                name: "recipient".into(),
+                                detail: None,
+                            });
+                        }
+                        "passphrase" => {
+                            env.passphrases.remove(&env.set_channel_id(cfg));
+                            out.push(Event::Unset {
+                                name: "passphrase".into(),
+                                detail: None,
+                            });
+                        }
+                        "passphrase-ttl" => {
+                            env.passphrase_ttl = None;
+                            env.key_passphrase = None;
+                            out.push(Event::Unset {
+                                name: "passphrase-ttl".into(),
+                                detail: Some("cached key passphrase dropped".into()),
+                            });
+                        }
+                        "ephemeral" => {
+                            env.ephemeral = None;
+                            out.push(Event::Unset {
+                                name: "ephemeral".into(),
+                                detail: Some("plaintext is shown right away".into()),
+                            });
+                        }
+                        "auto-decrypt" => {
+                            let ch = env.set_channel_id(cfg);
+                            env.auto_decrypt.channels.remove(&ch);
+                            out.push(Event::Unset {
+                                name: "auto-decrypt".into(),
+                                detail: Some(format!(
+                                    "channel {ch} follows the default, {}",
+                                    env.auto_decrypt.default.as_str()
+                                )),
+                            });
                         }
                         "channel" => {
                             env.channel_id = None;
-                            out_lines.push(format!(
-                                "{} {}",
-                                "unset channel (back to env)".yellow(),
-                                cfg.channel_id.to_string().cyan()
+                            out.push(Event::Unset {
+                                name: "channel".into(),
+                                detail: Some(format!("back to env {}", cfg.channel_id)),
+                            });
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "Usage: export unset <recipient|channel|passphrase|passphrase-ttl|auto-decrypt>"
                             ));
                         }
-                        _ => return Err(anyhow!("Usage: export unset <fpr|channel>")),
                     }
                 }
 
-                _ => return Err(anyhow!("Usage: export <recipient|channel|show|unset> ...")),
+                _ => {
+                    return Err(anyhow!(
+                        "Usage: export <recipient|channel|passphrase|passphrase-ttl|auto-decrypt|decrypt-rate|show|unset> ..."
+                    ));
+                }
             }
 
-            return Ok((CmdOutcome::Continue, out_lines, ui_events));
+            Ok((CmdOutcome::Continue, out, ui_events))
+        }
+        "contact" => {
+            let sub = args.next()?.unwrap_or_default();
+            match sub.as_str() {
+                "add" => {
+                    const USAGE: &str = "Usage: contact add <alias> <fpr|email|name>";
+                    let alias = args.expect(USAGE)?;
+                    let query = args.value()?;
+                    if query.is_empty() {
+                        return Err(anyhow!(USAGE));
+                    }
+                    let keys = gpg.list_public_keys().await?;
+                    let mut st = lock(state);
+                    let env = &mut st.env;
+                    let found = recipient::candidates(&query, &keys, &env.contacts);
+                    let key = match found.as_slice() {
+                        [k] => *k,
+                        [] => return Err(anyhow!("No public key matches '{query}' (see: keys)")),
+                        _ => {
+                            return Err(anyhow!(
+                                "'{query}' matches {} keys, use a longer fingerprint",
+                                found.len()
+                            ));
+                        }
+                    };
+                    env.contacts.insert(alias.clone(), key.fpr.clone());
+                    env.refresh_recipient_hints(&keys);
+                    out.push(Event::ContactAdded {
+                        alias,
+                        fpr: key.fpr.clone(),
+                    });
+                }
+                "rm" => {
+                    let alias = args.expect("Usage: contact rm <alias>")?;
+                    {
+                        let mut st = lock(state);
+                        if st.env.contacts.remove(&alias).is_none() {
+                            return Err(anyhow!("No contact named {alias}"));
+                        }
+                        st.env.authors.retain(|_, a| *a != alias);
+                    }
+                    if let Ok(keys) = gpg.list_public_keys().await {
+                        lock(state).env.refresh_recipient_hints(&keys);
+                    }
+                    out.push(Event::ContactRemoved { alias });
+                }
+                "link" => {
+                    const USAGE: &str = "Usage: contact link <alias> <discord user id|@mention>";
+                    let alias = args.expect(USAGE)?;
+                    let user = args.expect(USAGE)?;
+                    let mut st = lock(state);
+                    let env = &mut st.env;
+                    if !env.contacts.contains_key(&alias) {
+                        return Err(anyhow!("No contact named {alias}"));
+                    }
+                    let id = recipient::parse_user_id(&user).ok_or_else(|| anyhow!(USAGE))?;
+                    env.authors.insert(id, alias.clone());
+                    out.push(Event::Info(format!(
+                        "Messages from Discord user {id} now count as {alias} for auto-decrypt"
+                    )));
+                }
+                "list" => {
+                    out.push(Event::Contacts(
+                        lock(state)
+                            .env
+                            .contacts
+                            .iter()
+                            .map(|(a, f)| (a.clone(), f.clone()))
+                            .collect(),
+                    ));
+                }
+                _ => return Err(anyhow!("Usage: contact <add|rm|link|list> ...")),
+            }
+            Ok((CmdOutcome::Continue, out, ui_events))
         }
         "clear" => {
             ui_events.push(UiEvent::Clear);
-            return Ok((CmdOutcome::Continue, out_lines, ui_events));
+            Ok((CmdOutcome::Continue, out, ui_events))
+        }
+
+        "panic" => {
+            let mut st = lock(state);
+            st.log.wipe_plaintext();
+            let env = &mut st.env;
+            env.passphrases.clear();
+            env.key_passphrase = None;
+            env.sessions.clear();
+            env.groups.clear();
+            prompter.wipe();
+            ui_events.push(UiEvent::Wipe);
+            // after the wipe, or it would go with it
+            ui_events.push(UiEvent::Show(Event::Warning(
+                "Screen, input history, plaintext, passphrases, sessions and group keys cleared."
+                    .into(),
+            )));
+            Ok((CmdOutcome::Continue, out, ui_events))
         }
 
         "help" | "h" | "?" => {
-            out_lines.push(render_help());
-            return Ok((CmdOutcome::Continue, out_lines, ui_events));
+            out.push(Event::Help);
+            Ok((CmdOutcome::Continue, out, ui_events))
         }
 
         "me" => {
-            if !crypto::gpg::available()? {
-                out_lines.push(render_warn("gpg not found."));
-                return Ok((CmdOutcome::Continue, out_lines, ui_events));
+            if !gpg.available().await? {
+                out.push(Event::Warning("gpg not found.".into()));
+                return Ok((CmdOutcome::Continue, out, ui_events));
             }
 
-            out_lines.push(crypto::gpg::version_line()?.dimmed().to_string());
+            out.push(Event::SecretKeys {
+                gpg_version: gpg.version_line().await?,
+                fprs: gpg.list_secret_fingerprints().await?,
+            });
 
-            let fprs = crypto::gpg::list_secret_fingerprints()?;
-            if fprs.is_empty() {
-                out_lines.push(render_warn("No secret keys found in your GPG keyring."));
-            } else {
-                out_lines.push("Secret key fingerprints:".bold().to_string());
-                for f in fprs {
-                    out_lines.push(format!("  {}", f.dimmed()));
+            Ok((CmdOutcome::Continue, out, ui_events))
+        }
+
+        "send" | "s" => {
+            let msg = match args.rest() {
+                m if m.trim().is_empty() => prompter.compose().await?,
+                m => SecretString::new(m.to_string()),
+            };
+
+            let ch = lock(state).env.set_channel_id(cfg);
+            transport::send_message(&cfg.token, ch, &msg).await?;
+            out.push(Event::Sent { channel_id: ch });
+            Ok((CmdOutcome::Continue, out, ui_events))
+        }
+
+        "sign" => {
+            let msg = match args.rest() {
+                m if m.trim().is_empty() => prompter.compose().await?,
+                m => SecretString::new(m.to_string()),
+            };
+
+            let ch = lock(state).env.set_channel_id(cfg);
+            let armored = sign_or_prompt(&msg, None, ch, state, gpg, prompter).await?;
+            transport::send_message(&cfg.token, ch, &armored).await?;
+            out.push(Event::Signed { channel_id: ch });
+            Ok((CmdOutcome::Continue, out, ui_events))
+        }
+
+        "group" => {
+            const USAGE: &str = "Usage: group create <member...> | add <member> | rm <member> \
+                                 | rotate | send [message...] | show | leave";
+
+            let ch = lock(state).env.set_channel_id(cfg);
+            let sub = args.next()?.unwrap_or_default();
+            match sub.as_str() {
+                "create" | "add" | "rm" | "rotate" => {
+                    let keys = gpg.list_public_keys().await?;
+                    let own = gpg.list_secret_primaries().await?;
+                    let (current, contacts) = {
+                        let st = lock(state);
+                        (st.env.groups.key(ch).cloned(), st.env.contacts.clone())
+                    };
+                    let mut members = match (&current, sub.as_str()) {
+                        (_, "create") => {
+                            // our key first, what gpg signs with by default
+                            let Some(own) = own.first() else {
+                                return Err(anyhow!("No secret key to sign with"));
+                            };
+                            vec![own.clone()]
+                        }
+                        (Some(key), _) => key.members.clone(),
+                        (None, _) => {
+                            return Err(anyhow!(
+                                "No group in this channel (group create <member...>)"
+                            ));
+                        }
+                    };
+
+                    match sub.as_str() {
+                        "create" => {
+                            while let Some(q) = args.next()? {
+                                members.push(recipient::resolve(&q, &keys, &contacts)?.fpr.clone());
+                            }
+                            if members.len() < 2 {
+                                return Err(anyhow!(USAGE));
+                            }
+                        }
+                        "add" => {
+                            let q = args.expect(USAGE)?;
+                            members.push(recipient::resolve(&q, &keys, &contacts)?.fpr.clone());
+                        }
+                        "rm" => {
+                            let q = args.expect(USAGE)?;
+                            let fpr = groups::find_member(&q, &members, &keys, &contacts)?;
+                            if own.iter().any(|f| f.eq_ignore_ascii_case(&fpr)) {
+                                return Err(anyhow!("That's your key, to leave: group leave"));
+                            }
+                            members.retain(|m| *m != fpr);
+                        }
+                        _ => {}
+                    }
+
+                    let key = match current {
+                        Some(current) if sub != "create" => current.rotate(members),
+                        _ => crypto::group::GroupKey::new(members),
+                    };
+                    let armored = sign_or_prompt(
+                        &key.distribution(),
+                        Some(&key.members),
+                        ch,
+                        state,
+                        gpg,
+                        prompter,
+                    )
+                    .await?;
+                    // every member adds a key packet, compact holds more than twice as many
+                    let compact = armored.chars().count() > MESSAGE_LIMIT;
+                    let text = outgoing(armored, compact, false)?;
+                    transport::send_message(&cfg.token, ch, &text).await?;
+                    out.push(Event::Info(format!(
+                        "Group {} key epoch {} sent to {} members",
+                        ids::short(&key.gid),
+                        key.epoch,
+                        key.members.len()
+                    )));
+                    lock(state).env.groups.set(ch, key, "you");
                 }
-            }
 
-            return Ok((CmdOutcome::Continue, out_lines, ui_events));
+                "send" => {
+                    let Some(key) = lock(state).env.groups.key(ch).cloned() else {
+                        return Err(anyhow!(
+                            "No group in this channel (group create <member...>)"
+                        ));
+                    };
+                    let msg = match args.rest() {
+                        m if m.trim().is_empty() => prompter.compose().await?,
+                        m => SecretString::new(m.to_string()),
+                    };
+                    let opts = crypto::gpg_async::EncryptOpts {
+                        hidden: false,
+                        pad: cfg.pad,
+                    };
+                    let armored = gpg.encrypt_symmetric(&key.passphrase(), &msg, opts).await?;
+                    transport::send_message(&cfg.token, ch, &armored).await?;
+                    out.push(Event::SentGroup {
+                        channel_id: ch,
+                        group: key.gid,
+                        epoch: key.epoch,
+                        members: key.members.len(),
+                    });
+                }
+
+                "show" => out.push(Event::Group {
+                    channel_id: ch,
+                    info: lock(state).env.groups.info(ch),
+                }),
+
+                "leave" => {
+                    if !lock(state).env.groups.leave(ch) {
+                        return Err(anyhow!("No group in this channel"));
+                    }
+                    out.push(Event::Info(
+                        "Group keys forgotten. Members still have them until one runs: group rm <you>"
+                            .into(),
+                    ));
+                }
+
+                _ => return Err(anyhow!(USAGE)),
+            }
+            Ok((CmdOutcome::Continue, out, ui_events))
         }
 
-        "send" | "s" => {
-            let msg = parts.collect::<Vec<_>>().join(" ");
-            if msg.is_empty() {
-                return Err(anyhow!("Usage: send <message...>"));
+        "session" => {
+            const USAGE: &str = "Usage: session start <recipient> | accept <id> | send <id> [message...] | list | end <id>";
+            use sessions::SessionState;
+
+            let sub = args.next()?.unwrap_or_default();
+            match sub.as_str() {
+                "start" => {
+                    let query = args.expect(USAGE)?;
+                    let keys = gpg.list_public_keys().await?;
+                    let (peer, ch, init) = {
+                        let mut st = lock(state);
+                        let env = &mut st.env;
+                        let key = recipient::resolve(&query, &keys, &env.contacts)?;
+                        let peer = key.uid.clone().unwrap_or_else(|| key.fpr.clone());
+
+                        let ch = env.set_channel_id(cfg);
+                        let init = env.sessions.start(&key.fpr, &peer, ch);
+                        (peer, ch, init)
+                    };
+                    let armored =
+                        match sign_or_prompt(&init.to_string(), None, ch, state, gpg, prompter)
+                            .await
+                        {
+                            Ok(armored) => armored,
+                            Err(e) => {
+                                lock(state).env.sessions.end(init.sid());
+                                return Err(e);
+                            }
+                        };
+                    transport::send_message(&cfg.token, ch, &armored).await?;
+                    out.push(Event::Info(format!(
+                        "Session {} requested, waiting for {peer} to accept",
+                        ids::short(init.sid())
+                    )));
+                }
+
+                "accept" => {
+                    let (sid, info, accept, hs) = {
+                        let st = lock(state);
+                        let sid = st
+                            .env
+                            .sessions
+                            .resolve(&args.expect(USAGE)?, &[SessionState::Offered])?;
+                        let Some(info) = st.env.sessions.info(&sid) else {
+                            return Err(anyhow!("No session with id={sid}"));
+                        };
+                        // signed before the keys change, a locked key leaves the offer open
+                        let (accept, hs) = st.env.sessions.accept(&sid)?;
+                        (sid, info, accept, hs)
+                    };
+                    let armored = sign_or_prompt(
+                        &accept.to_string(),
+                        None,
+                        info.channel_id,
+                        state,
+                        gpg,
+                        prompter,
+                    )
+                    .await?;
+                    transport::send_message(&cfg.token, info.channel_id, &armored).await?;
+                    lock(state).env.sessions.accepted(&sid, hs)?;
+                    out.push(Event::Info(format!(
+                        "Session {} with {} established",
+                        ids::short(&sid),
+                        info.peer
+                    )));
+                }
+
+                "send" => {
+                    let sid = lock(state)
+                        .env
+                        .sessions
+                        .resolve(&args.expect(USAGE)?, &[SessionState::Active])?;
+                    let msg = match args.rest() {
+                        m if m.trim().is_empty() => prompter.compose().await?,
+                        m => SecretString::new(m.to_string()),
+                    };
+                    let msg = match cfg.pad {
+                        true => crypto::pad::pad(&msg)?,
+                        false => msg,
+                    };
+
+                    let (info, (ch, wire)) = {
+                        let mut st = lock(state);
+                        let Some(info) = st.env.sessions.info(&sid) else {
+                            return Err(anyhow!("No session with id={sid}"));
+                        };
+                        (info, st.env.sessions.encrypt(&sid, &msg)?)
+                    };
+                    transport::send_message(&cfg.token, ch, &wire).await?;
+                    out.push(Event::SentSession {
+                        channel_id: ch,
+                        session: sid,
+                        to: info.peer,
+                    });
+                }
+
+                "list" => out.push(Event::Sessions(lock(state).env.sessions.list())),
+
+                "end" => {
+                    let mut st = lock(state);
+                    let sid = st.env.sessions.resolve(
+                        &args.expect(USAGE)?,
+                        &[
+                            SessionState::Started,
+                            SessionState::Offered,
+                            SessionState::Active,
+                        ],
+                    )?;
+                    st.env.sessions.end(&sid);
+                    out.push(Event::Info(format!(
+                        "Session {} ended, its keys are gone",
+                        ids::short(&sid)
+                    )));
+                }
+
+                _ => return Err(anyhow!(USAGE)),
             }
+            Ok((CmdOutcome::Continue, out, ui_events))
+        }
 
-            transport::send_message(&cfg.token, env.set_channel_id(&cfg), &msg).await?;
-            out_lines.push(render_outgoing_sent());
-            return Ok((CmdOutcome::Continue, out_lines, ui_events));
+        "search" => {
+            let q = search::Query::parse(args)?;
+            let hits = lock(state).log.search(&q);
+            out.push(Event::SearchResults {
+                query: q.text,
+                hits,
+            });
+            Ok((CmdOutcome::Continue, out, ui_events))
         }
 
-        "keys" => {
-            let keys = crypto::gpg::list_public_keys()?;
-            if keys.is_empty() {
-                out_lines.push(render_warn("No public keys found in your GPG keyring."));
-            } else {
-                out_lines.push("Public keys (recipients):".bold().to_string());
-                for k in keys {
-                    match k.uid {
-                        Some(uid) => out_lines.push(format!("  {}  —  {}", k.fpr.dimmed(), uid)),
-                        _ => out_lines.push(format!("  {}", k.fpr.dimmed())),
+        "archive" => {
+            const USAGE: &str = "Usage: archive export [--count <n>] [--format jsonl|mbox] \
+                                 [--plaintext] [--session] <file> | archive import <file>";
+
+            let sub = args.next()?.unwrap_or_default();
+            match sub.as_str() {
+                "export" => {
+                    let mut count = 100usize;
+                    let mut format = None;
+                    let mut with_plaintext = false;
+                    let mut from_session = false;
+
+                    while let Some(flag) = args.peek_flag() {
+                        match flag.as_str() {
+                            "--count" | "-n" => {
+                                args.next()?;
+                                count = args
+                                    .expect(USAGE)?
+                                    .parse()
+                                    .map_err(|_| anyhow!("--count must be a number"))?;
+                            }
+                            "--format" => {
+                                args.next()?;
+                                let f = args.expect(USAGE)?;
+                                format =
+                                    Some(archive::Format::parse(&f).ok_or_else(|| {
+                                        anyhow!("--format must be jsonl or mbox")
+                                    })?);
+                            }
+                            "--plaintext" => {
+                                args.next()?;
+                                with_plaintext = true;
+                            }
+                            "--session" => {
+                                args.next()?;
+                                from_session = true;
+                            }
+                            "--" => {
+                                args.next()?;
+                                break;
+                            }
+                            _ => break,
+                        }
                     }
+
+                    let path = std::path::PathBuf::from(args.expect(USAGE)?);
+                    let format = format.unwrap_or_else(|| archive::Format::for_path(&path));
+                    let ch = lock(state).env.set_channel_id(cfg);
+
+                    let messages: Vec<transport::ChatEvent> = if from_session {
+                        let all: Vec<_> = lock(state)
+                            .log
+                            .iter()
+                            .filter(|e| e.message.channel_id == ch && e.part == 0)
+                            .map(|e| e.message.clone())
+                            .collect();
+                        all[all.len().saturating_sub(count)..].to_vec()
+                    } else {
+                        transport::fetch_messages(&cfg.token, ch, count).await?
+                    };
+
+                    let secrets = lock(state).env.secrets_for(ch);
+                    let records = archive::records(&messages, gpg, &secrets).await;
+                    archive::write(&path, format, &records, with_plaintext)?;
+
+                    out.push(Event::ArchiveExported {
+                        path: path.display().to_string(),
+                        messages: records.len(),
+                        encrypted: records.iter().filter(|r| !r.pgp.is_empty()).count(),
+                        plaintext: with_plaintext,
+                    });
+                }
+
+                "import" => {
+                    let path = std::path::PathBuf::from(args.expect(USAGE)?);
+                    let messages = archive::read(&path)?;
+
+                    out.push(Event::ArchiveImported {
+                        path: path.display().to_string(),
+                        messages: messages.len(),
+                    });
+                    out.extend(handle_chat_events(&messages, state, gpg).await);
                 }
+
+                _ => return Err(anyhow!(USAGE)),
             }
-            return Ok((CmdOutcome::Continue, out_lines, ui_events));
+            Ok((CmdOutcome::Continue, out, ui_events))
+        }
+
+        "keys" => {
+            let keys = gpg.list_public_keys().await?;
+            lock(state).env.refresh_recipient_hints(&keys);
+            out.push(Event::KeyList(keys));
+            Ok((CmdOutcome::Continue, out, ui_events))
         }
 
         "load" => {
-            let n_str = parts.next().ok_or_else(|| anyhow!("Usage: load <count>"))?;
+            let n_str = args.expect("Usage: load <count>")?;
             let n: usize = n_str
                 .parse()
                 .map_err(|_| anyhow!("load <count> must be a number"))?;
 
-            let history =
-                transport::fetch_messages(&cfg.token, env.set_channel_id(&cfg), n).await?;
+            let ch = lock(state).env.set_channel_id(cfg);
+            let history = transport::fetch_messages(&cfg.token, ch, n).await?;
             if history.is_empty() {
-                out_lines.push(render_warn("No messages returned."));
-                return Ok((CmdOutcome::Continue, out_lines, ui_events));
+                out.push(Event::Warning("No messages returned.".into()));
+                return Ok((CmdOutcome::Continue, out, ui_events));
             }
 
-            out_lines.push(format!(
-                "{} {}/{} {}",
-                "Loading".bold(),
-                history.len().to_string().cyan(),
-                n.to_string().cyan(),
-                "messages...".bold()
-            ));
+            out.push(Event::Loading {
+                fetched: history.len(),
+                requested: n,
+            });
 
-            for ev in history {
-                let lines = handle_chat_event(&ev, pgp_inbox).await?;
-                out_lines.extend(lines);
-            }
+            out.extend(handle_chat_events(&history, state, gpg).await);
 
-            return Ok((CmdOutcome::Continue, out_lines, ui_events));
+            Ok((CmdOutcome::Continue, out, ui_events))
         }
 
         "pgp" => {
-            let sub = parts.next().unwrap_or("");
-            match sub {
+            let sub = args.next()?.unwrap_or_default();
+            match sub.as_str() {
                 "list" => {
-                    if pgp_inbox.is_empty() {
-                        out_lines.push(render_warn("No PGP messages captured yet."));
-                    } else {
-                        out_lines.push("Captured PGP messages (latest last):".bold().to_string());
-                        for (id, block) in pgp_inbox.iter() {
-                            out_lines.push(format!(
-                                "  {} {} {}",
-                                "id=".dimmed(),
-                                id.purple(),
-                                format!("({} chars)", block.len()).dimmed()
-                            ));
-                        }
-                    }
-                    return Ok((CmdOutcome::Continue, out_lines, ui_events));
+                    out.push(Event::PgpList(
+                        lock(state).pgp_inbox.iter().cloned().collect(),
+                    ));
+                    Ok((CmdOutcome::Continue, out, ui_events))
                 }
 
                 "decrypt-last" => {
-                    let Some((id, block)) = pgp_inbox.back().cloned() else {
-                        out_lines.push(render_warn("No PGP messages captured yet."));
-                        return Ok((CmdOutcome::Continue, out_lines, ui_events));
+                    let Some(CapturedPgp {
+                        id,
+                        block,
+                        channel_id,
+                        ..
+                    }) = lock(state).pgp_inbox.back().cloned()
+                    else {
+                        out.push(Event::Warning("No PGP messages captured yet.".into()));
+                        return Ok((CmdOutcome::Continue, out, ui_events));
                     };
 
-                    out_lines.extend(render_decrypt_attempt(&id, &block));
-                    return Ok((CmdOutcome::Continue, out_lines, ui_events));
+                    let res =
+                        decrypt_or_prompt(&block, channel_id, false, state, gpg, prompter).await;
+                    out.extend(decrypted(id, channel_id, res, &mut lock(state)));
+                    Ok((CmdOutcome::Continue, out, ui_events))
                 }
 
                 "decrypt" => {
-                    let id = parts
-                        .next()
-                        .ok_or_else(|| anyhow!("Usage: pgp decrypt <id>"))?;
-                    let Some((_id, block)) = pgp_inbox.iter().find(|(i, _)| i == id).cloned()
-                    else {
-                        return Err(anyhow!("No captured PGP message with id={id}"));
+                    const USAGE: &str = "Usage: pgp decrypt <id> [--any-passphrase]";
+                    let mut id = None;
+                    let mut any_channel = false;
+                    while let Some(word) = args.next()? {
+                        match word.as_str() {
+                            // every channel's passphrase, not only where it was posted
+                            "--any-passphrase" | "-a" => any_channel = true,
+                            _ if id.is_none() => id = Some(word),
+                            _ => return Err(anyhow!(USAGE)),
+                        }
+                    }
+                    let id = id.ok_or_else(|| anyhow!(USAGE))?;
+                    let (id, block, channel_id) = {
+                        let st = lock(state);
+                        let id = ids::resolve(&id, st.pgp_inbox.iter().map(|c| c.id.as_str()))?;
+                        let Some((block, channel_id)) = st
+                            .pgp_inbox
+                            .iter()
+                            .find(|c| c.id == id)
+                            .map(|c| (c.block.clone(), c.channel_id))
+                        else {
+                            return Err(anyhow!("No captured PGP message with id={id}"));
+                        };
+                        (id, block, channel_id)
                     };
 
-                    out_lines.extend(render_decrypt_attempt(id, &block));
-                    return Ok((CmdOutcome::Continue, out_lines, ui_events));
+                    let res =
+                        decrypt_or_prompt(&block, channel_id, any_channel, state, gpg, prompter)
+                            .await;
+                    out.extend(decrypted(id, channel_id, res, &mut lock(state)));
+                    Ok((CmdOutcome::Continue, out, ui_events))
+                }
+
+                "show" => {
+                    let id = args.expect("Usage: pgp show <id>")?;
+                    let (id, cached, captured) = {
+                        let st = lock(state);
+                        let id = ids::resolve(
+                            &id,
+                            st.pgp_inbox
+                                .iter()
+                                .map(|c| c.id.as_str())
+                                .chain(st.log.pgp_ids()),
+                        )?;
+                        let captured = st
+                            .pgp_inbox
+                            .iter()
+                            .find(|c| c.id == id)
+                            .map(|c| (c.block.clone(), c.channel_id));
+                        (id.clone(), st.log.decrypted(&id), captured)
+                    };
+                    // no second gpg run for a block that already decrypted
+                    let res = match (cached, captured) {
+                        (Some(d), _) => Ok(d),
+                        (None, None) => {
+                            return Err(anyhow!("No captured PGP message with id={id}"));
+                        }
+                        (None, Some((block, channel_id))) => {
+                            let res =
+                                decrypt_or_prompt(&block, channel_id, false, state, gpg, prompter)
+                                    .await;
+                            let mut st = lock(state);
+                            set_status(&mut st.pgp_inbox, &id, PgpStatus::of(&res));
+                            if let Ok(d) = &res {
+                                st.log.set_decrypted(&id, d);
+                            }
+                            res
+                        }
+                    };
+                    out.push(decrypt_event(id, res, lock(state).env.ephemeral));
+                    Ok((CmdOutcome::Continue, out, ui_events))
                 }
 
                 "send" => {
-                    let first = parts.next().ok_or_else(|| {
-                        anyhow!(
-                            "Usage: pgp send <message...> OR pgp send -r <fpr|uid> <message...>"
-                        )
-                    })?;
+                    const USAGE: &str = "Usage: pgp send [-r|-R <fpr|email|name|alias> | --symmetric] [--throw-keyids] [--pad] [--compact] [--code] [message...]";
 
                     let mut recipient: Option<String> = None;
-                    let mut msg_parts: Vec<String> = Vec::new();
-
-                    if matches!(first, "-r") {
-                        let r = parts
-                            .next()
-                            .ok_or_else(|| anyhow!("Usage: pgp send -r <fpr|uid> <message...>"))?;
-                        recipient = Some(r.to_string());
-                        msg_parts = parts.map(|s| s.to_string()).collect();
-                        if msg_parts.is_empty() {
-                            return Err(anyhow!("Usage: pgp send -r <fpr|uid> <message...>"));
+                    let mut symmetric = false;
+                    let mut opts = crypto::gpg_async::EncryptOpts {
+                        hidden: false,
+                        pad: cfg.pad,
+                    };
+                    let mut compact = false;
+                    let mut code = false;
+
+                    // flags first; `--` ends them, everything after is the message
+                    while let Some(flag) = args.peek_flag() {
+                        match flag.as_str() {
+                            "-r" => {
+                                args.next()?;
+                                recipient = Some(args.expect(USAGE)?);
+                            }
+                            // like gpg: a recipient left out of the message
+                            "-R" => {
+                                args.next()?;
+                                recipient = Some(args.expect(USAGE)?);
+                                opts.hidden = true;
+                            }
+                            "--throw-keyids" => {
+                                args.next()?;
+                                opts.hidden = true;
+                            }
+                            "--pad" | "-p" => {
+                                args.next()?;
+                                opts.pad = true;
+                            }
+                            "--symmetric" | "-c" => {
+                                args.next()?;
+                                symmetric = true;
+                            }
+                            "--compact" | "-z" => {
+                                args.next()?;
+                                compact = true;
+                            }
+                            "--code" | "-b" => {
+                                args.next()?;
+                                code = true;
+                            }
+                            "--" => {
+                                args.next()?;
+                                break;
+                            }
+                            _ => break,
                         }
-                    } else {
-                        // No recipient flag
-                        msg_parts.push(first.to_string());
-                        msg_parts.extend(parts.map(|s| s.to_string()));
+                    }
+
+                    if symmetric && (recipient.is_some() || opts.hidden) {
+                        return Err(anyhow!(USAGE));
+                    }
+
+                    let msg = match args.rest() {
+                        m if m.trim().is_empty() => prompter.compose().await?,
+                        m => SecretString::new(m.to_string()),
+                    };
+                    let (ch, pass, default_fpr) = {
+                        let st = lock(state);
+                        let ch = st.env.set_channel_id(cfg);
+                        (
+                            ch,
+                            st.env.passphrases.get(&ch).cloned(),
+                            st.env.default_fpr.clone(),
+                        )
+                    };
+
+                    if symmetric {
+                        let pass = match pass {
+                            Some(p) => p,
+                            None => prompter.secret("Passphrase: ").await?,
+                        };
+
+                        let armored = gpg.encrypt_symmetric(&pass, &msg, opts).await?;
+                        transport::send_message(&cfg.token, ch, &outgoing(armored, compact, code)?)
+                            .await?;
+
+                        out.push(Event::SentPgp {
+                            channel_id: ch,
+                            to: None,
+                            hidden: false,
+                        });
+                        return Ok((CmdOutcome::Continue, out, ui_events));
                     }
 
                     let recipient = match recipient {
                         Some(r) => r,
-                        _ => env.default_fpr.clone().ok_or_else(|| {
+                        _ => default_fpr.ok_or_else(|| {
                             anyhow!("No exported recipient set. Use: export recipient <fpr|uid>")
                         })?,
                     };
 
-                    let msg = msg_parts.join(" ");
+                    let keys = gpg.list_public_keys().await?;
+                    let key = recipient::resolve(&recipient, &keys, &lock(state).env.contacts)?;
 
-                    let armored = crypto::gpg::encrypt_to_recipient(&recipient, &msg)?;
-                    transport::send_message(&cfg.token, env.set_channel_id(cfg), &armored).await?;
+                    let armored = gpg.encrypt_to_recipient(&key.fpr, &msg, opts).await?;
+                    transport::send_message(&cfg.token, ch, &outgoing(armored, compact, code)?)
+                        .await?;
 
-                    out_lines.push(format!(
-                        "{} {} {}",
-                        "→ sent encrypted PGP message".green(),
-                        "to".dimmed(),
-                        recipient.cyan()
-                    ));
+                    out.push(Event::SentPgp {
+                        channel_id: ch,
+                        to: Some(key.uid.clone().unwrap_or_else(|| key.fpr.clone())),
+                        hidden: opts.hidden,
+                    });
 
-                    return Ok((CmdOutcome::Continue, out_lines, ui_events));
+                    Ok((CmdOutcome::Continue, out, ui_events))
                 }
 
-                _ => return Err(anyhow!("Usage: pgp <list|send|decrypt <id>|decrypt-last>")),
+                _ => Err(anyhow!("Usage: pgp <list|send|decrypt <id>|decrypt-last>")),
             }
         }
 
-        "quit" | "exit" | "q" => {
-            return Ok((CmdOutcome::Quit, out_lines, ui_events));
-        }
+        "quit" | "exit" | "q" => Ok((CmdOutcome::Quit, out, ui_events)),
 
-        _ => return Err(anyhow!("Unknown command: {cmd} (try: help)")),
+        _ => Err(anyhow!("Unknown command: {cmd} (try: help)")),
     }
 }
 
-async fn handle_chat_event(
-    ev: &transport::ChatEvent,
-    pgp_inbox: &mut VecDeque<(String, String)>,
-) -> Result<Vec<String>> {
-    let mut lines = Vec::new();
+/// Characters Discord takes in one message
+const MESSAGE_LIMIT: usize = 2000;
+
+/// Armor as posted, optionally in the compact encoding and optionally
+/// in a code block so Discord leaves the lines alone
+fn outgoing(armored: String, compact: bool, code: bool) -> Result<String> {
+    let text = match compact {
+        true => crypto::compact::from_armor(&armored)?,
+        false => armored,
+    };
+    Ok(match code {
+        true => format!("```\n{}\n```", text.trim_end()),
+        false => text,
+    })
+}
 
-    if let Some((id, block)) = crypto::detect_pgp(&ev.content) {
-        pgp_inbox.push_back((id.clone(), block.clone()));
-        while pgp_inbox.len() > 50 {
-            pgp_inbox.pop_front();
+/// Decrypt with the passphrases of `channel_id`, where the block was
+/// posted, or of every channel with `any_channel`. Asks for one if the
+/// block or the secret key needs it.
+async fn decrypt_or_prompt(
+    block: &str,
+    channel_id: u64,
+    any_channel: bool,
+    state: &Shared,
+    gpg: &crypto::gpg_async::Gpg,
+    prompter: &Prompter,
+) -> DecryptResult {
+    let mut secrets = {
+        let st = lock(state);
+        match any_channel {
+            true => st.env.secrets_any_channel(channel_id),
+            false => st.env.secrets_for(channel_id),
         }
-
-        match crypto::gpg::decrypt(&block) {
-            Ok(pt) => lines.push(render_pgp_decrypted(&ev.author, &id, &pt)),
-            Err(crypto::gpg::DecryptError::NotForMe { .. }) => {
-                lines.push(render_pgp_unknown(&ev.author, &id))
-            }
-            Err(crypto::gpg::DecryptError::InvalidMessage { .. }) => {
-                lines.push(render_pgp_invalid(&ev.author, &id))
-            }
-            Err(e) => {
-                lines.push(render_pgp_error(&ev.author, &id));
-                tracing::debug!("{e:?}");
+    };
+    match gpg.decrypt(block, &secrets).await {
+        Err(crypto::gpg::DecryptError::NeedsPassphrase) => {
+            let Ok(pass) = prompter.secret("Passphrase: ").await else {
+                return Err(crypto::gpg::DecryptError::NeedsPassphrase);
+            };
+            secrets.passphrases = vec![pass];
+            gpg.decrypt(block, &secrets).await
+        }
+        Err(e @ crypto::gpg::DecryptError::KeyLocked { .. }) => {
+            let Ok(pass) = prompter.secret("Secret key passphrase: ").await else {
+                return Err(e);
+            };
+            secrets.key_passphrase = Some(pass.clone());
+            let res = gpg.decrypt(block, &secrets).await;
+            if res.is_ok() {
+                lock(state).env.cache_key_passphrase(pass);
             }
+            res
         }
-    } else {
-        lines.push(render_incoming(&ev.author, &ev.content));
+        res => res,
     }
-
-    Ok(lines)
 }
 
-fn render_help() -> String {
-    fn section(s: &mut String, title: &str) {
-        s.push_str(&format!("\n{}\n", title.bold()));
+/// Sign with the default key, clearsigned or encrypted to `encrypt_to`,
+/// asking for the key's passphrase if it's locked
+async fn sign_or_prompt(
+    text: &str,
+    encrypt_to: Option<&[String]>,
+    channel_id: u64,
+    state: &Shared,
+    gpg: &crypto::gpg_async::Gpg,
+    prompter: &Prompter,
+) -> Result<String> {
+    let sign = async |pass: Option<&str>| match encrypt_to {
+        Some(recipients) => gpg.sign_encrypt_to_recipients(recipients, text, pass).await,
+        None => gpg.clearsign(text, pass).await,
+    };
+
+    let secrets = lock(state).env.secrets_for(channel_id);
+    match sign(secrets.key_passphrase.as_deref().map(String::as_str)).await {
+        Err(crypto::gpg::DecryptError::KeyLocked { .. }) => {
+            let pass = prompter.secret("Secret key passphrase: ").await?;
+            let armored = sign(Some(&pass)).await?;
+            lock(state).env.cache_key_passphrase(pass);
+            Ok(armored)
+        }
+        Err(crypto::gpg::DecryptError::NotForMe { .. }) => {
+            Err(anyhow!("No secret key to sign with"))
+        }
+        res => Ok(res?),
     }
+}
 
-    let core: &[(&str, &str)] = &[
-        ("help | h | ?", "Show this help"),
-        ("me", "Show your local GPG secret key fingerprints"),
-        (
-            "keys",
-            "List public keys (recipients) from your GPG keyring",
-        ),
-        (
-            "load <count>",
-            "Load and replay last <count> messages from the channel",
-        ),
-        (
-            "send <message...> | s <message...>",
-            "Send message to channel",
-        ),
-        ("clear", "Clear the screen"),
-        ("quit | exit | q", "Exit"),
-    ];
+/// Hand a group key distribution to the channel's group. Its plaintext is
+/// replaced so the key stays off the screen and out of the log.
+fn take_group_key(
+    env: &mut SessionEnv,
+    channel_id: u64,
+    d: &mut crypto::gpg::Decrypted,
+) -> Option<Event> {
+    let key = crypto::group::GroupKey::parse(&d.plaintext)?;
+    d.plaintext = SecretString::new(key.redacted());
+    let res = match (&d.signature, &d.signer_fpr) {
+        (Some(sig @ crypto::gpg::Signature::Good { .. }), Some(fpr)) => {
+            env.groups.received(channel_id, key, fpr, sig.signer())
+        }
+        _ => Err(anyhow!("Group key ignored, it has no good signature")),
+    };
+    match res {
+        Ok(note) => note.map(Event::Info),
+        Err(e) => Some(Event::Warning(e.to_string())),
+    }
+}
 
-    let pgp: &[(&str, &str)] = &[
-        ("pgp list", "List captured PGP blocks"),
-        ("pgp decrypt <id>", "Try to decrypt a captured PGP block"),
-        (
-            "pgp decrypt-last",
-            "Try to decrypt the latest captured PGP block",
-        ),
-        (
-            "pgp send <message...>",
-            "Encrypt and send using exported recipient",
-        ),
-        (
-            "pgp send -r <fpr|uid> <message...>",
-            "Encrypt and send to an explicit recipient",
-        ),
-    ];
+type DecryptResult = std::result::Result<crypto::gpg::Decrypted, crypto::gpg::DecryptError>;
+
+type DecryptJob = tokio::task::JoinHandle<DecryptResult>;
+
+/// A part of an incoming message, waiting on gpg before it can be shown
+/// while `J` is a [`DecryptJob`], ready once it's a [`DecryptResult`]
+enum Incoming<J = DecryptJob> {
+    /// Text around the blocks
+    Plain(String),
+    /// Decrypting, or why not
+    Encrypted {
+        id: String,
+        job: std::result::Result<J, PgpStatus>,
+        error: Option<crypto::armor::ArmorError>,
+    },
+    /// Cleartext signed, verifying unless the armor is broken
+    Signed {
+        id: String,
+        block: String,
+        job: std::result::Result<J, crypto::armor::ArmorError>,
+    },
+    /// A whole message of a forward-secret session
+    Session {
+        id: String,
+        session: String,
+        data: Vec<u8>,
+    },
+    /// Keys and detached signatures, only reported
+    Armor {
+        id: String,
+        kind: crypto::ArmorKind,
+        block: String,
+    },
+}
 
-    let exports: &[(&str, &str)] = &[
-        (
-            "export recipient <fpr|uid>",
-            "Set default PGP recipient for this session",
-        ),
-        (
-            "export channel <id>",
-            "Override Discord channel for send/listen",
-        ),
-        ("export show", "Show current exported session values"),
-        ("export unset <recipient|channel>", "Clear exported value"),
-    ];
+impl Incoming {
+    /// Wait for gpg to finish with this part
+    async fn resolve(self) -> Incoming<DecryptResult> {
+        async fn join(job: DecryptJob) -> DecryptResult {
+            job.await
+                .unwrap_or_else(|e| Err(crypto::gpg::DecryptError::Io(e.to_string())))
+        }
 
-    let all = core.iter().chain(pgp.iter()).chain(exports.iter());
-    let max_cmd_len = all.clone().map(|(c, _)| c.len()).max().unwrap_or(0);
+        match self {
+            Incoming::Plain(content) => Incoming::Plain(content),
+            Incoming::Encrypted { id, job, error } => Incoming::Encrypted {
+                id,
+                job: match job {
+                    Ok(job) => Ok(join(job).await),
+                    Err(status) => Err(status),
+                },
+                error,
+            },
+            Incoming::Signed { id, block, job } => Incoming::Signed {
+                id,
+                block,
+                job: match job {
+                    Ok(job) => Ok(join(job).await),
+                    Err(e) => Err(e),
+                },
+            },
+            Incoming::Session { id, session, data } => Incoming::Session { id, session, data },
+            Incoming::Armor { id, kind, block } => Incoming::Armor { id, kind, block },
+        }
+    }
+}
 
-    let col_width = max_cmd_len + 4;
+/// Messages with their parts, as [`start_chat_events`] left them
+type Started = Vec<(transport::ChatEvent, Vec<Incoming>)>;
 
-    let pad = |cmd: &str| format!("{cmd:<width$}", width = col_width);
+/// [`Started`] once gpg is done with every part
+type Resolved = Vec<(transport::ChatEvent, Vec<Incoming<DecryptResult>>)>;
 
-    let mut s = String::new();
+/// Capture a block and start decrypting or verifying it. Only `live`
+/// blocks, as they come in from the gateway, go through the auto-decrypt
+/// policy; `load` and `archive import` were asked for.
+fn start_block(
+    ev: &transport::ChatEvent,
+    armored: crypto::Armored,
+    live: bool,
+    env: &mut SessionEnv,
+    pgp_inbox: &mut VecDeque<CapturedPgp>,
+    gpg: &crypto::gpg_async::Gpg,
+) -> Incoming {
+    let crypto::Armored {
+        kind,
+        index,
+        block,
+        error,
+        ..
+    } = armored;
+    let id = crypto::pgp_block_id(ev.message_id, index);
+
+    match kind {
+        crypto::ArmorKind::Message => {
+            pgp_inbox.push_back(CapturedPgp {
+                id: id.clone(),
+                channel_id: ev.channel_id,
+                author: ev.author.clone(),
+                block: SecretString::new(block.clone()),
+                status: PgpStatus::Pending,
+            });
+            while pgp_inbox.len() > 50 {
+                pgp_inbox.pop_front();
+            }
 
-    section(&mut s, "Commands:");
-    for (cmd, desc) in core {
-        s.push_str(&format!("  {} {}\n", pad(cmd).cyan(), desc.dimmed()));
+            let verdict = match live {
+                true => env.auto_decrypt.check(ev, &env.authors),
+                false => policy::Verdict::Decrypt,
+            };
+            let job = match verdict {
+                _ if error.is_some() => Err(PgpStatus::Invalid),
+                policy::Verdict::Decrypt => {
+                    let gpg = gpg.clone();
+                    let secrets = env.secrets_for(ev.channel_id);
+                    Ok(tokio::spawn(
+                        async move { gpg.decrypt(&block, &secrets).await },
+                    ))
+                }
+                policy::Verdict::Skip => Err(PgpStatus::Skipped),
+                policy::Verdict::RateLimited => Err(PgpStatus::RateLimited),
+            };
+            Incoming::Encrypted { id, job, error }
+        }
+        crypto::ArmorKind::SignedMessage => {
+            let job = match error {
+                Some(e) => Err(e),
+                None => {
+                    let gpg = gpg.clone();
+                    let armored = block.clone();
+                    Ok(tokio::spawn(
+                        async move { gpg.verify_cleartext(&armored).await },
+                    ))
+                }
+            };
+            Incoming::Signed { id, block, job }
+        }
+        kind => Incoming::Armor { id, kind, block },
     }
+}
 
-    section(&mut s, "PGP:");
-    for (cmd, desc) in pgp {
-        s.push_str(&format!("  {} {}\n", pad(cmd).cyan(), desc.dimmed()));
-    }
+/// Incoming messages as events, in order, decrypting their PGP blocks
+/// concurrently (bounded by the gpg job limit)
+async fn handle_chat_events(
+    evs: &[transport::ChatEvent],
+    state: &Shared,
+    gpg: &crypto::gpg_async::Gpg,
+) -> Vec<Event> {
+    let started = start_chat_events(evs, false, &mut lock(state), gpg);
+    let done = resolve_chat_events(started).await;
+    finish_chat_events(done, &mut lock(state))
+}
 
-    section(&mut s, "Session exports (live only):");
-    for (cmd, desc) in exports {
-        s.push_str(&format!("  {} {}\n", pad(cmd).cyan(), desc.dimmed()));
-    }
+/// Split incoming messages into parts, capturing their PGP blocks and
+/// starting gpg on them, see [`start_block`] for `live`
+fn start_chat_events(
+    evs: &[transport::ChatEvent],
+    live: bool,
+    st: &mut State,
+    gpg: &crypto::gpg_async::Gpg,
+) -> Started {
+    let State { env, pgp_inbox, .. } = st;
+    let mut pending = Vec::with_capacity(evs.len());
+
+    for ev in evs {
+        if let Some((session, data)) = crypto::ratchet::decode(&ev.content) {
+            let id = crypto::pgp_block_id(ev.message_id, 0);
+            let session = session.to_string();
+            pending.push((ev.clone(), vec![Incoming::Session { id, session, data }]));
+            continue;
+        }
 
-    s
+        let mut parts = Vec::new();
+        let mut text_from = 0;
+        for armored in crypto::armored_blocks(&ev.content) {
+            let text = ev.content[text_from..armored.span.start].trim();
+            if !text.is_empty() {
+                parts.push(Incoming::Plain(text.to_string()));
+            }
+            text_from = armored.span.end;
+            parts.push(start_block(ev, armored, live, env, pgp_inbox, gpg));
+        }
+        let text = ev.content[text_from..].trim();
+        if parts.is_empty() || !text.is_empty() {
+            parts.push(Incoming::Plain(text.to_string()));
+        }
+        pending.push((ev.clone(), parts));
+    }
+    pending
 }
 
-fn render_warn(msg: &str) -> String {
-    format!("{}", msg.yellow())
+/// Wait for gpg on every part, in order
+async fn resolve_chat_events(started: Started) -> Resolved {
+    let mut done = Vec::with_capacity(started.len());
+    for (ev, parts) in started {
+        let mut resolved = Vec::with_capacity(parts.len());
+        for part in parts {
+            resolved.push(part.resolve().await);
+        }
+        done.push((ev, resolved));
+    }
+    done
 }
 
-fn render_error(msg: &str) -> String {
-    format!("{} {}", "!".red().bold(), msg.red())
-}
+/// Resolved messages as events, their plaintext logged and key exchanges
+/// and group keys taken up
+fn finish_chat_events(done: Resolved, st: &mut State) -> Vec<Event> {
+    let State {
+        env,
+        pgp_inbox,
+        log,
+    } = st;
+    let mut out = Vec::new();
+    for (ev, parts) in done {
+        let at = search::local_time(ev.timestamp);
+        for (part, incoming) in parts.into_iter().enumerate() {
+            let entry =
+                |pgp_id: Option<String>, text: Option<SecretString>, signer| search::LogEntry {
+                    message: ev.clone(),
+                    part,
+                    at,
+                    pgp_id,
+                    text,
+                    signer,
+                };
+
+            match incoming {
+                Incoming::Plain(content) => {
+                    log.push(entry(None, Some(SecretString::new(content.clone())), None));
+                    out.push(Event::IncomingPlain {
+                        at,
+                        channel_id: ev.channel_id,
+                        author: ev.author.clone(),
+                        content,
+                    });
+                }
 
-fn ts() -> String {
-    Local::now().format("%H:%M:%S").to_string()
-}
+                Incoming::Signed { id, block, job } => {
+                    let (res, error) = match job {
+                        Ok(res) => (res, None),
+                        Err(e) => (
+                            Err(crypto::gpg::DecryptError::Io(e.to_string())),
+                            Some(e.to_string()),
+                        ),
+                    };
+                    let (signer, signer_fpr, text) = match res {
+                        Ok(d) => (
+                            d.signature,
+                            d.signer_fpr,
+                            Some(d.plaintext.trim_end().to_string()),
+                        ),
+                        Err(e) => {
+                            tracing::debug!("{e:?}");
+                            (None, None, None)
+                        }
+                    };
+                    // a session key exchange counts only with a good signature
+                    let note = match (&signer, &signer_fpr, &text) {
+                        (
+                            Some(sig @ crypto::gpg::Signature::Good { .. }),
+                            Some(fpr),
+                            Some(text),
+                        ) => crypto::ratchet::KeyExchange::parse(text).and_then(|kx| {
+                            env.sessions
+                                .key_exchange(kx, fpr, sig.signer(), ev.channel_id)
+                        }),
+                        _ => None,
+                    };
+                    log.push(entry(
+                        None,
+                        Some(SecretString::new(text.clone().unwrap_or(block))),
+                        signer.clone(),
+                    ));
+                    out.push(Event::IncomingSigned {
+                        at,
+                        channel_id: ev.channel_id,
+                        author: ev.author.clone(),
+                        id,
+                        signer,
+                        text,
+                        error,
+                    });
+                    if let Some(note) = note {
+                        out.push(Event::Info(note));
+                    }
+                }
+
+                Incoming::Session { id, session, data } => {
+                    let (plaintext, error) = match env.sessions.decrypt(&session, &data) {
+                        Ok(pt) => (Some(pt), None),
+                        Err(e) => (None, Some(e.to_string())),
+                    };
+                    log.push(entry(Some(id.clone()), plaintext.clone(), None));
+                    out.push(Event::IncomingSession {
+                        at,
+                        channel_id: ev.channel_id,
+                        author: ev.author.clone(),
+                        id,
+                        session,
+                        plaintext,
+                        error,
+                    });
+                }
+
+                Incoming::Armor { id, kind, block } => {
+                    log.push(entry(None, Some(SecretString::new(block)), None));
+                    out.push(Event::IncomingArmor {
+                        at,
+                        channel_id: ev.channel_id,
+                        author: ev.author.clone(),
+                        id,
+                        kind,
+                    });
+                }
+
+                Incoming::Encrypted { id, job, error } => {
+                    let mut note = None;
+                    let (status, signer, plaintext, hidden_to) = match job {
+                        Ok(res) => {
+                            let status = PgpStatus::of(&res);
+                            match res {
+                                Ok(mut d) => {
+                                    note = take_group_key(env, ev.channel_id, &mut d);
+                                    (status, d.signature, Some(d.plaintext), d.hidden_to)
+                                }
+                                Err(e) => {
+                                    tracing::debug!("{e:?}");
+                                    (status, None, None, None)
+                                }
+                            }
+                        }
+                        Err(status) => (status, None, None, None),
+                    };
+                    set_status(pgp_inbox, &id, status);
+                    log.push(entry(Some(id.clone()), plaintext.clone(), signer.clone()));
+                    // kept in the log for `pgp show`
+                    let hidden = env.ephemeral.is_some() && plaintext.is_some();
+                    out.push(Event::IncomingPgp {
+                        at,
+                        channel_id: ev.channel_id,
+                        author: ev.author.clone(),
+                        id,
+                        status,
+                        signer,
+                        plaintext: plaintext.filter(|_| !hidden),
+                        hidden,
+                        hidden_to,
+                        error: error.map(|e| e.to_string()),
+                    });
+                    out.extend(note);
+                }
+            }
+        }
+    }
 
-fn render_incoming(author: &str, content: &str) -> String {
-    format!(
-        "\n[{}] {} {}: {}",
-        ts().dimmed(),
-        "←".cyan(),
-        author.cyan(),
-        content
-    )
+    out
 }
 
-fn render_outgoing_sent() -> String {
-    "→ sent".green().to_string()
+/// Record the result of decrypting captured block `id`, the events to show
+fn decrypted(id: String, channel_id: u64, mut res: DecryptResult, st: &mut State) -> Vec<Event> {
+    set_status(&mut st.pgp_inbox, &id, PgpStatus::of(&res));
+    let note = res
+        .as_mut()
+        .ok()
+        .and_then(|d| take_group_key(&mut st.env, channel_id, d));
+    if let Ok(d) = &res {
+        st.log.set_decrypted(&id, d);
+    }
+    let mut out = vec![decrypt_event(id, res, st.env.ephemeral)];
+    out.extend(note);
+    out
 }
 
-fn render_decrypt_attempt(id: &str, block: &str) -> Vec<String> {
-    let mut out = Vec::new();
-    match crypto::gpg::decrypt(block) {
-        Ok(pt) => {
-            out.push(format!(
-                "{} {}",
-                "Decrypted".green().bold(),
-                format!("(id={id})").dimmed()
-            ));
-            out.push(pt);
-        }
-        Err(crypto::gpg::DecryptError::NotForMe { .. }) => {
-            out.push(format!(
-                "{} {}",
-                "Not for me".yellow(),
-                format!("(id={id})").dimmed()
-            ));
-        }
-        Err(crypto::gpg::DecryptError::InvalidMessage { .. }) => {
-            out.push(format!(
-                "{} {}",
-                "Invalid PGP message".red(),
-                format!("(id={id})").dimmed()
-            ));
-        }
+fn decrypt_event(
+    id: String,
+    res: std::result::Result<crypto::gpg::Decrypted, crypto::gpg::DecryptError>,
+    ephemeral: Option<Duration>,
+) -> Event {
+    let status = PgpStatus::of(&res);
+    match res {
+        Ok(d) => Event::Decrypt {
+            id,
+            status,
+            signer: d.signature,
+            plaintext: Some(d.plaintext),
+            hidden_to: d.hidden_to,
+            hide_after: ephemeral.map(|t| t.as_secs()),
+        },
         Err(e) => {
-            out.push(format!(
-                "{} {}",
-                "Decrypt error".red(),
-                format!("(id={id})").dimmed()
-            ));
             tracing::debug!("{e:?}");
+            Event::Decrypt {
+                id,
+                status,
+                signer: None,
+                plaintext: None,
+                hidden_to: None,
+                hide_after: None,
+            }
         }
     }
-    out
 }
 
-fn render_pgp_decrypted(author: &str, id: &str, plaintext: &str) -> String {
-    format!(
-        "\n[{}] {} {}: {} {} {} \n{}",
-        ts().dimmed(),
-        "←".cyan(),
-        author.cyan(),
-        "[PGP]".purple(),
-        format!("id={id}").dimmed(),
-        "decrypted".green(),
-        plaintext.green()
-    )
-}
-
-fn render_pgp_invalid(author: &str, id: &str) -> String {
-    format!(
-        "\n[{}] {} {}: {} {} {}",
-        ts().dimmed(),
-        "←".cyan(),
-        author.cyan(),
-        "[PGP]".purple(),
-        format!("id={id}").dimmed(),
-        "invalid".red()
-    )
-}
-
-fn render_pgp_error(author: &str, id: &str) -> String {
-    format!(
-        "\n[{}] {} {}: {} {} {}",
-        ts().dimmed(),
-        "←".cyan(),
-        author.cyan(),
-        "[PGP]".purple(),
-        format!("id={id}").dimmed(),
-        "decrypt error".red()
-    )
-}
-
-fn render_pgp_unknown(author: &str, id: &str) -> String {
-    format!(
-        "\n[{}] {} {}: {} {} {}",
-        ts().dimmed(),
-        "←".cyan(),
-        author.cyan(),
-        "[PGP]".purple(),
-        format!("id={id}").dimmed(),
-        "not for me".yellow()
-    )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass(p: &str) -> SecretString {
+        SecretString::new(p.to_string())
+    }
+
+    #[test]
+    fn passphrases_stay_with_their_channel() {
+        let mut env = SessionEnv::default();
+        env.passphrases.insert(1, pass("one"));
+        env.passphrases.insert(2, pass("two"));
+        let tried = |s: crypto::gpg_async::Secrets| {
+            s.passphrases
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tried(env.secrets_for(1)), ["one"]);
+        assert_eq!(tried(env.secrets_for(3)), Vec::<String>::new());
+        assert_eq!(tried(env.secrets_any_channel(2)), ["two", "one"]);
+    }
+
+    #[tokio::test]
+    async fn loaded_blocks_skip_the_rate_limit() {
+        let block = crypto::armor::armor(
+            crypto::ArmorKind::Message,
+            &crypto::armor::Dearmored {
+                headers: Vec::new(),
+                data: vec![1, 2, 3],
+            },
+        );
+        let evs: Vec<transport::ChatEvent> = (0..12)
+            .map(|i| transport::ChatEvent {
+                message_id: i,
+                channel_id: 10,
+                author_id: 7,
+                author: "bob".to_string(),
+                content: block.clone(),
+                timestamp: 0,
+                mentions: Vec::new(),
+            })
+            .collect();
+        let gpg = crypto::gpg_async::Gpg::default();
+        let started_jobs = |live: bool| {
+            let mut st = State::default();
+            st.env.auto_decrypt = policy::AutoDecrypt::new(common::DecryptPolicy::Always, 10);
+            let mut jobs = 0;
+            for (_, parts) in start_chat_events(&evs, live, &mut st, &gpg) {
+                for part in parts {
+                    if let Incoming::Encrypted { job: Ok(job), .. } = part {
+                        job.abort();
+                        jobs += 1;
+                    }
+                }
+            }
+            jobs
+        };
+
+        assert_eq!(started_jobs(false), 12);
+        assert_eq!(started_jobs(true), 10);
+    }
+
+    #[tokio::test]
+    async fn message_parts_keep_their_order() {
+        let block = |kind, len| {
+            crypto::armor::armor(
+                kind,
+                &crypto::armor::Dearmored {
+                    headers: Vec::new(),
+                    data: vec![len; len as usize],
+                },
+            )
+        };
+        let (first, second) = (
+            block(crypto::ArmorKind::Message, 20),
+            block(crypto::ArmorKind::Message, 30),
+        );
+        let key = block(crypto::ArmorKind::PublicKey, 10);
+        let ev = transport::ChatEvent {
+            message_id: 5,
+            channel_id: 10,
+            author_id: 7,
+            author: "bob".to_string(),
+            content: format!("quoting:\n{first}\nreply:\n{second}{key}\nbye"),
+            timestamp: 0,
+            mentions: Vec::new(),
+        };
+
+        let mut st = State::default();
+        let gpg = crypto::gpg_async::Gpg::default();
+        let mut started = start_chat_events(&[ev], false, &mut st, &gpg);
+        let parts: Vec<String> = started
+            .remove(0)
+            .1
+            .into_iter()
+            .map(|part| match part {
+                Incoming::Plain(text) => text,
+                Incoming::Encrypted { id, job, .. } => {
+                    if let Ok(job) = job {
+                        job.abort();
+                    }
+                    format!("encrypted {id}")
+                }
+                Incoming::Armor { id, kind, .. } => format!("{} {id}", kind.as_str()),
+                Incoming::Signed { id, .. } | Incoming::Session { id, .. } => id,
+            })
+            .collect();
+
+        let id = |i| crypto::pgp_block_id(5, i);
+        assert_eq!(
+            parts,
+            [
+                "quoting:".to_string(),
+                format!("encrypted {}", id(0)),
+                "reply:".to_string(),
+                format!("encrypted {}", id(1)),
+                format!("public key {}", id(2)),
+                "bye".to_string(),
+            ]
+        );
+        let captured: Vec<_> = st.pgp_inbox.iter().map(|c| c.id.clone()).collect();
+        assert_eq!(captured, [id(0), id(1)]);
+    }
 }
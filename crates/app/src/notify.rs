@@ -0,0 +1,285 @@
+use common::NotifyMode;
+use std::collections::HashMap;
+
+use crate::{PgpStatus, event::Event};
+
+/// A desktop notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+}
+
+/// Shows notifications; implemented over D-Bus, swappable for tests
+pub trait Notifier: Send + Sync {
+    /// Fire and forget, failures are only logged
+    fn notify(&self, n: Notification);
+}
+
+/// `org.freedesktop.Notifications` on the session bus
+pub struct DbusNotifier {
+    conn: zbus::Connection,
+}
+
+impl DbusNotifier {
+    pub async fn connect() -> zbus::Result<Self> {
+        Ok(Self {
+            conn: zbus::Connection::session().await?,
+        })
+    }
+}
+
+impl Notifier for DbusNotifier {
+    fn notify(&self, n: Notification) {
+        let conn = self.conn.clone();
+        tokio::spawn(async move {
+            let hints: HashMap<&str, zbus::zvariant::Value> = HashMap::new();
+            let res = conn
+                .call_method(
+                    Some("org.freedesktop.Notifications"),
+                    "/org/freedesktop/Notifications",
+                    Some("org.freedesktop.Notifications"),
+                    "Notify",
+                    &(
+                        "pgp-disc",
+                        0u32,
+                        "",
+                        n.summary.as_str(),
+                        n.body.as_str(),
+                        Vec::<&str>::new(),
+                        hints,
+                        -1i32,
+                    ),
+                )
+                .await;
+            if let Err(e) = res {
+                tracing::debug!("notification failed: {e}");
+            }
+        });
+    }
+}
+
+/// Notification for an incoming event: a block that decrypted, or a
+/// message mentioning `me`. Content only in [`NotifyMode::Preview`].
+pub fn for_event(
+    ev: &Event,
+    msg: &transport::ChatEvent,
+    me: Option<u64>,
+    mode: NotifyMode,
+) -> Option<Notification> {
+    if mode == NotifyMode::Off || me == Some(msg.author_id) {
+        return None;
+    }
+    let preview = mode == NotifyMode::Preview;
+
+    match ev {
+        Event::IncomingPgp {
+            author,
+            status: PgpStatus::Decrypted,
+            plaintext,
+            ..
+        }
+        | Event::IncomingSession {
+            author,
+            plaintext: plaintext @ Some(_),
+            ..
+        } => Some(Notification {
+            summary: format!("Encrypted message from {author}"),
+            body: match plaintext {
+                Some(pt) if preview => pt.to_string(),
+                _ => String::new(),
+            },
+        }),
+        Event::IncomingPlain {
+            author, content, ..
+        } if me.is_some_and(|id| msg.mentions.contains(&id)) => Some(Notification {
+            summary: format!("{author} mentioned you"),
+            body: if preview {
+                content.clone()
+            } else {
+                String::new()
+            },
+        }),
+        _ => None,
+    }
+}
+
+/// One notification per message, not per block: the first of `events`
+/// that [`for_event`] has one for
+pub fn message(
+    notifier: &dyn Notifier,
+    events: &[Event],
+    msg: &transport::ChatEvent,
+    me: Option<u64>,
+    mode: NotifyMode,
+) {
+    if let Some(n) = events.iter().find_map(|e| for_event(e, msg, me, mode)) {
+        notifier.notify(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+    use common::SecretString;
+    use std::sync::Mutex;
+
+    const ME: u64 = 1;
+    const THEM: u64 = 2;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Notification>>);
+
+    impl Notifier for Recorder {
+        fn notify(&self, n: Notification) {
+            self.0.lock().unwrap().push(n);
+        }
+    }
+
+    impl Recorder {
+        fn sent(&self) -> Vec<Notification> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn msg(author_id: u64, mentions: Vec<u64>) -> transport::ChatEvent {
+        transport::ChatEvent {
+            message_id: 10,
+            channel_id: 20,
+            author_id,
+            author: "bob".to_string(),
+            content: "hello".to_string(),
+            timestamp: 0,
+            mentions,
+        }
+    }
+
+    fn pgp(status: PgpStatus, plaintext: Option<&str>) -> Event {
+        Event::IncomingPgp {
+            at: Local::now(),
+            channel_id: 20,
+            author: "bob".to_string(),
+            id: "0123456789abcdef".to_string(),
+            status,
+            signer: None,
+            plaintext: plaintext.map(|p| SecretString::new(p.to_string())),
+            hidden: false,
+            hidden_to: None,
+            error: None,
+        }
+    }
+
+    fn plain() -> Event {
+        Event::IncomingPlain {
+            at: Local::now(),
+            channel_id: 20,
+            author: "bob".to_string(),
+            content: "hey <@1>".to_string(),
+        }
+    }
+
+    fn run(events: &[Event], msg: &transport::ChatEvent, mode: NotifyMode) -> Vec<Notification> {
+        let r = Recorder::default();
+        message(&r, events, msg, Some(ME), mode);
+        r.sent()
+    }
+
+    #[test]
+    fn off_sends_nothing() {
+        let events = [pgp(PgpStatus::Decrypted, Some("secret")), plain()];
+        assert!(run(&events, &msg(THEM, vec![ME]), NotifyMode::Off).is_empty());
+    }
+
+    #[test]
+    fn preview_includes_the_body() {
+        let sent = run(
+            &[pgp(PgpStatus::Decrypted, Some("secret"))],
+            &msg(THEM, vec![]),
+            NotifyMode::Preview,
+        );
+        assert_eq!(
+            sent,
+            [Notification {
+                summary: "Encrypted message from bob".to_string(),
+                body: "secret".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn on_leaves_the_body_out() {
+        let sent = run(
+            &[pgp(PgpStatus::Decrypted, Some("secret"))],
+            &msg(THEM, vec![]),
+            NotifyMode::On,
+        );
+        assert_eq!(
+            sent,
+            [Notification {
+                summary: "Encrypted message from bob".to_string(),
+                body: String::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn decrypted_without_plaintext_has_an_empty_body() {
+        let sent = run(
+            &[pgp(PgpStatus::Decrypted, None)],
+            &msg(THEM, vec![]),
+            NotifyMode::Preview,
+        );
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].body.is_empty());
+    }
+
+    #[test]
+    fn only_decrypted_blocks() {
+        let events = [pgp(PgpStatus::Skipped, None), pgp(PgpStatus::Pending, None)];
+        assert!(run(&events, &msg(THEM, vec![]), NotifyMode::Preview).is_empty());
+    }
+
+    #[test]
+    fn own_messages_are_skipped() {
+        let events = [pgp(PgpStatus::Decrypted, Some("secret")), plain()];
+        assert!(run(&events, &msg(ME, vec![ME]), NotifyMode::Preview).is_empty());
+    }
+
+    #[test]
+    fn mentions() {
+        let sent = run(&[plain()], &msg(THEM, vec![ME]), NotifyMode::Preview);
+        assert_eq!(
+            sent,
+            [Notification {
+                summary: "bob mentioned you".to_string(),
+                body: "hey <@1>".to_string(),
+            }]
+        );
+        let sent = run(&[plain()], &msg(THEM, vec![ME]), NotifyMode::On);
+        assert!(sent[0].body.is_empty());
+
+        assert!(run(&[plain()], &msg(THEM, vec![3]), NotifyMode::Preview).is_empty());
+        let r = Recorder::default();
+        message(
+            &r,
+            &[plain()],
+            &msg(THEM, vec![ME]),
+            None,
+            NotifyMode::Preview,
+        );
+        assert!(r.sent().is_empty());
+    }
+
+    #[test]
+    fn one_notification_per_message() {
+        let events = [
+            plain(),
+            pgp(PgpStatus::Decrypted, Some("first")),
+            pgp(PgpStatus::Decrypted, Some("second")),
+        ];
+        let sent = run(&events, &msg(THEM, vec![]), NotifyMode::Preview);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].body, "first");
+    }
+}
@@ -0,0 +1,127 @@
+use common::DecryptPolicy;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::recipient::Authors;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Whether an incoming block may be decrypted right away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Decrypt,
+    /// Policy says wait for `pgp decrypt`
+    Skip,
+    /// Author used up their attempts for this minute
+    RateLimited,
+}
+
+/// Auto-decrypt policy with per-channel overrides and per-author rate limit
+#[derive(Debug, Clone, Default)]
+pub struct AutoDecrypt {
+    pub default: DecryptPolicy,
+    pub channels: HashMap<u64, DecryptPolicy>,
+    /// Attempts per author and minute, 0 = no limit
+    pub rate: u32,
+    // author id -> recent attempts
+    attempts: HashMap<u64, VecDeque<Instant>>,
+}
+
+impl AutoDecrypt {
+    pub fn new(default: DecryptPolicy, rate: u32) -> Self {
+        Self {
+            default,
+            rate,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy_for(&self, channel_id: u64) -> DecryptPolicy {
+        self.channels
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Decide for a block in `ev`; an allowed attempt counts against the rate.
+    /// Under [`DecryptPolicy::Contacts`] only Discord users linked to a
+    /// contact pass, whatever name they show.
+    pub fn check(&mut self, ev: &transport::ChatEvent, authors: &Authors) -> Verdict {
+        let allowed = match self.policy_for(ev.channel_id) {
+            DecryptPolicy::Always => true,
+            DecryptPolicy::Contacts => authors.contains_key(&ev.author_id),
+            DecryptPolicy::Never => false,
+        };
+        if !allowed {
+            return Verdict::Skip;
+        }
+
+        if self.rate > 0 {
+            let now = Instant::now();
+            let recent = self.attempts.entry(ev.author_id).or_default();
+            while recent
+                .front()
+                .is_some_and(|t| now.duration_since(*t) > WINDOW)
+            {
+                recent.pop_front();
+            }
+            if recent.len() >= self.rate as usize {
+                return Verdict::RateLimited;
+            }
+            recent.push_back(now);
+        }
+
+        Verdict::Decrypt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(author_id: u64, author: &str) -> transport::ChatEvent {
+        transport::ChatEvent {
+            message_id: 1,
+            channel_id: 10,
+            author_id,
+            author: author.to_string(),
+            content: String::new(),
+            timestamp: 0,
+            mentions: Vec::new(),
+        }
+    }
+
+    fn authors() -> Authors {
+        Authors::from([(42, "alice".to_string())])
+    }
+
+    #[test]
+    fn contacts_go_by_discord_id_not_name() {
+        let mut p = AutoDecrypt::new(DecryptPolicy::Contacts, 0);
+        assert_eq!(
+            p.check(&event(42, "Alice W."), &authors()),
+            Verdict::Decrypt
+        );
+        // someone naming themselves like the alias
+        assert_eq!(p.check(&event(7, "alice"), &authors()), Verdict::Skip);
+        assert_eq!(p.check(&event(42, "alice"), &Authors::new()), Verdict::Skip);
+    }
+
+    #[test]
+    fn channel_overrides() {
+        let mut p = AutoDecrypt::new(DecryptPolicy::Never, 0);
+        p.channels.insert(10, DecryptPolicy::Always);
+        assert_eq!(p.check(&event(7, "bob"), &authors()), Verdict::Decrypt);
+        p.channels.clear();
+        assert_eq!(p.check(&event(42, "alice"), &authors()), Verdict::Skip);
+    }
+
+    #[test]
+    fn rate_limit_per_author() {
+        let mut p = AutoDecrypt::new(DecryptPolicy::Always, 2);
+        assert_eq!(p.check(&event(7, "bob"), &authors()), Verdict::Decrypt);
+        assert_eq!(p.check(&event(7, "bob"), &authors()), Verdict::Decrypt);
+        assert_eq!(p.check(&event(7, "bob"), &authors()), Verdict::RateLimited);
+        assert_eq!(p.check(&event(42, "alice"), &authors()), Verdict::Decrypt);
+    }
+}
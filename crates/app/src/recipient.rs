@@ -0,0 +1,279 @@
+use anyhow::{Result, anyhow};
+use crypto::gpg::PublicKey;
+use std::collections::BTreeMap;
+
+/// Session contacts: alias -> fingerprint
+pub type Contacts = BTreeMap<String, String>;
+
+/// Discord user id -> contact alias. Aliases are labels of our own, only
+/// these ids count as the contact for `auto-decrypt contacts`.
+pub type Authors = BTreeMap<u64, String>;
+
+/// Discord user id from a `<@id>` mention, as Discord shows it when
+/// copied, or a bare id
+pub fn parse_user_id(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = match s.strip_prefix("<@") {
+        Some(m) => m.strip_suffix('>')?.trim_start_matches('!'),
+        None => s,
+    };
+    s.parse().ok().filter(|id| *id > 0)
+}
+
+fn uid_email(uid: &str) -> Option<&str> {
+    let start = uid.rfind('<')?;
+    let end = uid[start..].find('>')? + start;
+    Some(&uid[start + 1..end])
+}
+
+fn uid_name(uid: &str) -> &str {
+    match uid.find('<') {
+        Some(i) => uid[..i].trim(),
+        None => uid.trim(),
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Candidate keys for `query`, strongest match kind first:
+/// contact alias, fingerprint suffix, exact email, email fragment, name fragment.
+pub fn candidates<'a>(
+    query: &str,
+    keys: &'a [PublicKey],
+    contacts: &Contacts,
+) -> Vec<&'a PublicKey> {
+    let q = query.trim().to_lowercase();
+    let q = q.strip_prefix("0x").unwrap_or(&q);
+
+    if let Some(fpr) = contacts
+        .iter()
+        .find(|(alias, _)| alias.to_lowercase() == q)
+        .map(|(_, fpr)| fpr.to_lowercase())
+    {
+        return keys
+            .iter()
+            .filter(|k| k.fpr.to_lowercase() == fpr)
+            .collect();
+    }
+
+    let passes: [&dyn Fn(&PublicKey) -> bool; 4] = [
+        &|k| {
+            q.len() >= 8
+                && is_hex(q)
+                && std::iter::once(&k.fpr)
+                    .chain(k.subkeys.iter().map(|s| &s.fpr))
+                    .any(|f| f.to_lowercase().ends_with(q))
+        },
+        &|k| {
+            k.uids
+                .iter()
+                .filter_map(|u| uid_email(u))
+                .any(|e| e.to_lowercase() == q)
+        },
+        &|k| {
+            q.contains('@')
+                && k.uids
+                    .iter()
+                    .filter_map(|u| uid_email(u))
+                    .any(|e| e.to_lowercase().contains(q))
+        },
+        &|k| {
+            k.uids.iter().any(|u| {
+                uid_name(u).to_lowercase().contains(q)
+                    || uid_email(u).is_some_and(|e| e.to_lowercase().contains(q))
+            })
+        },
+    ];
+
+    for pass in passes {
+        let found: Vec<_> = keys.iter().filter(|k| pass(k)).collect();
+        if !found.is_empty() {
+            return found;
+        }
+    }
+
+    Vec::new()
+}
+
+fn key_line(k: &PublicKey) -> String {
+    format!(
+        "\n  {}  —  {}",
+        k.fpr,
+        k.uid.as_deref().unwrap_or("(no uid)")
+    )
+}
+
+/// Resolve `query` to exactly one key usable for encryption. Revoked,
+/// expired and other unusable keys only count when nothing else matches,
+/// so a rotated key isn't ambiguous with the one it replaced.
+pub fn resolve<'a>(
+    query: &str,
+    keys: &'a [PublicKey],
+    contacts: &Contacts,
+) -> Result<&'a PublicKey> {
+    let mut usable = Vec::new();
+    let mut unusable = Vec::new();
+    for k in candidates(query, keys, contacts) {
+        match k.check_encrypt() {
+            Ok(()) => usable.push(k),
+            Err(e) => unusable.push((k, e)),
+        }
+    }
+
+    match (usable.as_slice(), unusable.as_slice()) {
+        ([k], _) => Ok(k),
+        ([], []) => Err(anyhow!("No public key matches '{query}' (see: keys)")),
+        ([], [(k, e)]) => Err(anyhow!("Refusing to encrypt to {query}: {e} ({})", k.fpr)),
+        ([], many) => {
+            let mut msg = format!("Refusing to encrypt to {query}, no matching key is usable:");
+            for (k, e) in many {
+                msg.push_str(&format!("{} ({e})", key_line(k)));
+            }
+            Err(anyhow!(msg))
+        }
+        (many, _) => {
+            let mut msg = format!("'{query}' is ambiguous, {} keys match:", many.len());
+            for k in many {
+                msg.push_str(&key_line(k));
+            }
+            msg.push_str("\nUse a longer fingerprint or: contact add <alias> <fpr>");
+            Err(anyhow!(msg))
+        }
+    }
+}
+
+/// Strings offered when tab-completing a recipient
+pub fn completion_hints(keys: &[PublicKey], contacts: &Contacts) -> Vec<String> {
+    let mut out: Vec<String> = contacts.keys().cloned().collect();
+    for k in keys {
+        out.extend(
+            k.uids
+                .iter()
+                .filter_map(|u| uid_email(u))
+                .map(str::to_string),
+        );
+        out.push(k.fpr.clone());
+    }
+    out.sort();
+    out.dedup();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::gpg::{Capabilities, Validity};
+
+    const ALICE: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+    const ALICE_OLD: &str = "FEDCBA9876543210FEDCBA9876543210FEDCBA98";
+    const BOB: &str = "1111222233334444555566667777888899990000";
+
+    fn key(fpr: &str, uid: &str, validity: Validity) -> PublicKey {
+        PublicKey {
+            fpr: fpr.to_string(),
+            uid: Some(uid.to_string()),
+            uids: vec![uid.to_string()],
+            validity,
+            algo: "ed25519".to_string(),
+            bits: 255,
+            created: None,
+            expires: None,
+            caps: Capabilities {
+                encrypt: true,
+                sign: true,
+                certify: true,
+                authenticate: false,
+            },
+            subkeys: Vec::new(),
+        }
+    }
+
+    fn keys() -> Vec<PublicKey> {
+        vec![
+            key(ALICE, "Alice <alice@example.org>", Validity::Full),
+            key(BOB, "Bob <bob@example.org>", Validity::Ultimate),
+        ]
+    }
+
+    fn fpr(query: &str, keys: &[PublicKey], contacts: &Contacts) -> Result<String> {
+        resolve(query, keys, contacts).map(|k| k.fpr.clone())
+    }
+
+    #[test]
+    fn by_fingerprint_email_name_and_alias() {
+        let keys = keys();
+        let none = Contacts::new();
+        assert_eq!(fpr(ALICE, &keys, &none).unwrap(), ALICE);
+        assert_eq!(fpr("0x89abcdef01234567", &keys, &none).unwrap(), ALICE);
+        assert_eq!(fpr("bob@example.org", &keys, &none).unwrap(), BOB);
+        assert_eq!(fpr("BOB@EXAMPLE.ORG", &keys, &none).unwrap(), BOB);
+        assert_eq!(fpr("alice", &keys, &none).unwrap(), ALICE);
+
+        let contacts = Contacts::from([("b".to_string(), BOB.to_string())]);
+        assert_eq!(fpr("b", &keys, &contacts).unwrap(), BOB);
+        // short hex isn't taken for a fingerprint
+        assert!(fpr("0123", &keys, &none).is_err());
+        let e = fpr("carol", &keys, &none).unwrap_err().to_string();
+        assert!(e.starts_with("No public key matches 'carol'"), "{e}");
+    }
+
+    #[test]
+    fn ambiguous_names_list_the_keys() {
+        let keys = keys();
+        let e = fpr("example.org", &keys, &Contacts::new())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.starts_with("'example.org' is ambiguous, 2 keys match:"),
+            "{e}"
+        );
+        assert!(e.contains(ALICE) && e.contains(BOB), "{e}");
+    }
+
+    #[test]
+    fn rotated_key_wins_over_revoked_one() {
+        let mut keys = keys();
+        keys.push(key(
+            ALICE_OLD,
+            "Alice <alice@example.org>",
+            Validity::Revoked,
+        ));
+        assert_eq!(
+            fpr("alice@example.org", &keys, &Contacts::new()).unwrap(),
+            ALICE
+        );
+
+        keys[0].validity = Validity::Expired;
+        let e = fpr("alice@example.org", &keys, &Contacts::new())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.starts_with("Refusing to encrypt to alice@example.org, no matching key is usable"),
+            "{e}"
+        );
+        assert!(
+            e.contains("key is revoked") && e.contains("key is expired"),
+            "{e}"
+        );
+
+        let e = fpr(ALICE_OLD, &keys, &Contacts::new())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            e,
+            format!("Refusing to encrypt to {ALICE_OLD}: key is revoked ({ALICE_OLD})")
+        );
+    }
+
+    #[test]
+    fn user_ids_from_mentions() {
+        assert_eq!(parse_user_id("123456789"), Some(123456789));
+        assert_eq!(parse_user_id("<@123456789>"), Some(123456789));
+        assert_eq!(parse_user_id("<@!123456789>"), Some(123456789));
+        assert_eq!(parse_user_id("<@123456789"), None);
+        assert_eq!(parse_user_id("@alice"), None);
+        assert_eq!(parse_user_id("0"), None);
+    }
+}
@@ -0,0 +1,1420 @@
+use chrono::{DateTime, Local};
+use crypto::ArmorKind;
+use crypto::gpg::{PublicKey, Signature, Validity};
+use owo_colors::OwoColorize;
+use serde_json::{Value, json};
+
+use crate::PgpStatus;
+use crate::event::Event;
+use crate::ids;
+use crate::sessions::SessionState;
+
+/// Turns events into printable text
+pub trait Renderer: Send {
+    /// Text for `ev`, possibly several lines
+    fn render(&self, ev: &Event) -> String;
+
+    /// Text that makes `change`, `None` when the output can't
+    fn screen(&self, change: Screen<'_>) -> Option<String>;
+}
+
+/// Changes to what's on screen, not events of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screen<'a> {
+    /// `clear`
+    Clear,
+    /// Take the revealed plaintext of block `id` away again
+    Hide { id: &'a str },
+    /// `panic`, scrollback included
+    Wipe,
+}
+
+/// Renderer for an `--output` name
+pub fn by_name(name: &str) -> Option<Box<dyn Renderer>> {
+    match name {
+        "ansi" => Some(Box::new(Ansi)),
+        "plain" => Some(Box::new(Plain)),
+        "json" => Some(Box::new(JsonLines)),
+        _ => None,
+    }
+}
+
+/// Colored terminal output
+pub struct Ansi;
+
+/// Same text as [`Ansi`] without escape codes
+pub struct Plain;
+
+/// One JSON object per event
+pub struct JsonLines;
+
+const HELP: &[(&str, &[(&str, &str)])] = &[
+    (
+        "Commands:",
+        &[
+            ("help | h | ?", "Show this help"),
+            ("me", "Show your local GPG secret key fingerprints"),
+            (
+                "keys",
+                "List public keys (recipients) from your GPG keyring",
+            ),
+            (
+                "load <count>",
+                "Load and replay last <count> messages from the channel",
+            ),
+            (
+                "send <message...> | s <message...>",
+                "Send message to channel",
+            ),
+            (
+                "send | pgp send [-r|-R ..|--symmetric] [--pad] [--compact] [--code]",
+                "Compose a multi-line message ($EDITOR, or lines ending with '.')",
+            ),
+            (
+                "sign <message...>",
+                "Send a clearsigned message, readable by everyone",
+            ),
+            (
+                "search <query...>",
+                "Search this session's messages and decrypted plaintext",
+            ),
+            (
+                "search --author|--channel|--since|--until ..",
+                "Narrow a search (--encrypted: PGP only, dates YYYY-MM-DD)",
+            ),
+            ("clear", "Clear the screen"),
+            (
+                "panic",
+                "Clear the screen and history, forget plaintext and passphrases",
+            ),
+            ("quit | exit | q", "Exit"),
+        ],
+    ),
+    (
+        "PGP:",
+        &[
+            ("pgp list", "List captured PGP blocks"),
+            (
+                "pgp decrypt <id> [--any-passphrase]",
+                "Try to decrypt a captured PGP block (ids may be shortened to 4+ chars), \
+                 with every channel's passphrase instead of only its own",
+            ),
+            (
+                "pgp show <id>",
+                "Reveal the plaintext of a block hidden by ephemeral view",
+            ),
+            (
+                "pgp decrypt-last",
+                "Try to decrypt the latest captured PGP block",
+            ),
+            (
+                "pgp send <message...>",
+                "Encrypt and send using exported recipient",
+            ),
+            (
+                "pgp send -r <fpr|email|name|alias> <message...>",
+                "Encrypt and send to an explicit recipient",
+            ),
+            (
+                "pgp send -R <fpr|email|name|alias> <message...>",
+                "Encrypt without naming the recipient's key (--throw-keyids)",
+            ),
+            (
+                "pgp send --symmetric <message...>",
+                "Encrypt with the channel passphrase (prompts if unset)",
+            ),
+            (
+                "pgp send --pad <message...>",
+                "Pad to 256/512/1024/4096 bytes so the length doesn't show",
+            ),
+            (
+                "pgp send --compact <message...>",
+                "Send binary OpenPGP packed into CJK characters, less than half the length",
+            ),
+            (
+                "pgp send --code <message...>",
+                "Send the armor in a code block, safe from chat formatting",
+            ),
+        ],
+    ),
+    (
+        "Forward-secret sessions (live only):",
+        &[
+            (
+                "session start <fpr|email|name|alias>",
+                "Post a signed key exchange, the peer accepts with their key",
+            ),
+            (
+                "session accept <id>",
+                "Sign and post the answer to a request",
+            ),
+            (
+                "session send <id> <message...>",
+                "Encrypt under a one-time key, old messages stay safe if keys leak",
+            ),
+            ("session list", "List sessions and their state"),
+            ("session end <id>", "Forget a session and its keys"),
+        ],
+    ),
+    (
+        "Groups (live only, one per channel):",
+        &[
+            (
+                "group create <member...>",
+                "Send a new group key to you and the members, encrypted to each",
+            ),
+            (
+                "group add|rm <fpr|email|name|alias>",
+                "Change members, rotating the key so it only reaches the new list",
+            ),
+            ("group rotate", "Send a fresh key to the same members"),
+            (
+                "group send <message...>",
+                "Encrypt once under the group key, the same size for any group",
+            ),
+            (
+                "group show",
+                "Members and key epoch of this channel's group",
+            ),
+            ("group leave", "Forget this channel's group keys"),
+        ],
+    ),
+    (
+        "Session exports (live only):",
+        &[
+            (
+                "export recipient <fpr|email|name|alias>",
+                "Set default PGP recipient for this session",
+            ),
+            (
+                "export channel <id>",
+                "Override Discord channel for send/listen",
+            ),
+            (
+                "export passphrase",
+                "Set the group passphrase for the current channel",
+            ),
+            (
+                "export passphrase-ttl <seconds>",
+                "Cache your secret key passphrase this long (0 = never)",
+            ),
+            (
+                "export auto-decrypt <always|contacts|never> [--default]",
+                "Auto-decrypt policy for this channel (or all channels)",
+            ),
+            (
+                "export decrypt-rate <n>",
+                "Auto-decrypt attempts per author and minute (0 = no limit)",
+            ),
+            (
+                "export ephemeral <seconds>",
+                "Hide incoming plaintext, clear revealed plaintext after <seconds>",
+            ),
+            ("export show", "Show current exported session values"),
+            (
+                "export unset <name>",
+                "Clear an exported value (recipient, channel, passphrase, ...)",
+            ),
+        ],
+    ),
+    (
+        "Archives:",
+        &[
+            (
+                "archive export [--count <n>] [--plaintext] <file>",
+                "Write channel history with decrypt status (.jsonl or .mbox)",
+            ),
+            (
+                "archive export --session | --format jsonl|mbox",
+                "Archive this session's messages instead / pick the format",
+            ),
+            (
+                "archive import <file>",
+                "Replay an archive, decrypting with your keys",
+            ),
+        ],
+    ),
+    (
+        "Contacts (live only):",
+        &[
+            (
+                "contact add <alias> <fpr|email|name>",
+                "Name a public key for use as a recipient",
+            ),
+            ("contact rm <alias>", "Remove a contact"),
+            (
+                "contact link <alias> <discord user id>",
+                "Trust a Discord user as the contact for auto-decrypt contacts",
+            ),
+            ("contact list", "List contacts"),
+        ],
+    ),
+];
+
+impl Renderer for Ansi {
+    fn render(&self, ev: &Event) -> String {
+        match ev {
+            Event::Connected { channel_id } => {
+                format!("discord — connected\nChannel ID: {channel_id}\nCommands: help\n")
+            }
+
+            Event::IncomingPlain {
+                at,
+                author,
+                content,
+                ..
+            } => format!(
+                "\n[{}] {} {}: {}",
+                ts(at).dimmed(),
+                "←".cyan(),
+                author.cyan(),
+                content
+            ),
+
+            Event::IncomingPgp {
+                at,
+                author,
+                id,
+                status,
+                signer,
+                plaintext,
+                hidden,
+                hidden_to,
+                error,
+                ..
+            } => {
+                let mut s = format!(
+                    "\n[{}] {} {}: {} {} {}",
+                    ts(at).dimmed(),
+                    "←".cyan(),
+                    author.cyan(),
+                    "[PGP]".purple(),
+                    format!("id={}", ids::short(id)).dimmed(),
+                    pgp_status(*status)
+                );
+                if matches!(
+                    status,
+                    PgpStatus::KeyLocked | PgpStatus::Skipped | PgpStatus::RateLimited
+                ) {
+                    s.push_str(&format!(
+                        " {}",
+                        format!("(pgp decrypt {})", ids::short(id)).dimmed()
+                    ));
+                }
+                if *hidden {
+                    s.push_str(&format!(
+                        " {}",
+                        format!("(hidden, pgp show {})", ids::short(id)).dimmed()
+                    ));
+                }
+                if let Some(e) = error {
+                    s.push_str(&format!(" {}", format!("({e})").red()));
+                }
+                if let Some(fpr) = hidden_to {
+                    s.push_str(&format!(" {}", opened_with(fpr)));
+                }
+                if let Some(sig) = signer {
+                    s.push_str(&format!(" {}", signature(sig)));
+                }
+                if let Some(pt) = plaintext {
+                    s.push_str(&format!(" \n{}", pt.as_str().green()));
+                }
+                s
+            }
+
+            Event::Decrypt {
+                id,
+                status,
+                signer,
+                plaintext,
+                hidden_to,
+                hide_after,
+            } => {
+                let label = match status {
+                    PgpStatus::Decrypted => "Decrypted".green().bold().to_string(),
+                    PgpStatus::NotForMe => "Not for me".yellow().to_string(),
+                    PgpStatus::NeedsPassphrase => "Needs passphrase".yellow().to_string(),
+                    PgpStatus::KeyLocked => "Secret key locked".yellow().to_string(),
+                    PgpStatus::Invalid => "Invalid PGP message".red().to_string(),
+                    PgpStatus::Pending | PgpStatus::Skipped | PgpStatus::RateLimited => {
+                        "Not decrypted".yellow().to_string()
+                    }
+                    PgpStatus::Error => "Decrypt error".red().to_string(),
+                };
+                let mut s = format!("{} {}", label, format!("(id={})", ids::short(id)).dimmed());
+                if let Some(fpr) = hidden_to {
+                    s.push_str(&format!(" {}", opened_with(fpr)));
+                }
+                if let Some(sig) = signer {
+                    s.push_str(&format!(" {}", signature(sig)));
+                }
+                if let Some(secs) = hide_after {
+                    s.push_str(&format!(" {}", format!("(hidden in {secs}s)").dimmed()));
+                }
+                if let Some(pt) = plaintext {
+                    s.push('\n');
+                    s.push_str(pt);
+                }
+                s
+            }
+
+            Event::IncomingSigned {
+                at,
+                author,
+                id,
+                signer,
+                text,
+                error,
+                ..
+            } => {
+                let mut s = format!(
+                    "\n[{}] {} {}: {} {} {}",
+                    ts(at).dimmed(),
+                    "←".cyan(),
+                    author.cyan(),
+                    "[PGP]".purple(),
+                    format!("id={}", ids::short(id)).dimmed(),
+                    match signer {
+                        Some(sig) => signed_by(sig),
+                        None => "signed, could not be verified".red().to_string(),
+                    }
+                );
+                if let Some(e) = error {
+                    s.push_str(&format!(" {}", format!("({e})").red()));
+                }
+                if let Some(text) = text {
+                    s.push('\n');
+                    s.push_str(text);
+                }
+                s
+            }
+
+            Event::IncomingArmor {
+                at,
+                author,
+                id,
+                kind,
+                ..
+            } => {
+                let what = match kind {
+                    ArmorKind::PrivateKey => "private key posted in chat!".red().bold().to_string(),
+                    ArmorKind::Signature => "detached signature".yellow().to_string(),
+                    kind => kind.as_str().yellow().to_string(),
+                };
+                format!(
+                    "\n[{}] {} {}: {} {} {}",
+                    ts(at).dimmed(),
+                    "←".cyan(),
+                    author.cyan(),
+                    "[PGP]".purple(),
+                    format!("id={}", ids::short(id)).dimmed(),
+                    what
+                )
+            }
+
+            Event::IncomingSession {
+                at,
+                author,
+                id,
+                session,
+                plaintext,
+                error,
+                ..
+            } => {
+                let mut s = format!(
+                    "\n[{}] {} {}: {} {}",
+                    ts(at).dimmed(),
+                    "←".cyan(),
+                    author.cyan(),
+                    format!("[session {}]", ids::short(session)).purple(),
+                    format!("id={}", ids::short(id)).dimmed(),
+                );
+                if let Some(e) = error {
+                    s.push_str(&format!(" {}", format!("({e})").yellow()));
+                }
+                if let Some(pt) = plaintext {
+                    s.push_str(&format!(" \n{}", pt.as_str().green()));
+                }
+                s
+            }
+
+            Event::Sent { .. } => "→ sent".green().to_string(),
+
+            Event::Signed { .. } => "→ sent signed message".green().to_string(),
+
+            Event::SentPgp { to, hidden, .. } => match to {
+                Some(to) => format!(
+                    "{} {} {}{}",
+                    "→ sent encrypted PGP message".green(),
+                    "to".dimmed(),
+                    to.cyan(),
+                    match hidden {
+                        true => format!(" {}", "(recipient hidden)".dimmed()),
+                        false => String::new(),
+                    }
+                ),
+                None => format!(
+                    "{} {}",
+                    "→ sent encrypted PGP message".green(),
+                    "(passphrase)".dimmed()
+                ),
+            },
+
+            Event::SentSession { session, to, .. } => format!(
+                "{} {} {} {}",
+                "→ sent in session".green(),
+                ids::short(session).purple(),
+                "to".dimmed(),
+                to.cyan()
+            ),
+
+            Event::Sessions(sessions) => {
+                if sessions.is_empty() {
+                    return "No sessions.".yellow().to_string();
+                }
+                let mut s = "Sessions (kept until exit):".bold().to_string();
+                for i in sessions {
+                    s.push_str(&format!(
+                        "\n  {} {} {} {}",
+                        ids::short(&i.id).purple(),
+                        i.peer.cyan(),
+                        format!("channel {}", i.channel_id).dimmed(),
+                        match i.state {
+                            SessionState::Active => i.state.as_str().green().to_string(),
+                            _ => i.state.as_str().yellow().to_string(),
+                        }
+                    ));
+                }
+                s
+            }
+
+            Event::SentGroup {
+                group,
+                epoch,
+                members,
+                ..
+            } => format!(
+                "{} {} {}",
+                "→ sent to group".green(),
+                ids::short(group).purple(),
+                format!("(key epoch {epoch}, {members} members)").dimmed()
+            ),
+
+            Event::Group { info, .. } => match info {
+                None => "No group in this channel (group create <member...>)"
+                    .yellow()
+                    .to_string(),
+                Some(g) => {
+                    let mut s = format!(
+                        "{} {} {}",
+                        "Group".bold(),
+                        ids::short(&g.gid).purple(),
+                        format!("key epoch {} from {}", g.epoch, g.from).dimmed()
+                    );
+                    for m in &g.members {
+                        s.push_str(&format!("\n  {}", m.dimmed()));
+                    }
+                    s
+                }
+            },
+
+            Event::Exported {
+                name,
+                value,
+                detail,
+            } => {
+                let mut s = format!("{} {}", format!("exported {name} =").green(), value.cyan());
+                if let Some(d) = detail {
+                    s.push_str(&format!(" {}", d.dimmed()));
+                }
+                s
+            }
+
+            Event::Unset { name, detail } => {
+                let mut s = format!("unset {name}").yellow().to_string();
+                if let Some(d) = detail {
+                    s.push_str(&format!(" {}", format!("({d})").yellow()));
+                }
+                s
+            }
+
+            Event::Exports {
+                channel_id,
+                recipient,
+                passphrase,
+                passphrase_ttl,
+                auto_decrypt,
+                decrypt_rate,
+                ephemeral,
+            } => {
+                let rows = [
+                    ("channel", channel_id.to_string()),
+                    (
+                        "recipient",
+                        recipient.as_deref().unwrap_or("(not set)").to_string(),
+                    ),
+                    (
+                        "passphrase",
+                        if *passphrase { "(set)" } else { "(not set)" }.to_string(),
+                    ),
+                    (
+                        "passphrase-ttl",
+                        match passphrase_ttl {
+                            Some(secs) => format!("{secs}s"),
+                            None => "(not cached)".to_string(),
+                        },
+                    ),
+                    ("auto-decrypt", auto_decrypt.as_str().to_string()),
+                    (
+                        "decrypt-rate",
+                        match decrypt_rate {
+                            0 => "(no limit)".to_string(),
+                            n => format!("{n}/min per author"),
+                        },
+                    ),
+                    (
+                        "ephemeral",
+                        match ephemeral {
+                            Some(secs) => format!("{secs}s"),
+                            None => "(off)".to_string(),
+                        },
+                    ),
+                ];
+                let mut s = "Session exports:".bold().to_string();
+                for (k, v) in rows {
+                    s.push_str(&format!("\n  {} {}", k.dimmed(), v.cyan()));
+                }
+                s
+            }
+
+            Event::ContactAdded { alias, fpr } => format!(
+                "{} {} {} {}",
+                "added contact".green(),
+                alias.cyan(),
+                "=".dimmed(),
+                fpr.dimmed()
+            ),
+
+            Event::ContactRemoved { alias } => {
+                format!("{} {}", "removed contact".yellow(), alias.cyan())
+            }
+
+            Event::Contacts(contacts) => {
+                if contacts.is_empty() {
+                    return "No contacts set.".yellow().to_string();
+                }
+                let mut s = "Contacts:".bold().to_string();
+                for (alias, fpr) in contacts {
+                    s.push_str(&format!("\n  {} {}", alias.cyan(), fpr.dimmed()));
+                }
+                s
+            }
+
+            Event::KeyList(keys) => {
+                if keys.is_empty() {
+                    return "No public keys found in your GPG keyring."
+                        .yellow()
+                        .to_string();
+                }
+                let mut lines = vec!["Public keys (recipients):".bold().to_string()];
+                for k in keys {
+                    lines.extend(public_key(k));
+                }
+                lines.join("\n")
+            }
+
+            Event::SecretKeys { gpg_version, fprs } => {
+                let mut s = gpg_version.dimmed().to_string();
+                if fprs.is_empty() {
+                    s.push_str(&format!(
+                        "\n{}",
+                        "No secret keys found in your GPG keyring.".yellow()
+                    ));
+                } else {
+                    s.push_str(&format!("\n{}", "Secret key fingerprints:".bold()));
+                    for f in fprs {
+                        s.push_str(&format!("\n  {}", f.dimmed()));
+                    }
+                }
+                s
+            }
+
+            Event::PgpList(captured) => {
+                if captured.is_empty() {
+                    return "No PGP messages captured yet.".yellow().to_string();
+                }
+                let mut s = "Captured PGP messages (latest last):".bold().to_string();
+                for c in captured {
+                    s.push_str(&format!(
+                        "\n  {} {} {} {} {}",
+                        "id=".dimmed(),
+                        ids::short(&c.id).purple(),
+                        c.author.cyan(),
+                        format!("({} chars)", c.block.len()).dimmed(),
+                        c.status.as_str()
+                    ));
+                }
+                s
+            }
+
+            Event::SearchResults { query, hits } => {
+                if hits.is_empty() {
+                    return format!("No messages match '{query}'.").yellow().to_string();
+                }
+                let mut s = format!(
+                    "{} {}",
+                    hits.len().to_string().cyan(),
+                    match hits.len() {
+                        1 => "match".bold(),
+                        _ => "matches".bold(),
+                    }
+                );
+                for h in hits {
+                    let e = &h.entry;
+                    s.push_str(&format!(
+                        "\n[{}] {} {}",
+                        e.at.format("%Y-%m-%d %H:%M").dimmed(),
+                        e.message.channel_id.to_string().dimmed(),
+                        e.message.author.cyan()
+                    ));
+                    if let Some(id) = &e.pgp_id {
+                        s.push_str(&format!(
+                            " {} {}",
+                            "[PGP]".purple(),
+                            format!("id={}", ids::short(id)).dimmed()
+                        ));
+                    }
+                    if h.truncated.0 {
+                        s.push_str(&format!("\n  {}", "…".dimmed()));
+                    }
+                    for l in &h.snippet {
+                        s.push_str(&format!("\n  {}", highlight(l, query)));
+                    }
+                    if h.truncated.1 {
+                        s.push_str(&format!("\n  {}", "…".dimmed()));
+                    }
+                }
+                s
+            }
+
+            Event::ArchiveExported {
+                path,
+                messages,
+                encrypted,
+                plaintext,
+            } => {
+                let mut s = format!(
+                    "{} {} {} {}",
+                    "archived".green(),
+                    format!("{messages} messages ({encrypted} PGP)").cyan(),
+                    "to".dimmed(),
+                    path.cyan()
+                );
+                if *plaintext {
+                    s.push_str(&format!(" {}", "(includes plaintext)".yellow()));
+                }
+                s
+            }
+
+            Event::ArchiveImported { path, messages } => format!(
+                "{} {} {} {}",
+                "Importing".bold(),
+                messages.to_string().cyan(),
+                "messages from".bold(),
+                path.cyan()
+            ),
+
+            Event::Loading { fetched, requested } => format!(
+                "{} {}/{} {}",
+                "Loading".bold(),
+                fetched.to_string().cyan(),
+                requested.to_string().cyan(),
+                "messages...".bold()
+            ),
+
+            Event::Help => help(),
+
+            Event::Info(msg) => msg.dimmed().to_string(),
+            Event::Warning(msg) => msg.yellow().to_string(),
+            Event::Error(msg) => format!("{} {}", "!".red().bold(), msg.red()),
+        }
+    }
+
+    fn screen(&self, change: Screen<'_>) -> Option<String> {
+        Some(match change {
+            Screen::Clear => "\x1B[2J\x1B[H".to_string(),
+            // a line printer can't take back single lines, scrollback goes too
+            Screen::Hide { id } => {
+                let id = ids::short(id);
+                format!(
+                    "\x1B[2J\x1B[3J\x1B[H{}",
+                    self.render(&Event::Info(format!(
+                        "Plaintext of id={id} cleared from the screen (pgp show {id})"
+                    )))
+                )
+            }
+            Screen::Wipe => "\x1B[2J\x1B[3J\x1B[H".to_string(),
+        })
+    }
+}
+
+impl Renderer for Plain {
+    fn render(&self, ev: &Event) -> String {
+        strip_ansi(&Ansi.render(ev))
+    }
+
+    /// Without escape codes nothing printed can be taken back
+    fn screen(&self, change: Screen<'_>) -> Option<String> {
+        match change {
+            Screen::Clear | Screen::Wipe => None,
+            Screen::Hide { id } => {
+                let id = ids::short(id);
+                Some(self.render(&Event::Warning(format!(
+                    "Plaintext of id={id} is due to be hidden, clear your scrollback (pgp show {id})"
+                ))))
+            }
+        }
+    }
+}
+
+impl Renderer for JsonLines {
+    fn render(&self, ev: &Event) -> String {
+        json_event(ev).to_string()
+    }
+
+    fn screen(&self, change: Screen<'_>) -> Option<String> {
+        let v = match change {
+            Screen::Clear => json!({ "type": "clear" }),
+            Screen::Hide { id } => json!({ "type": "hide", "id": id }),
+            Screen::Wipe => json!({ "type": "wipe" }),
+        };
+        Some(v.to_string())
+    }
+}
+
+fn ts(at: &DateTime<Local>) -> String {
+    at.format("%H:%M:%S").to_string()
+}
+
+fn pgp_status(status: PgpStatus) -> String {
+    match status {
+        PgpStatus::Decrypted => status.as_str().green().to_string(),
+        PgpStatus::NotForMe
+        | PgpStatus::NeedsPassphrase
+        | PgpStatus::KeyLocked
+        | PgpStatus::RateLimited => status.as_str().yellow().to_string(),
+        PgpStatus::Pending | PgpStatus::Skipped => status.as_str().dimmed().to_string(),
+        PgpStatus::Invalid | PgpStatus::Error => status.as_str().red().to_string(),
+    }
+}
+
+fn signature(sig: &Signature) -> String {
+    match sig {
+        Signature::Good { uid, .. } => format!("{} {}", "signed by".dimmed(), uid.green()),
+        Signature::Bad { uid, .. } => format!("BAD signature from {uid}").red().to_string(),
+        Signature::UnknownKey { key_id } => {
+            format!("{} {}", "signed by unknown key".yellow(), key_id.dimmed())
+        }
+    }
+}
+
+/// Which of our keys a message with hidden recipients was for
+fn opened_with(fpr: &str) -> String {
+    format!(
+        "(hidden recipient, your key {})",
+        &fpr[fpr.len().saturating_sub(16)..]
+    )
+    .dimmed()
+    .to_string()
+}
+
+/// Verification result of a cleartext signed message
+fn signed_by(sig: &Signature) -> String {
+    match sig {
+        Signature::Good { uid, .. } => format!("signed by {uid} (valid)").green().to_string(),
+        Signature::Bad { uid, .. } => format!("signed by {uid} (BAD signature)")
+            .red()
+            .bold()
+            .to_string(),
+        Signature::UnknownKey { key_id } => {
+            format!("signed by unknown key {key_id} (not verified)")
+                .yellow()
+                .to_string()
+        }
+    }
+}
+
+/// `line` with case-insensitive occurrences of `needle` emphasized
+fn highlight(line: &str, needle: &str) -> String {
+    let lower = line.to_lowercase();
+    let needle = needle.to_lowercase();
+    // lowercasing can change byte lengths, don't highlight then
+    if needle.is_empty() || lower.len() != line.len() {
+        return line.to_string();
+    }
+
+    let mut out = String::new();
+    let mut pos = 0;
+    while let Some(i) = lower[pos..].find(&needle) {
+        let start = pos + i;
+        let end = start + needle.len();
+        out.push_str(&line[pos..start]);
+        out.push_str(&(&line[start..end]).yellow().bold().to_string());
+        pos = end;
+    }
+    out.push_str(&line[pos..]);
+    out
+}
+
+fn help() -> String {
+    let max_cmd_len = HELP
+        .iter()
+        .flat_map(|(_, rows)| rows.iter())
+        .map(|(c, _)| c.len())
+        .max()
+        .unwrap_or(0);
+
+    let col_width = max_cmd_len + 4;
+
+    let mut s = String::new();
+    for (title, rows) in HELP {
+        s.push_str(&format!("\n{}\n", title.bold()));
+        for (cmd, desc) in *rows {
+            let cmd = format!("{cmd:<width$}", width = col_width);
+            s.push_str(&format!("  {} {}\n", cmd.cyan(), desc.dimmed()));
+        }
+    }
+    s
+}
+
+fn fmt_date(ts: Option<i64>) -> String {
+    ts.and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+        .map(|d| d.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "never".to_string())
+}
+
+fn public_key(k: &PublicKey) -> Vec<String> {
+    let mut out = Vec::new();
+
+    match &k.uid {
+        Some(uid) => out.push(format!("  {}  —  {}", k.fpr.dimmed(), uid)),
+        _ => out.push(format!("  {}", k.fpr.dimmed())),
+    }
+    for uid in k.uids.iter().skip(1) {
+        out.push(format!("  {:>40}  {}", "aka".dimmed(), uid));
+    }
+
+    let validity = match k.validity {
+        Validity::Revoked | Validity::Expired | Validity::Disabled | Validity::Invalid => {
+            k.validity.as_str().red().to_string()
+        }
+        Validity::Full | Validity::Ultimate => k.validity.as_str().green().to_string(),
+        _ => k.validity.as_str().yellow().to_string(),
+    };
+
+    let usable = match k.check_encrypt() {
+        Ok(()) => String::new(),
+        Err(e) => format!("  {}", format!("({e})").red()),
+    };
+
+    out.push(format!(
+        "    {}{} [{}] {} {} {} {} {} {}{}",
+        k.algo,
+        k.bits,
+        k.caps,
+        "created".dimmed(),
+        fmt_date(k.created),
+        "expires".dimmed(),
+        fmt_date(k.expires),
+        "validity".dimmed(),
+        validity,
+        usable
+    ));
+
+    for sub in &k.subkeys {
+        out.push(format!(
+            "    {} {}{} [{}] {} {} {}",
+            "sub".dimmed(),
+            sub.algo,
+            sub.bits,
+            sub.caps,
+            "expires".dimmed(),
+            fmt_date(sub.expires),
+            sub.validity.as_str().dimmed()
+        ));
+    }
+
+    out
+}
+
+/// Drop SGR escape sequences (`ESC [ ... m`)
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1B' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn json_signature(sig: &Option<Signature>) -> Value {
+    match sig {
+        None => Value::Null,
+        Some(s) => json!({
+            "valid": matches!(s, Signature::Good { .. }),
+            "key_id": s.key_id(),
+            "signer": s.signer(),
+        }),
+    }
+}
+
+fn json_key(k: &PublicKey) -> Value {
+    json!({
+        "fpr": k.fpr,
+        "uids": k.uids,
+        "validity": k.validity.as_str(),
+        "algo": k.algo,
+        "bits": k.bits,
+        "caps": k.caps.to_string(),
+        "created": k.created,
+        "expires": k.expires,
+        "usable": k.check_encrypt().err().map(|e| e.to_string()).unwrap_or_else(|| "ok".into()),
+        "subkeys": k.subkeys.iter().map(|s| json!({
+            "fpr": s.fpr,
+            "validity": s.validity.as_str(),
+            "algo": s.algo,
+            "bits": s.bits,
+            "caps": s.caps.to_string(),
+            "expires": s.expires,
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn json_event(ev: &Event) -> Value {
+    match ev {
+        Event::Connected { channel_id } => {
+            json!({ "type": "connected", "channel_id": channel_id.to_string() })
+        }
+        Event::IncomingPlain {
+            at,
+            channel_id,
+            author,
+            content,
+        } => json!({
+            "type": "incoming_plain",
+            "at": at.to_rfc3339(),
+            "channel_id": channel_id.to_string(),
+            "author": author,
+            "content": content,
+        }),
+        Event::IncomingPgp {
+            at,
+            channel_id,
+            author,
+            id,
+            status,
+            signer,
+            plaintext,
+            hidden,
+            hidden_to,
+            error,
+        } => json!({
+            "type": "incoming_pgp",
+            "at": at.to_rfc3339(),
+            "channel_id": channel_id.to_string(),
+            "author": author,
+            "id": id,
+            "status": status.as_str(),
+            "signer": json_signature(signer),
+            "plaintext": plaintext.as_deref(),
+            "hidden": hidden,
+            "hidden_to": hidden_to,
+            "error": error,
+        }),
+        Event::Decrypt {
+            id,
+            status,
+            signer,
+            plaintext,
+            hidden_to,
+            hide_after,
+        } => json!({
+            "type": "decrypt",
+            "id": id,
+            "status": status.as_str(),
+            "signer": json_signature(signer),
+            "plaintext": plaintext.as_deref(),
+            "hidden_to": hidden_to,
+            "hide_after": hide_after,
+        }),
+        Event::Sent { channel_id } => {
+            json!({ "type": "sent", "channel_id": channel_id.to_string() })
+        }
+        Event::IncomingSigned {
+            at,
+            channel_id,
+            author,
+            id,
+            signer,
+            text,
+            error,
+        } => json!({
+            "type": "incoming_signed",
+            "at": at.to_rfc3339(),
+            "channel_id": channel_id.to_string(),
+            "author": author,
+            "id": id,
+            "signer": json_signature(signer),
+            "text": text,
+            "error": error,
+        }),
+        Event::IncomingArmor {
+            at,
+            channel_id,
+            author,
+            id,
+            kind,
+        } => json!({
+            "type": "incoming_armor",
+            "at": at.to_rfc3339(),
+            "channel_id": channel_id.to_string(),
+            "author": author,
+            "id": id,
+            "kind": kind.as_str(),
+        }),
+        Event::IncomingSession {
+            at,
+            channel_id,
+            author,
+            id,
+            session,
+            plaintext,
+            error,
+        } => json!({
+            "type": "incoming_session",
+            "at": at.to_rfc3339(),
+            "channel_id": channel_id.to_string(),
+            "author": author,
+            "id": id,
+            "session": session,
+            "plaintext": plaintext.as_deref(),
+            "error": error,
+        }),
+        Event::Signed { channel_id } => {
+            json!({ "type": "signed", "channel_id": channel_id.to_string() })
+        }
+        Event::SentPgp {
+            channel_id,
+            to,
+            hidden,
+        } => json!({
+            "type": "sent_pgp",
+            "channel_id": channel_id.to_string(),
+            "to": to,
+            "hidden": hidden,
+        }),
+        Event::SentSession {
+            channel_id,
+            session,
+            to,
+        } => json!({
+            "type": "sent_session",
+            "channel_id": channel_id.to_string(),
+            "session": session,
+            "to": to,
+        }),
+        Event::Sessions(sessions) => json!({
+            "type": "sessions",
+            "sessions": sessions
+                .iter()
+                .map(|i| json!({
+                    "id": i.id,
+                    "peer": i.peer,
+                    "channel_id": i.channel_id.to_string(),
+                    "state": i.state.as_str(),
+                }))
+                .collect::<Vec<_>>(),
+        }),
+        Event::SentGroup {
+            channel_id,
+            group,
+            epoch,
+            members,
+        } => json!({
+            "type": "sent_group",
+            "channel_id": channel_id.to_string(),
+            "group": group,
+            "epoch": epoch,
+            "members": members,
+        }),
+        Event::Group { channel_id, info } => json!({
+            "type": "group",
+            "channel_id": channel_id.to_string(),
+            "group": info.as_ref().map(|g| json!({
+                "id": g.gid,
+                "epoch": g.epoch,
+                "members": g.members,
+                "from": g.from,
+            })),
+        }),
+        Event::Exported {
+            name,
+            value,
+            detail,
+        } => json!({ "type": "exported", "name": name, "value": value, "detail": detail }),
+        Event::Unset { name, detail } => {
+            json!({ "type": "unset", "name": name, "detail": detail })
+        }
+        Event::Exports {
+            channel_id,
+            recipient,
+            passphrase,
+            passphrase_ttl,
+            auto_decrypt,
+            decrypt_rate,
+            ephemeral,
+        } => json!({
+            "type": "exports",
+            "channel_id": channel_id.to_string(),
+            "recipient": recipient,
+            "passphrase": passphrase,
+            "passphrase_ttl": passphrase_ttl,
+            "auto_decrypt": auto_decrypt.as_str(),
+            "decrypt_rate": decrypt_rate,
+            "ephemeral": ephemeral,
+        }),
+        Event::ContactAdded { alias, fpr } => {
+            json!({ "type": "contact_added", "alias": alias, "fpr": fpr })
+        }
+        Event::ContactRemoved { alias } => json!({ "type": "contact_removed", "alias": alias }),
+        Event::Contacts(contacts) => json!({
+            "type": "contacts",
+            "contacts": contacts
+                .iter()
+                .map(|(alias, fpr)| json!({ "alias": alias, "fpr": fpr }))
+                .collect::<Vec<_>>(),
+        }),
+        Event::KeyList(keys) => json!({
+            "type": "keys",
+            "keys": keys.iter().map(json_key).collect::<Vec<_>>(),
+        }),
+        Event::SecretKeys { gpg_version, fprs } => json!({
+            "type": "secret_keys",
+            "gpg_version": gpg_version,
+            "fprs": fprs,
+        }),
+        Event::PgpList(captured) => json!({
+            "type": "pgp_list",
+            "blocks": captured
+                .iter()
+                .map(|c| json!({
+                    "id": c.id,
+                    "channel_id": c.channel_id.to_string(),
+                    "author": c.author,
+                    "chars": c.block.len(),
+                    "status": c.status.as_str(),
+                }))
+                .collect::<Vec<_>>(),
+        }),
+        Event::SearchResults { query, hits } => json!({
+            "type": "search_results",
+            "query": query,
+            "hits": hits
+                .iter()
+                .map(|h| json!({
+                    "message_id": h.entry.message.message_id.to_string(),
+                    "at": h.entry.at.to_rfc3339(),
+                    "channel_id": h.entry.message.channel_id.to_string(),
+                    "author": h.entry.message.author,
+                    "pgp_id": h.entry.pgp_id,
+                    "snippet": h.snippet,
+                }))
+                .collect::<Vec<_>>(),
+        }),
+        Event::ArchiveExported {
+            path,
+            messages,
+            encrypted,
+            plaintext,
+        } => json!({
+            "type": "archive_exported",
+            "path": path,
+            "messages": messages,
+            "encrypted": encrypted,
+            "plaintext": plaintext,
+        }),
+        Event::ArchiveImported { path, messages } => json!({
+            "type": "archive_imported",
+            "path": path,
+            "messages": messages,
+        }),
+        Event::Loading { fetched, requested } => json!({
+            "type": "loading",
+            "fetched": fetched,
+            "requested": requested,
+        }),
+        Event::Help => json!({
+            "type": "help",
+            "sections": HELP
+                .iter()
+                .map(|(title, rows)| json!({
+                    "title": title.trim_end_matches(':'),
+                    "commands": rows
+                        .iter()
+                        .map(|(cmd, desc)| json!({ "command": cmd, "description": desc }))
+                        .collect::<Vec<_>>(),
+                }))
+                .collect::<Vec<_>>(),
+        }),
+        Event::Info(msg) => json!({ "type": "info", "message": msg }),
+        Event::Warning(msg) => json!({ "type": "warning", "message": msg }),
+        Event::Error(msg) => json!({ "type": "error", "message": msg }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use common::SecretString;
+
+    const ID: &str = "123456789-0";
+
+    fn at() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 45).unwrap()
+    }
+
+    fn incoming(plaintext: Option<&str>, status: PgpStatus) -> Event {
+        Event::IncomingPgp {
+            at: at(),
+            channel_id: 42,
+            author: "bob".to_string(),
+            id: ID.to_string(),
+            status,
+            signer: None,
+            plaintext: plaintext.map(|p| SecretString::new(p.to_string())),
+            hidden: false,
+            hidden_to: None,
+            error: None,
+        }
+    }
+
+    fn events() -> Vec<Event> {
+        vec![
+            Event::Connected { channel_id: 42 },
+            Event::IncomingPlain {
+                at: at(),
+                channel_id: 42,
+                author: "bob".to_string(),
+                content: "hi there".to_string(),
+            },
+            incoming(Some("meet at noon"), PgpStatus::Decrypted),
+            incoming(None, PgpStatus::RateLimited),
+            Event::Decrypt {
+                id: ID.to_string(),
+                status: PgpStatus::KeyLocked,
+                signer: None,
+                plaintext: None,
+                hidden_to: None,
+                hide_after: None,
+            },
+            Event::SentPgp {
+                channel_id: 42,
+                to: Some("Alice <alice@example.org>".to_string()),
+                hidden: true,
+            },
+            Event::KeyList(Vec::new()),
+            Event::SearchResults {
+                query: "noon".to_string(),
+                hits: Vec::new(),
+            },
+            Event::Help,
+            Event::Info("note".to_string()),
+            Event::Warning("careful".to_string()),
+            Event::Error("boom".to_string()),
+        ]
+    }
+
+    #[test]
+    fn plain_is_ansi_without_escapes() {
+        for ev in events() {
+            let plain = Plain.render(&ev);
+            assert!(!plain.contains('\x1B'), "{plain:?}");
+            assert_eq!(plain, strip_ansi(&Ansi.render(&ev)));
+        }
+    }
+
+    #[test]
+    fn plain_text() {
+        let short = ids::short(ID);
+        assert_eq!(
+            Plain.render(&Event::Connected { channel_id: 42 }),
+            "discord — connected\nChannel ID: 42\nCommands: help\n"
+        );
+        assert_eq!(
+            Plain.render(&incoming(Some("meet at noon"), PgpStatus::Decrypted)),
+            format!("\n[12:30:45] ← bob: [PGP] id={short} decrypted \nmeet at noon")
+        );
+        assert_eq!(
+            Plain.render(&incoming(None, PgpStatus::RateLimited)),
+            format!("\n[12:30:45] ← bob: [PGP] id={short} rate limited (pgp decrypt {short})")
+        );
+        assert_eq!(Plain.render(&Event::Error("boom".into())), "! boom");
+    }
+
+    #[test]
+    fn json_is_one_object_per_line() {
+        for ev in events() {
+            let line = JsonLines.render(&ev);
+            assert!(!line.contains('\n'), "{line}");
+            let v: Value = serde_json::from_str(&line).unwrap();
+            assert!(v["type"].is_string(), "{line}");
+        }
+
+        let v: Value = serde_json::from_str(
+            &JsonLines.render(&incoming(Some("meet at noon"), PgpStatus::Decrypted)),
+        )
+        .unwrap();
+        assert_eq!(v["type"], "incoming_pgp");
+        // ids as strings, they don't fit in a JSON double
+        assert_eq!(v["channel_id"], "42");
+        assert_eq!(v["id"], ID);
+        assert_eq!(v["status"], "decrypted");
+        assert_eq!(v["plaintext"], "meet at noon");
+        assert_eq!(v["signer"], Value::Null);
+
+        let v: Value =
+            serde_json::from_str(&JsonLines.render(&Event::Warning("careful".into()))).unwrap();
+        assert_eq!(v, json!({ "type": "warning", "message": "careful" }));
+    }
+
+    #[test]
+    fn screen_changes_per_output() {
+        let hide = Screen::Hide { id: ID };
+        for change in [Screen::Clear, hide, Screen::Wipe] {
+            assert!(Ansi.screen(change).unwrap().starts_with("\x1B[2J"));
+        }
+
+        assert_eq!(Plain.screen(Screen::Clear), None);
+        assert_eq!(Plain.screen(Screen::Wipe), None);
+        let text = Plain.screen(hide).unwrap();
+        assert!(
+            !text.contains('\x1B') && text.contains(ids::short(ID)),
+            "{text}"
+        );
+
+        let json =
+            |change| serde_json::from_str::<Value>(&JsonLines.screen(change).unwrap()).unwrap();
+        assert_eq!(json(Screen::Clear), json!({ "type": "clear" }));
+        assert_eq!(json(hide), json!({ "type": "hide", "id": ID }));
+        assert_eq!(json(Screen::Wipe), json!({ "type": "wipe" }));
+    }
+}
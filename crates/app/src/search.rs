@@ -0,0 +1,423 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use std::collections::VecDeque;
+
+use common::SecretString;
+use crypto::gpg::{Decrypted, Signature};
+
+use crate::cmdline::Args;
+
+const MAX_ENTRIES: usize = 5000;
+
+/// A message seen this session
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    /// The message as received, ciphertext included
+    pub message: transport::ChatEvent,
+    /// Which piece of the message: text between blocks, or a block
+    pub part: usize,
+    pub at: DateTime<Local>,
+    /// PGP block id, for encrypted blocks
+    pub pgp_id: Option<String>,
+    /// Text of the part, or the plaintext once the block decrypted
+    pub text: Option<SecretString>,
+    pub signer: Option<Signature>,
+}
+
+/// Messages of the live session, searchable by plaintext
+#[derive(Debug, Clone, Default)]
+pub struct MessageLog {
+    entries: VecDeque<LogEntry>,
+}
+
+impl MessageLog {
+    /// Add a message part, replacing an earlier copy (e.g. from `load`)
+    pub fn push(&mut self, entry: LogEntry) {
+        if let Some(old) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.message.message_id == entry.message.message_id && e.part == entry.part)
+        {
+            // keep plaintext a replayed copy failed to decrypt
+            let text = entry.text.or(old.text.take());
+            let signer = entry.signer.or(old.signer.take());
+            *old = LogEntry {
+                text,
+                signer,
+                ..entry
+            };
+            return;
+        }
+        self.entries.push_back(entry);
+        while self.entries.len() > MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Record the plaintext of a block decrypted later on
+    pub fn set_decrypted(&mut self, pgp_id: &str, d: &Decrypted) {
+        for e in self
+            .entries
+            .iter_mut()
+            .filter(|e| e.pgp_id.as_deref() == Some(pgp_id))
+        {
+            e.text = Some(d.plaintext.clone());
+            e.signer = d.signature.clone();
+        }
+    }
+
+    /// Plaintext of an already decrypted block
+    pub fn decrypted(&self, pgp_id: &str) -> Option<Decrypted> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| e.pgp_id.as_deref() == Some(pgp_id))
+            .and_then(|e| {
+                Some(Decrypted {
+                    plaintext: e.text.clone()?,
+                    signature: e.signer.clone(),
+                    hidden_to: None,
+                    signer_fpr: None,
+                })
+            })
+    }
+
+    /// Ids of all logged PGP blocks
+    pub fn pgp_ids(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().filter_map(|e| e.pgp_id.as_deref())
+    }
+
+    /// Overwrite and drop all decrypted plaintext
+    pub fn wipe_plaintext(&mut self) {
+        for e in self.entries.iter_mut().filter(|e| e.pgp_id.is_some()) {
+            e.text = None;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LogEntry> {
+        self.entries.iter()
+    }
+
+    pub fn search(&self, q: &Query) -> Vec<Hit> {
+        let needle = q.text.to_lowercase();
+
+        self.entries
+            .iter()
+            .filter(|e| !q.encrypted_only || e.pgp_id.is_some())
+            .filter(|e| q.channel_id.is_none_or(|ch| e.message.channel_id == ch))
+            .filter(|e| {
+                q.author
+                    .as_deref()
+                    .is_none_or(|a| e.message.author.to_lowercase().contains(&a.to_lowercase()))
+            })
+            .filter(|e| q.since.is_none_or(|t| e.at >= t))
+            .filter(|e| q.until.is_none_or(|t| e.at < t))
+            .filter_map(|e| {
+                let text = e.text.as_deref()?;
+                let lines: Vec<&str> = text.lines().collect();
+                let first = lines
+                    .iter()
+                    .position(|l| l.to_lowercase().contains(&needle))?;
+                let last = lines
+                    .iter()
+                    .rposition(|l| l.to_lowercase().contains(&needle))
+                    .unwrap_or(first);
+
+                let from = first.saturating_sub(q.context);
+                let to = (last + q.context + 1).min(lines.len());
+                Some(Hit {
+                    entry: e.clone(),
+                    snippet: lines[from..to].iter().map(|l| l.to_string()).collect(),
+                    truncated: (from > 0, to < lines.len()),
+                })
+            })
+            .collect()
+    }
+}
+
+/// A matching message with the lines around the match
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub entry: LogEntry,
+    pub snippet: Vec<String>,
+    /// Lines left out (before, after) the snippet
+    pub truncated: (bool, bool),
+}
+
+/// `search` arguments
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub text: String,
+    pub author: Option<String>,
+    pub channel_id: Option<u64>,
+    pub since: Option<DateTime<Local>>,
+    pub until: Option<DateTime<Local>>,
+    pub encrypted_only: bool,
+    /// Lines of context around matching lines
+    pub context: usize,
+}
+
+pub const USAGE: &str = "Usage: search [--author <name>] [--channel <id>] [--since <YYYY-MM-DD>] \
+     [--until <YYYY-MM-DD>] [--encrypted] [--context <lines>] <query...>";
+
+impl Query {
+    pub fn parse(mut args: Args) -> Result<Self> {
+        let mut q = Query {
+            context: 1,
+            ..Default::default()
+        };
+
+        while let Some(flag) = args.peek_flag() {
+            match flag.as_str() {
+                "--author" => {
+                    args.next()?;
+                    q.author = Some(args.expect(USAGE)?);
+                }
+                "--channel" => {
+                    args.next()?;
+                    q.channel_id = Some(
+                        args.expect(USAGE)?
+                            .parse()
+                            .map_err(|_| anyhow!("channel_id must be an integer"))?,
+                    );
+                }
+                "--since" => {
+                    args.next()?;
+                    q.since = Some(start_of_day(&args.expect(USAGE)?)?);
+                }
+                "--until" => {
+                    args.next()?;
+                    // inclusive: up to the start of the next day
+                    q.until = Some(start_of_day(&args.expect(USAGE)?)? + chrono::Days::new(1));
+                }
+                "--encrypted" | "-e" => {
+                    args.next()?;
+                    q.encrypted_only = true;
+                }
+                "--context" | "-C" => {
+                    args.next()?;
+                    q.context = args
+                        .expect(USAGE)?
+                        .parse()
+                        .map_err(|_| anyhow!("--context must be a number of lines"))?;
+                }
+                "--" => {
+                    args.next()?;
+                    break;
+                }
+                _ => break,
+            }
+        }
+
+        q.text = args.value()?;
+        if q.text.is_empty() {
+            return Err(anyhow!(USAGE));
+        }
+        Ok(q)
+    }
+}
+
+fn start_of_day(date: &str) -> Result<DateTime<Local>> {
+    let d = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| anyhow!("Dates are YYYY-MM-DD, got {date}"))?;
+    Local
+        .from_local_datetime(&d.and_time(chrono::NaiveTime::MIN))
+        .earliest()
+        .ok_or_else(|| anyhow!("{date} has no local midnight"))
+}
+
+/// Local time of a unix timestamp, now if it's out of range
+pub fn local_time(unix: i64) -> DateTime<Local> {
+    Local
+        .timestamp_opt(unix, 0)
+        .single()
+        .unwrap_or_else(Local::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str, time: &str) -> DateTime<Local> {
+        let t =
+            chrono::NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%Y-%m-%d %H:%M:%S")
+                .unwrap();
+        Local.from_local_datetime(&t).earliest().unwrap()
+    }
+
+    fn entry(
+        id: u64,
+        channel_id: u64,
+        author: &str,
+        at: DateTime<Local>,
+        pgp: bool,
+        text: Option<&str>,
+    ) -> LogEntry {
+        LogEntry {
+            message: transport::ChatEvent {
+                message_id: id,
+                channel_id,
+                author_id: id,
+                author: author.to_string(),
+                content: String::new(),
+                timestamp: at.timestamp(),
+                mentions: Vec::new(),
+            },
+            part: 0,
+            at,
+            pgp_id: pgp.then(|| format!("{id}-0")),
+            text: text.map(|t| SecretString::new(t.to_string())),
+            signer: None,
+        }
+    }
+
+    fn log() -> MessageLog {
+        let mut log = MessageLog::default();
+        for e in [
+            entry(
+                1,
+                1,
+                "alice",
+                at("2024-05-01", "00:00:00"),
+                false,
+                Some("hello world"),
+            ),
+            entry(
+                2,
+                1,
+                "bob",
+                at("2024-05-01", "23:59:59"),
+                true,
+                Some("secret hello\nbye"),
+            ),
+            entry(
+                3,
+                2,
+                "Alice Smith",
+                at("2024-05-02", "00:00:00"),
+                true,
+                Some("hello from alice"),
+            ),
+            entry(
+                4,
+                2,
+                "bob",
+                at("2024-05-02", "12:00:00"),
+                false,
+                Some("a\nb\nhello\nc\nHello\nd\ne"),
+            ),
+            entry(
+                5,
+                1,
+                "carol",
+                at("2024-05-03", "00:00:00"),
+                false,
+                Some("hello again"),
+            ),
+            // not decrypted, nothing to match
+            entry(6, 1, "alice", at("2024-05-03", "08:00:00"), true, None),
+        ] {
+            log.push(e);
+        }
+        log
+    }
+
+    fn search(line: &str) -> Vec<u64> {
+        let q = Query::parse(Args::new(line)).unwrap();
+        log()
+            .search(&q)
+            .iter()
+            .map(|h| h.entry.message.message_id)
+            .collect()
+    }
+
+    #[test]
+    fn text_alone() {
+        assert_eq!(search("hello"), [1, 2, 3, 4, 5]);
+        assert_eq!(search("HELLO WORLD"), [1]);
+        assert_eq!(search("nowhere"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn author_is_a_case_insensitive_substring() {
+        assert_eq!(search("--author alice hello"), [1, 3]);
+        assert_eq!(search("--author SMITH hello"), [3]);
+        assert_eq!(search("--author bob hello"), [2, 4]);
+    }
+
+    #[test]
+    fn channel() {
+        assert_eq!(search("--channel 2 hello"), [3, 4]);
+        assert_eq!(search("--channel 3 hello"), Vec::<u64>::new());
+        assert!(Query::parse(Args::new("--channel general hello")).is_err());
+    }
+
+    #[test]
+    fn since_and_until_take_whole_days() {
+        // midnight belongs to the day it starts
+        assert_eq!(search("--since 2024-05-02 hello"), [3, 4, 5]);
+        assert_eq!(search("--until 2024-05-01 hello"), [1, 2]);
+        assert_eq!(
+            search("--since 2024-05-02 --until 2024-05-02 hello"),
+            [3, 4]
+        );
+        assert_eq!(search("--since 2024-05-04 hello"), Vec::<u64>::new());
+        assert!(Query::parse(Args::new("--since 05/02/2024 hello")).is_err());
+    }
+
+    #[test]
+    fn encrypted_only() {
+        assert_eq!(search("--encrypted hello"), [2, 3]);
+        assert_eq!(search("-e hello"), [2, 3]);
+    }
+
+    #[test]
+    fn filters_combine() {
+        assert_eq!(search("--author bob --encrypted hello"), [2]);
+        assert_eq!(
+            search("--author alice --channel 1 --since 2024-05-01 hello"),
+            [1]
+        );
+        assert_eq!(
+            search("--author bob --encrypted --since 2024-05-02 hello"),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn context_spans_first_to_last_match() {
+        let snippet = |context: &str| {
+            let q = Query::parse(Args::new(&format!(
+                "--author bob --channel 2 -C {context} hello"
+            )))
+            .unwrap();
+            let hits = log().search(&q);
+            assert_eq!(hits.len(), 1);
+            (hits[0].snippet.clone(), hits[0].truncated)
+        };
+
+        assert_eq!(
+            snippet("0"),
+            (
+                vec!["hello".into(), "c".into(), "Hello".into()],
+                (true, true)
+            )
+        );
+        assert_eq!(
+            snippet("1"),
+            (
+                vec![
+                    "b".into(),
+                    "hello".into(),
+                    "c".into(),
+                    "Hello".into(),
+                    "d".into()
+                ],
+                (true, true)
+            )
+        );
+        let (lines, truncated) = snippet("10");
+        assert_eq!(lines.len(), 7);
+        assert_eq!(truncated, (false, false));
+    }
+}
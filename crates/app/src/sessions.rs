@@ -0,0 +1,328 @@
+use anyhow::{Result, anyhow};
+use common::SecretString;
+use crypto::ratchet::{self, Handshake, KeyExchange, Ratchet};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::ids;
+
+/// Where a session is in its key exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// Our init is out, waiting for their accept
+    Started,
+    /// Their init came in, waiting for `session accept`
+    Offered,
+    Active,
+}
+
+impl SessionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionState::Started => "waiting for accept",
+            SessionState::Offered => "offered to you",
+            SessionState::Active => "active",
+        }
+    }
+}
+
+/// A session as listed by `session list`
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub peer: String,
+    pub channel_id: u64,
+    pub state: SessionState,
+}
+
+#[derive(Debug, Clone)]
+enum Stage {
+    Started(Handshake),
+    Offered([u8; 32]),
+    Active(Box<Ratchet>),
+}
+
+#[derive(Debug, Clone)]
+struct Session {
+    peer_fpr: String,
+    peer: String,
+    channel_id: u64,
+    stage: Stage,
+}
+
+/// Forward-secret sessions with other pgp-disc users, in memory only.
+/// The key exchange is clearsigned, so PGP keys vouch for who is on the
+/// other end, and messages after it are encrypted under ratchet keys.
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    // our primary fingerprints, key exchanges are addressed to them
+    own: Vec<String>,
+    sessions: BTreeMap<String, Session>,
+    // ended sessions, so their init isn't offered again when old
+    // messages are loaded
+    ended: HashSet<String>,
+}
+
+impl Sessions {
+    pub fn new(own: &[String]) -> Self {
+        Self {
+            own: own.to_vec(),
+            sessions: BTreeMap::new(),
+            ended: HashSet::new(),
+        }
+    }
+
+    fn is_own(&self, fpr: &str) -> bool {
+        self.own.iter().any(|f| f.eq_ignore_ascii_case(fpr))
+    }
+
+    /// Full session id for a prefix, among sessions in `states`
+    pub fn resolve(&self, prefix: &str, states: &[SessionState]) -> Result<String> {
+        let ids: Vec<String> = self
+            .list()
+            .into_iter()
+            .filter(|s| states.contains(&s.state))
+            .map(|s| s.id)
+            .collect();
+        ids::resolve_among(prefix, ids.iter().map(String::as_str), "session")
+    }
+
+    /// A new session with the key `peer_fpr`, the init line to sign and post
+    pub fn start(&mut self, peer_fpr: &str, peer: &str, channel_id: u64) -> KeyExchange {
+        let sid = ratchet::new_session_id();
+        let hs = Handshake::new();
+        let init = KeyExchange::Init {
+            sid: sid.clone(),
+            to: peer_fpr.to_uppercase(),
+            key: hs.public(),
+        };
+        self.sessions.insert(
+            sid,
+            Session {
+                peer_fpr: peer_fpr.to_uppercase(),
+                peer: peer.to_string(),
+                channel_id,
+                stage: Stage::Started(hs),
+            },
+        );
+        init
+    }
+
+    /// Take up an offered session: the accept line to sign and post, and
+    /// the handshake to hand to [`Sessions::accepted`] once it's posted
+    pub fn accept(&self, sid: &str) -> Result<(KeyExchange, Handshake)> {
+        let s = self
+            .sessions
+            .get(sid)
+            .ok_or_else(|| anyhow!("No session with id={sid}"))?;
+        let Stage::Offered(_) = s.stage else {
+            return Err(anyhow!("Session {} isn't waiting for you", ids::short(sid)));
+        };
+
+        let hs = Handshake::new();
+        let accept = KeyExchange::Accept {
+            sid: sid.to_string(),
+            to: s.peer_fpr.clone(),
+            key: hs.public(),
+        };
+        Ok((accept, hs))
+    }
+
+    /// Session `sid` established with the handshake of its posted accept
+    pub fn accepted(&mut self, sid: &str, hs: Handshake) -> Result<()> {
+        let s = self
+            .sessions
+            .get_mut(sid)
+            .ok_or_else(|| anyhow!("Session {} ended while accepting", ids::short(sid)))?;
+        let Stage::Offered(theirs) = s.stage else {
+            return Err(anyhow!("Session {} isn't waiting for you", ids::short(sid)));
+        };
+        s.stage = Stage::Active(Box::new(Ratchet::responder(sid, hs, theirs)));
+        Ok(())
+    }
+
+    /// A key exchange line with a good signature by `signer_fpr`. What
+    /// changed, or `None` when it wasn't for us.
+    pub fn key_exchange(
+        &mut self,
+        kx: KeyExchange,
+        signer_fpr: &str,
+        signer: &str,
+        channel_id: u64,
+    ) -> Option<String> {
+        match kx {
+            KeyExchange::Init { sid, to, key }
+                if self.is_own(&to)
+                    && !self.is_own(signer_fpr)
+                    && !self.sessions.contains_key(&sid)
+                    && !self.ended.contains(&sid) =>
+            {
+                let note = format!(
+                    "Session request from {signer}, to take it: session accept {}",
+                    ids::short(&sid)
+                );
+                self.sessions.insert(
+                    sid,
+                    Session {
+                        peer_fpr: signer_fpr.to_uppercase(),
+                        peer: signer.to_string(),
+                        channel_id,
+                        stage: Stage::Offered(key),
+                    },
+                );
+                Some(note)
+            }
+            KeyExchange::Accept { sid, to, key } if self.is_own(&to) => {
+                let s = self.sessions.get_mut(&sid)?;
+                // only the key the init went to can answer it
+                if !s.peer_fpr.eq_ignore_ascii_case(signer_fpr) {
+                    return None;
+                }
+                let Stage::Started(hs) = &s.stage else {
+                    return None;
+                };
+                s.stage = Stage::Active(Box::new(Ratchet::initiator(&sid, hs.clone(), key)));
+                Some(format!(
+                    "Session {} with {} established",
+                    ids::short(&sid),
+                    s.peer
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// `plaintext` as a message of session `sid`, and the channel it goes to
+    pub fn encrypt(&mut self, sid: &str, plaintext: &str) -> Result<(u64, String)> {
+        let s = self
+            .sessions
+            .get_mut(sid)
+            .ok_or_else(|| anyhow!("No session with id={sid}"))?;
+        let Stage::Active(r) = &mut s.stage else {
+            return Err(anyhow!("Session {} isn't established yet", ids::short(sid)));
+        };
+        let data = r.encrypt(plaintext.as_bytes());
+        Ok((s.channel_id, ratchet::encode(sid, &data)))
+    }
+
+    /// Plaintext of a message of session `sid`, padding stripped
+    pub fn decrypt(&mut self, sid: &str, data: &[u8]) -> Result<SecretString> {
+        let Some(Stage::Active(r)) = self.sessions.get_mut(sid).map(|s| &mut s.stage) else {
+            return Err(anyhow!("not in one of your sessions"));
+        };
+        let data = r.decrypt(data)?;
+        let text = SecretString::new(
+            String::from_utf8(data.to_vec()).map_err(|_| anyhow!("plaintext not utf8"))?,
+        );
+        Ok(match crypto::pad::unpad(&text) {
+            Some(inner) => SecretString::new(inner.to_string()),
+            None => text,
+        })
+    }
+
+    pub fn info(&self, sid: &str) -> Option<SessionInfo> {
+        self.sessions.get(sid).map(|s| SessionInfo {
+            id: sid.to_string(),
+            peer: s.peer.clone(),
+            channel_id: s.channel_id,
+            state: match s.stage {
+                Stage::Started(_) => SessionState::Started,
+                Stage::Offered(_) => SessionState::Offered,
+                Stage::Active(_) => SessionState::Active,
+            },
+        })
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .keys()
+            .filter_map(|sid| self.info(sid))
+            .collect()
+    }
+
+    /// Forget session `sid` and its keys
+    pub fn end(&mut self, sid: &str) -> bool {
+        self.ended.insert(sid.to_string());
+        self.sessions.remove(sid).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        let sessions = std::mem::take(&mut self.sessions);
+        self.ended.extend(sessions.into_keys());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN: &str = "AAAA0000";
+    const PEER: &str = "BBBB1111";
+
+    fn init(sid: &str) -> KeyExchange {
+        KeyExchange::Init {
+            sid: sid.to_string(),
+            to: OWN.to_string(),
+            key: Handshake::new().public(),
+        }
+    }
+
+    #[test]
+    fn ended_sessions_arent_offered_again() {
+        let mut s = Sessions::new(&[OWN.to_string()]);
+        let sid = ratchet::new_session_id();
+        assert!(s.key_exchange(init(&sid), PEER, "peer", 1).is_some());
+        // the same init seen twice, e.g. once live and once on load
+        assert!(s.key_exchange(init(&sid), PEER, "peer", 1).is_none());
+
+        assert!(s.end(&sid));
+        assert!(s.key_exchange(init(&sid), PEER, "peer", 1).is_none());
+        assert!(s.list().is_empty());
+    }
+
+    #[test]
+    fn cleared_sessions_arent_offered_again() {
+        let mut s = Sessions::new(&[OWN.to_string()]);
+        let sid = ratchet::new_session_id();
+        s.key_exchange(init(&sid), PEER, "peer", 1);
+        s.clear();
+        assert!(s.key_exchange(init(&sid), PEER, "peer", 1).is_none());
+
+        let other = ratchet::new_session_id();
+        assert!(s.key_exchange(init(&other), PEER, "peer", 1).is_some());
+    }
+
+    #[test]
+    fn init_from_ourselves_or_to_others_is_ignored() {
+        let mut s = Sessions::new(&[OWN.to_string()]);
+        assert!(
+            s.key_exchange(init(&ratchet::new_session_id()), OWN, "me", 1)
+                .is_none()
+        );
+        let to_other = KeyExchange::Init {
+            sid: ratchet::new_session_id(),
+            to: PEER.to_string(),
+            key: Handshake::new().public(),
+        };
+        assert!(s.key_exchange(to_other, PEER, "peer", 1).is_none());
+    }
+
+    #[test]
+    fn accept_changes_nothing_until_accepted() {
+        let mut s = Sessions::new(&[OWN.to_string()]);
+        let sid = ratchet::new_session_id();
+        s.key_exchange(init(&sid), PEER, "peer", 1);
+
+        let (_, hs) = s.accept(&sid).unwrap();
+        assert_eq!(s.info(&sid).unwrap().state, SessionState::Offered);
+        s.accepted(&sid, hs).unwrap();
+        assert_eq!(s.info(&sid).unwrap().state, SessionState::Active);
+        assert!(s.accept(&sid).is_err());
+
+        // ended while the accept was being signed
+        let other = ratchet::new_session_id();
+        s.key_exchange(init(&other), PEER, "peer", 1);
+        let (_, hs) = s.accept(&other).unwrap();
+        s.end(&other);
+        assert!(s.accepted(&other, hs).is_err());
+    }
+}
@@ -0,0 +1,582 @@
+use common::SecretString;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, List, ListItem, Paragraph, Wrap},
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use zeroize::Zeroize;
+
+use crate::event::Event as AppEvent;
+use crate::history::History;
+use crate::render::{Ansi, Renderer};
+use crate::{CliControl, PgpStatus, Prompter, UiEvent, UiStatus, cmdline, compose, ids};
+
+const MAX_LINES: usize = 2000;
+
+enum Mode {
+    Normal,
+    Secret {
+        prompt: String,
+        reply: tokio::sync::oneshot::Sender<Option<SecretString>>,
+    },
+    Compose {
+        reply: tokio::sync::oneshot::Sender<std::result::Result<Option<SecretString>, String>>,
+    },
+}
+
+struct App {
+    status: UiStatus,
+    // channel id -> rendered lines, tagged with the pgp id of revealed plaintext
+    panes: HashMap<u64, Vec<(Option<String>, Line<'static>)>>,
+    input: String,
+    // byte offset into `input`
+    cursor: usize,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    // lines scrolled up from the bottom
+    scroll: usize,
+    mode: Mode,
+}
+
+/// Full-screen frontend, a drop-in for `spawn_cli_thread`
+pub fn spawn_tui_thread(
+    history: Option<History>,
+    saved: Vec<String>,
+) -> (
+    mpsc::UnboundedSender<UiEvent>,
+    mpsc::UnboundedReceiver<SecretString>,
+    Prompter,
+) {
+    let (ui_tx, ui_rx) = mpsc::unbounded_channel::<UiEvent>();
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel::<SecretString>();
+    let (ctl_tx, ctl_rx) = mpsc::unbounded_channel::<CliControl>();
+
+    std::thread::spawn(move || {
+        let mut app = App {
+            status: UiStatus::default(),
+            panes: HashMap::new(),
+            input: String::new(),
+            cursor: 0,
+            history: saved,
+            history_pos: None,
+            scroll: 0,
+            mode: Mode::Normal,
+        };
+        let mut terminal = ratatui::init();
+        let res = run(
+            &mut terminal,
+            &mut app,
+            history.as_ref(),
+            ui_rx,
+            ctl_rx,
+            &cmd_tx,
+        );
+        ratatui::restore();
+        if let Err(e) = res {
+            eprintln!("tui error: {e}");
+        }
+        if let Some(h) = &history
+            && let Err(e) = h.save(app.history.iter().map(String::as_str))
+        {
+            eprintln!("{e}");
+        }
+        // only after the terminal is restored, main exits on this
+        let _ = cmd_tx.send(SecretString::new("quit".to_string()));
+    });
+
+    (ui_tx, cmd_rx, Prompter { tx: ctl_tx })
+}
+
+fn run(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    history: Option<&History>,
+    mut ui_rx: mpsc::UnboundedReceiver<UiEvent>,
+    mut ctl_rx: mpsc::UnboundedReceiver<CliControl>,
+    cmd_tx: &mpsc::UnboundedSender<SecretString>,
+) -> std::io::Result<()> {
+    loop {
+        terminal.draw(|f| draw(f, app))?;
+
+        if event::poll(Duration::from_millis(50))?
+            && let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+            && !app.on_key(key, cmd_tx)
+        {
+            return Ok(());
+        }
+
+        while let Ok(ev) = ui_rx.try_recv() {
+            match ev {
+                UiEvent::Show(ev) => {
+                    let ch = ev.channel_id().unwrap_or(app.status.channel_id);
+                    let tag = match &ev {
+                        AppEvent::Decrypt {
+                            id,
+                            hide_after: Some(_),
+                            ..
+                        } => Some(id.clone()),
+                        _ => None,
+                    };
+                    app.push(ch, tag, &Ansi.render(&ev));
+                }
+                UiEvent::Hide { id } => app.hide(&id),
+                UiEvent::Wipe => {
+                    for (_, mut pane) in app.panes.drain() {
+                        pane.iter_mut().for_each(|(_, l)| wipe_line(l));
+                    }
+                    app.history.iter_mut().for_each(|h| h.zeroize());
+                    app.history.clear();
+                    app.history_pos = None;
+                    app.scroll = 0;
+                    if let Some(h) = history
+                        && let Err(e) = h.save([])
+                    {
+                        app.push(app.status.channel_id, None, &e.to_string());
+                    }
+                }
+                UiEvent::Status(st) => app.status = *st,
+                UiEvent::Clear => {
+                    app.panes.remove(&app.status.channel_id);
+                    app.scroll = 0;
+                }
+                UiEvent::Exit => return Ok(()),
+            }
+        }
+
+        // one prompt at a time
+        if matches!(app.mode, Mode::Normal)
+            && let Ok(ctl) = ctl_rx.try_recv()
+        {
+            match ctl {
+                CliControl::Secret { prompt, reply } => {
+                    app.clear_input();
+                    app.mode = Mode::Secret { prompt, reply };
+                }
+                CliControl::Compose { reply } => match compose::editor() {
+                    Some(editor) => {
+                        // hand the terminal to the editor
+                        ratatui::restore();
+                        let res = compose::edit(&editor).map_err(|e| e.to_string());
+                        *terminal = ratatui::init();
+                        let _ = reply.send(res);
+                    }
+                    None => {
+                        app.clear_input();
+                        app.mode = Mode::Compose { reply };
+                    }
+                },
+                // input history goes with UiEvent::Wipe
+                CliControl::Wipe | CliControl::Done => {}
+            }
+        }
+    }
+}
+
+impl App {
+    fn push(&mut self, channel_id: u64, tag: Option<String>, s: &str) {
+        let pane = self.panes.entry(channel_id).or_default();
+        for l in s.split('\n') {
+            pane.push((tag.clone(), ansi_line(l)));
+        }
+        if pane.len() > MAX_LINES {
+            pane.drain(..pane.len() - MAX_LINES);
+        }
+    }
+
+    /// Replace the revealed plaintext of `id` with a placeholder
+    fn hide(&mut self, id: &str) {
+        for pane in self.panes.values_mut() {
+            let Some(at) = pane.iter().position(|(t, _)| t.as_deref() == Some(id)) else {
+                continue;
+            };
+            pane.retain_mut(|(t, l)| {
+                let keep = t.as_deref() != Some(id);
+                if !keep {
+                    wipe_line(l);
+                }
+                keep
+            });
+            let short = ids::short(id);
+            pane.insert(
+                at,
+                (
+                    None,
+                    Line::from(format!(
+                        "[PGP] id={short} plaintext hidden (pgp show {short})"
+                    ))
+                    .style(Style::default().add_modifier(Modifier::DIM)),
+                ),
+            );
+        }
+    }
+
+    fn clear_input(&mut self) {
+        // may hold a passphrase or a draft
+        self.input.zeroize();
+        self.cursor = 0;
+    }
+
+    /// Returns false to quit
+    fn on_key(&mut self, key: KeyEvent, cmd_tx: &mpsc::UnboundedSender<SecretString>) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Char('c') if ctrl => return false,
+            KeyCode::Esc => {
+                match std::mem::replace(&mut self.mode, Mode::Normal) {
+                    Mode::Secret { reply, .. } => {
+                        let _ = reply.send(None);
+                    }
+                    Mode::Compose { reply } => {
+                        let _ = reply.send(Ok(None));
+                    }
+                    Mode::Normal => {}
+                }
+                self.clear_input();
+            }
+            KeyCode::Char('d') if ctrl && matches!(self.mode, Mode::Compose { .. }) => {
+                if let Mode::Compose { reply } = std::mem::replace(&mut self.mode, Mode::Normal) {
+                    let msg = SecretString::new(std::mem::take(&mut self.input));
+                    let _ = reply.send(Ok((!msg.trim().is_empty()).then_some(msg)));
+                }
+                self.cursor = 0;
+            }
+            KeyCode::Enter => match std::mem::replace(&mut self.mode, Mode::Normal) {
+                Mode::Normal => {
+                    let line = SecretString::new(std::mem::take(&mut self.input));
+                    self.cursor = 0;
+                    self.scroll = 0;
+                    let line = SecretString::new(line.trim_start().to_string());
+                    if line.trim().is_empty() {
+                        return true;
+                    }
+                    if matches!(line.trim_end(), "quit" | "exit" | "q") {
+                        return false;
+                    }
+                    self.history.push(cmdline::history_entry(&line));
+                    self.history_pos = None;
+                    if cmd_tx.send(line).is_err() {
+                        return false;
+                    }
+                }
+                Mode::Secret { reply, .. } => {
+                    let _ = reply.send(Some(SecretString::new(std::mem::take(&mut self.input))));
+                    self.cursor = 0;
+                }
+                m @ Mode::Compose { .. } => {
+                    self.mode = m;
+                    self.insert('\n');
+                }
+            },
+            KeyCode::Char(c) => self.insert(c),
+            KeyCode::Backspace => {
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                    self.input.remove(self.cursor);
+                }
+            }
+            KeyCode::Left => {
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+            }
+            KeyCode::Right => {
+                if let Some(c) = self.input[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            }
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up if matches!(self.mode, Mode::Normal) => self.recall(-1),
+            KeyCode::Down if matches!(self.mode, Mode::Normal) => self.recall(1),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ => {}
+        }
+        true
+    }
+
+    fn insert(&mut self, c: char) {
+        self.input.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    fn recall(&mut self, dir: isize) {
+        if self.history.is_empty() {
+            return;
+        }
+        let last = self.history.len() as isize - 1;
+        let pos = match self.history_pos {
+            None if dir < 0 => last,
+            None => return,
+            Some(p) => p as isize + dir,
+        };
+        if pos > last {
+            self.history_pos = None;
+            self.clear_input();
+            return;
+        }
+        let pos = pos.max(0) as usize;
+        self.history_pos = Some(pos);
+        self.input = self.history[pos].clone();
+        self.cursor = self.input.len();
+    }
+}
+
+fn draw(f: &mut Frame, app: &App) {
+    let input_height = match app.mode {
+        Mode::Compose { .. } => (app.input.lines().count() as u16 + 3).clamp(4, 12),
+        _ => 3,
+    };
+
+    let [body, input_area] =
+        Layout::vertical([Constraint::Min(5), Constraint::Length(input_height)]).areas(f.area());
+    let [sidebar, messages, blocks] = Layout::horizontal([
+        Constraint::Length(28),
+        Constraint::Min(30),
+        Constraint::Length(36),
+    ])
+    .areas(body);
+
+    draw_sidebar(f, app, sidebar);
+    draw_messages(f, app, messages);
+    draw_blocks(f, app, blocks);
+    draw_input(f, app, input_area);
+}
+
+fn draw_sidebar(f: &mut Frame, app: &App, area: Rect) {
+    let st = &app.status;
+    let dim = Style::default().add_modifier(Modifier::DIM);
+    let mut lines: Vec<Line> = Vec::new();
+
+    lines.push(Line::styled("Channels", Style::default().bold()));
+    for ch in &st.channels {
+        let style = if *ch == st.channel_id {
+            Style::default().fg(Color::Cyan).bold()
+        } else {
+            Style::default()
+        };
+        let marker = if st.passphrase_channels.contains(ch) {
+            " *"
+        } else {
+            ""
+        };
+        lines.push(Line::styled(format!(" {ch}{marker}"), style));
+    }
+
+    lines.push(Line::default());
+    lines.push(Line::styled("Contacts", Style::default().bold()));
+    if st.contacts.is_empty() {
+        lines.push(Line::styled(" (none)", dim));
+    }
+    for (alias, fpr) in &st.contacts {
+        lines.push(Line::from(vec![
+            Span::styled(format!(" {alias} "), Style::default().fg(Color::Cyan)),
+            Span::styled(short_fpr(fpr), dim),
+        ]));
+    }
+
+    lines.push(Line::default());
+    lines.push(Line::styled("Keys", Style::default().bold()));
+    lines.push(Line::from(vec![
+        Span::styled(" to ", dim),
+        Span::raw(
+            st.recipient
+                .as_deref()
+                .map(short_fpr)
+                .unwrap_or_else(|| "(not set)".to_string()),
+        ),
+    ]));
+    if st.secret_keys.is_empty() {
+        lines.push(Line::styled(
+            " no secret keys",
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    for fpr in &st.secret_keys {
+        lines.push(Line::from(vec![
+            Span::styled(" me ", dim),
+            Span::styled(short_fpr(fpr), Style::default().fg(Color::Green)),
+        ]));
+    }
+
+    f.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" pgp-disc ")),
+        area,
+    );
+}
+
+fn draw_messages(f: &mut Frame, app: &App, area: Rect) {
+    let empty = Vec::new();
+    let pane = app.panes.get(&app.status.channel_id).unwrap_or(&empty);
+
+    // pick lines from the bottom that fit once wrapped
+    let width = area.width.saturating_sub(2).max(1) as usize;
+    let height = area.height.saturating_sub(2) as usize;
+    let end = pane.len().saturating_sub(app.scroll);
+    let mut used = 0;
+    let mut start = end;
+    while start > 0 {
+        let h = pane[start - 1].1.width().div_ceil(width).max(1);
+        if used + h > height {
+            break;
+        }
+        used += h;
+        start -= 1;
+    }
+
+    let title = if app.scroll > 0 {
+        format!(" #{} (scrolled) ", app.status.channel_id)
+    } else {
+        format!(" #{} ", app.status.channel_id)
+    };
+
+    f.render_widget(
+        Paragraph::new(Text::from(
+            pane[start..end]
+                .iter()
+                .map(|(_, l)| l.clone())
+                .collect::<Vec<_>>(),
+        ))
+        .wrap(Wrap { trim: false })
+        .block(Block::bordered().title(title)),
+        area,
+    );
+}
+
+fn draw_blocks(f: &mut Frame, app: &App, area: Rect) {
+    let items: Vec<ListItem> = app
+        .status
+        .captured
+        .iter()
+        .rev()
+        .map(|c| {
+            let color = match c.status {
+                PgpStatus::Decrypted => Color::Green,
+                PgpStatus::NotForMe
+                | PgpStatus::NeedsPassphrase
+                | PgpStatus::KeyLocked
+                | PgpStatus::RateLimited => Color::Yellow,
+                PgpStatus::Invalid | PgpStatus::Error => Color::Red,
+                PgpStatus::Pending | PgpStatus::Skipped => Color::Gray,
+            };
+            ListItem::new(Line::from(vec![
+                Span::styled(
+                    format!("{} ", ids::short(&c.id)),
+                    Style::default().fg(Color::Magenta),
+                ),
+                Span::styled(format!("{} ", c.author), Style::default().fg(Color::Cyan)),
+                Span::styled(c.status.as_str(), Style::default().fg(color)),
+            ]))
+        })
+        .collect();
+
+    f.render_widget(
+        List::new(items).block(Block::bordered().title(" PGP blocks ")),
+        area,
+    );
+}
+
+fn draw_input(f: &mut Frame, app: &App, area: Rect) {
+    let (title, shown) = match &app.mode {
+        Mode::Normal => (" > ".to_string(), app.input.clone()),
+        Mode::Secret { prompt, .. } => (
+            format!(" {} (Esc cancels) ", prompt.trim_end()),
+            "*".repeat(app.input.chars().count()),
+        ),
+        Mode::Compose { .. } => (
+            " compose (Ctrl-D sends, Esc cancels) ".to_string(),
+            app.input.clone(),
+        ),
+    };
+
+    f.render_widget(
+        Paragraph::new(shown.as_str()).block(Block::bordered().title(title)),
+        area,
+    );
+
+    // cursor on the last line of the input
+    let before = &app.input[..app.cursor];
+    let row = before.matches('\n').count() as u16;
+    let col_text = before.rsplit('\n').next().unwrap_or("");
+    let col = match app.mode {
+        Mode::Secret { .. } => col_text.chars().count(),
+        _ => Line::raw(col_text).width(),
+    } as u16;
+    f.set_cursor_position((area.x + 1 + col, area.y + 1 + row));
+}
+
+fn short_fpr(fpr: &str) -> String {
+    fpr[fpr.len().saturating_sub(16)..].to_string()
+}
+
+/// Convert a line carrying ANSI SGR colors (as produced by the
+/// `render_*` functions) into styled spans, dropping other escapes
+/// Overwrite the text of a line that is about to be dropped
+fn wipe_line(line: &mut Line<'static>) {
+    for span in line.spans.iter_mut() {
+        if let Cow::Owned(s) = &mut span.content {
+            s.zeroize();
+        }
+    }
+}
+
+fn ansi_line(s: &str) -> Line<'static> {
+    let mut spans = Vec::new();
+    let mut style = Style::default();
+    let mut buf = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            buf.push(c);
+            continue;
+        }
+        if chars.peek() != Some(&'[') {
+            continue;
+        }
+        chars.next();
+
+        let mut params = String::new();
+        let mut fin = None;
+        for c in chars.by_ref() {
+            if ('\x40'..='\x7e').contains(&c) {
+                fin = Some(c);
+                break;
+            }
+            params.push(c);
+        }
+        if fin != Some('m') {
+            continue;
+        }
+
+        if !buf.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut buf), style));
+        }
+        for p in params.split(';') {
+            style = match p.parse::<u8>().unwrap_or(0) {
+                0 => Style::default(),
+                1 => style.add_modifier(Modifier::BOLD),
+                2 => style.add_modifier(Modifier::DIM),
+                22 => style.remove_modifier(Modifier::BOLD | Modifier::DIM),
+                39 => style.fg(Color::Reset),
+                n @ 30..=37 => style.fg(Color::Indexed(n - 30)),
+                n @ 90..=97 => style.fg(Color::Indexed(n - 90 + 8)),
+                _ => style,
+            };
+        }
+    }
+
+    if !buf.is_empty() {
+        spans.push(Span::styled(buf, style));
+    }
+    Line::from(spans)
+}
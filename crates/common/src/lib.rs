@@ -1,21 +1,162 @@
 use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+/// A `String` overwritten with zeros when dropped: plaintext, passphrases,
+/// message text on its way to gpg and the bot token
+pub type SecretString = zeroize::Zeroizing<String>;
+
+/// What desktop notifications may show
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NotifyMode {
+    #[default]
+    Off,
+    /// Only who sent something, never message content
+    On,
+    /// Include message text and decrypted plaintext
+    Preview,
+}
+
+/// Whether and how command history is kept between sessions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HistoryMode {
+    Off,
+    #[default]
+    Plain,
+    /// Encrypted to your own key
+    Encrypted,
+}
+
+/// Which incoming PGP blocks are decrypted without asking
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecryptPolicy {
+    #[default]
+    Always,
+    /// Only from Discord users linked to a contact
+    Contacts,
+    /// Only on `pgp decrypt`
+    Never,
+}
+
+impl DecryptPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "always" => Some(DecryptPolicy::Always),
+            "contacts" => Some(DecryptPolicy::Contacts),
+            "never" => Some(DecryptPolicy::Never),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DecryptPolicy::Always => "always",
+            DecryptPolicy::Contacts => "contacts",
+            DecryptPolicy::Never => "never",
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
-    pub token: String,
+    pub token: SecretString,
     pub channel_id: u64,
+    /// Seconds before a gpg invocation is killed
+    pub gpg_timeout_secs: u64,
+    /// Max gpg processes running at once
+    pub gpg_jobs: usize,
+    pub notify: NotifyMode,
+    pub auto_decrypt: DecryptPolicy,
+    /// Automatic decrypt attempts allowed per author and minute, 0 = no limit
+    pub decrypt_rate: u32,
+    /// Seconds decrypted plaintext stays on screen, 0 = until cleared
+    pub ephemeral_secs: u64,
+    pub history: HistoryMode,
+    /// Overrides the file in the XDG state dir
+    pub history_file: Option<PathBuf>,
+    /// Key the history is encrypted to, the first secret key if unset
+    pub history_key: Option<String>,
+    /// Pad every `pgp send` to hide the message length
+    pub pad: bool,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
-        let token = std::env::var("DISCORD_TOKEN")
-            .map_err(|_| anyhow!("Missing DISCORD_TOKEN env var"))?;
+        let token =
+            std::env::var("DISCORD_TOKEN").map_err(|_| anyhow!("Missing DISCORD_TOKEN env var"))?;
 
         let channel_id: u64 = std::env::var("DISCORD_CHANNEL_ID")
             .map_err(|_| anyhow!("Missing DISCORD_CHANNEL_ID env var"))?
             .parse()
             .map_err(|_| anyhow!("DISCORD_CHANNEL_ID must be an integer"))?;
 
-        Ok(Self { token, channel_id })
+        let gpg_timeout_secs: u64 = match std::env::var("PGP_DISC_GPG_TIMEOUT") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| anyhow!("PGP_DISC_GPG_TIMEOUT must be an integer (seconds)"))?,
+            Err(_) => 30,
+        };
+
+        let gpg_jobs: usize = match std::env::var("PGP_DISC_GPG_JOBS") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| anyhow!("PGP_DISC_GPG_JOBS must be an integer"))?,
+            Err(_) => 4,
+        };
+
+        let notify = match std::env::var("PGP_DISC_NOTIFY").as_deref() {
+            Err(_) | Ok("off") => NotifyMode::Off,
+            Ok("on") => NotifyMode::On,
+            Ok("preview") => NotifyMode::Preview,
+            Ok(_) => return Err(anyhow!("PGP_DISC_NOTIFY must be off, on or preview")),
+        };
+
+        let auto_decrypt = match std::env::var("PGP_DISC_AUTO_DECRYPT") {
+            Ok(v) => DecryptPolicy::parse(&v).ok_or_else(|| {
+                anyhow!("PGP_DISC_AUTO_DECRYPT must be always, contacts or never")
+            })?,
+            Err(_) => DecryptPolicy::Always,
+        };
+
+        let decrypt_rate: u32 = match std::env::var("PGP_DISC_DECRYPT_RATE") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| anyhow!("PGP_DISC_DECRYPT_RATE must be an integer (per minute)"))?,
+            Err(_) => 10,
+        };
+
+        let ephemeral_secs: u64 = match std::env::var("PGP_DISC_EPHEMERAL") {
+            Ok(v) => v
+                .parse()
+                .map_err(|_| anyhow!("PGP_DISC_EPHEMERAL must be an integer (seconds)"))?,
+            Err(_) => 0,
+        };
+
+        let history = match std::env::var("PGP_DISC_HISTORY").as_deref() {
+            Err(_) | Ok("plain") => HistoryMode::Plain,
+            Ok("off") => HistoryMode::Off,
+            Ok("encrypted") => HistoryMode::Encrypted,
+            Ok(_) => return Err(anyhow!("PGP_DISC_HISTORY must be off, plain or encrypted")),
+        };
+
+        let pad = match std::env::var("PGP_DISC_PAD").as_deref() {
+            Err(_) | Ok("off") => false,
+            Ok("on") => true,
+            Ok(_) => return Err(anyhow!("PGP_DISC_PAD must be off or on")),
+        };
+
+        Ok(Self {
+            token: SecretString::new(token),
+            channel_id,
+            gpg_timeout_secs,
+            gpg_jobs,
+            notify,
+            auto_decrypt,
+            decrypt_rate,
+            ephemeral_secs,
+            history,
+            history_file: std::env::var_os("PGP_DISC_HISTORY_FILE").map(PathBuf::from),
+            history_key: std::env::var("PGP_DISC_HISTORY_KEY").ok(),
+            pad,
+        })
     }
 }
@@ -0,0 +1,401 @@
+use base64::Engine as _;
+use std::fmt;
+
+use crate::ArmorKind;
+
+const LINE_LEN: usize = 64;
+
+/// Why an armored block can't be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArmorError {
+    MissingHeader,
+    MissingFooter,
+    /// Nothing between the armor lines
+    Empty,
+    /// Character `ch` at `offset` into the base64 body
+    BadBase64 {
+        offset: usize,
+        ch: char,
+    },
+    /// Character `ch` at `offset` into a compact block
+    BadCompact {
+        offset: usize,
+        ch: char,
+    },
+    /// Body length isn't a whole number of base64 quads
+    Truncated,
+    /// CRC24 line doesn't match the data
+    BadChecksum {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl fmt::Display for ArmorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArmorError::MissingHeader => write!(f, "missing BEGIN PGP line"),
+            ArmorError::MissingFooter => write!(f, "missing END PGP line"),
+            ArmorError::Empty => write!(f, "no data in armor"),
+            ArmorError::BadBase64 { offset, ch } => {
+                write!(f, "invalid base64 character {ch:?} at offset {offset}")
+            }
+            ArmorError::BadCompact { offset, ch } => {
+                write!(
+                    f,
+                    "invalid character {ch:?} at offset {offset} of compact block"
+                )
+            }
+            ArmorError::Truncated => write!(f, "base64 data is truncated"),
+            ArmorError::BadChecksum { expected, actual } => write!(
+                f,
+                "armor checksum mismatch (expected {expected:06X}, data has {actual:06X})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArmorError {}
+
+/// Decoded armor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dearmored {
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+}
+
+/// OpenPGP CRC24 (RFC 4880, 6.1)
+pub fn crc24(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xB704CE;
+    for b in data {
+        crc ^= (*b as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= 0x1864CFB;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
+
+/// A line as typed, without what chat formatting added around it:
+/// `> ` quote prefixes, backticks from code blocks and stray whitespace
+fn unformat(line: &str) -> String {
+    let line = line.trim();
+    let line = line.trim_start_matches('>').trim_start();
+    line.replace('`', "").trim().to_string()
+}
+
+fn is_header(line: &str) -> bool {
+    line.split_once(": ").is_some_and(|(k, _)| {
+        !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Parse a base64 armored block (anything but a cleartext signed message),
+/// tolerating CRLF, quote prefixes, code fences and re-wrapped lines.
+/// The checksum is verified when present.
+pub fn parse(kind: ArmorKind, raw: &str) -> Result<Dearmored, ArmorError> {
+    let (begin, end) = kind.markers();
+    let text = raw.replace("\r\n", "\n").replace('\r', "\n");
+    let text = text.lines().map(unformat).collect::<Vec<_>>().join("\n");
+
+    let inner = &text[text.find(begin).ok_or(ArmorError::MissingHeader)? + begin.len()..];
+    let inner = &inner[..inner.find(end).ok_or(ArmorError::MissingFooter)?];
+
+    // headers only while lines are intact, a mangled header is lost to the body
+    let mut lines = inner
+        .lines()
+        .map(str::trim)
+        .skip_while(|l| l.is_empty())
+        .peekable();
+    let mut headers = Vec::new();
+    while let Some(l) = lines.next_if(|l| is_header(l)) {
+        if let Some((k, v)) = l.split_once(": ") {
+            headers.push((k.to_string(), v.to_string()));
+        }
+    }
+
+    let body: String = lines
+        .flat_map(|l| l.chars())
+        .filter(|c| !c.is_whitespace())
+        .collect();
+
+    // `=XXXX` after the data; padding `=` only ever ends the data
+    let (data, checksum) = match body.len().checked_sub(5) {
+        Some(at)
+            if body.is_char_boundary(at)
+                && body[at..].starts_with('=')
+                && !body[at + 1..].contains('=') =>
+        {
+            (&body[..at], Some(&body[at + 1..]))
+        }
+        _ => (body.as_str(), None),
+    };
+
+    if data.is_empty() {
+        return Err(ArmorError::Empty);
+    }
+    let padding = data.len() - data.trim_end_matches('=').len();
+    if let Some((offset, ch)) = data.char_indices().find(|(i, c)| {
+        !(c.is_ascii_alphanumeric()
+            || *c == '+'
+            || *c == '/'
+            || (*c == '=' && *i >= data.len() - padding))
+    }) {
+        return Err(ArmorError::BadBase64 { offset, ch });
+    }
+    if data.len() % 4 != 0 || padding > 2 {
+        return Err(ArmorError::Truncated);
+    }
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let bytes = engine.decode(data).map_err(|_| ArmorError::Truncated)?;
+
+    if let Some(crc) = checksum {
+        let offset = data.len() + 1;
+        let crc = engine.decode(crc).map_err(|_| {
+            let ch = crc
+                .chars()
+                .find(|c| !c.is_ascii_alphanumeric() && !"+/".contains(*c));
+            ArmorError::BadBase64 {
+                offset,
+                ch: ch.unwrap_or('='),
+            }
+        })?;
+        let expected = crc.iter().fold(0u32, |acc, b| acc << 8 | *b as u32);
+        let actual = crc24(&bytes);
+        if expected != actual {
+            return Err(ArmorError::BadChecksum { expected, actual });
+        }
+    }
+
+    Ok(Dearmored {
+        headers,
+        data: bytes,
+    })
+}
+
+/// Armor `d` the way gpg writes it
+pub fn armor(kind: ArmorKind, d: &Dearmored) -> String {
+    let (begin, end) = kind.markers();
+    let body = base64::engine::general_purpose::STANDARD.encode(&d.data);
+    let crc = crc24(&d.data).to_be_bytes();
+
+    let mut out = format!("{begin}\n");
+    for (k, v) in &d.headers {
+        out.push_str(&format!("{k}: {v}\n"));
+    }
+    out.push('\n');
+    // base64 is ASCII, byte chunks are whole characters
+    for chunk in body.as_bytes().chunks(LINE_LEN) {
+        out.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+        out.push('\n');
+    }
+    out.push('=');
+    out.push_str(&base64::engine::general_purpose::STANDARD.encode(&crc[1..]));
+    out.push('\n');
+    out.push_str(end);
+    out
+}
+
+/// `raw` cleaned up for gpg: re-armored, or for a cleartext signed
+/// message the text unquoted and its signature re-armored
+pub fn normalize(kind: ArmorKind, raw: &str) -> Result<String, ArmorError> {
+    if kind != ArmorKind::SignedMessage {
+        return Ok(armor(kind, &parse(kind, raw)?));
+    }
+
+    let text = raw.replace("\r\n", "\n").replace('\r', "\n");
+    // the signed text may have lines starting with '>' of its own
+    let quoted = text
+        .lines()
+        .all(|l| l.trim().is_empty() || l.trim_start().starts_with('>'));
+    let text = match quoted {
+        true => text
+            .lines()
+            .map(|l| {
+                let l = l.trim_start().trim_start_matches('>');
+                l.strip_prefix(' ').unwrap_or(l)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        false => text,
+    };
+
+    let (sig_begin, _) = ArmorKind::Signature.markers();
+    let at = text.find(sig_begin).ok_or(ArmorError::MissingFooter)?;
+    let sig = armor(
+        ArmorKind::Signature,
+        &parse(ArmorKind::Signature, &text[at..])?,
+    );
+    let head = text[..at].trim_end_matches([' ', '\t']);
+    match head.ends_with('\n') {
+        true => Ok(format!("{head}{sig}")),
+        false => Ok(format!("{head}\n{sig}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> (Vec<u8>, String) {
+        let data: Vec<u8> = (0..100u8).map(|i| i.wrapping_mul(7)).collect();
+        let armored = armor(
+            ArmorKind::Message,
+            &Dearmored {
+                headers: vec![("Comment".to_string(), "test".to_string())],
+                data: data.clone(),
+            },
+        );
+        (data, armored)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (data, armored) = sample();
+        let d = parse(ArmorKind::Message, &armored).unwrap();
+        assert_eq!(d.data, data);
+        assert_eq!(d.headers, [("Comment".to_string(), "test".to_string())]);
+        assert_eq!(normalize(ArmorKind::Message, &armored).unwrap(), armored);
+    }
+
+    #[test]
+    fn strips_code_fences() {
+        let (data, armored) = sample();
+        let fenced = format!("```\n{armored}\n```");
+        assert_eq!(parse(ArmorKind::Message, &fenced).unwrap().data, data);
+        let fenced = format!("```pgp\n{armored}```");
+        assert_eq!(parse(ArmorKind::Message, &fenced).unwrap().data, data);
+        let inline = format!("`{}`", armored.replace('\n', "`\n`"));
+        assert_eq!(parse(ArmorKind::Message, &inline).unwrap().data, data);
+    }
+
+    #[test]
+    fn strips_quote_prefixes() {
+        let (data, armored) = sample();
+        let quoted: String = armored.lines().map(|l| format!("> {l}\n")).collect();
+        assert_eq!(parse(ArmorKind::Message, &quoted).unwrap().data, data);
+        let quoted: String = armored.lines().map(|l| format!(">{l}\n")).collect();
+        assert_eq!(normalize(ArmorKind::Message, &quoted).unwrap(), armored);
+    }
+
+    #[test]
+    fn tolerates_crlf_and_rewrapping() {
+        let (data, armored) = sample();
+        let crlf = armored.replace('\n', "\r\n");
+        assert_eq!(parse(ArmorKind::Message, &crlf).unwrap().data, data);
+
+        let (head, body) = armored.split_once("\n\n").unwrap();
+        let rewrapped = format!("{head}\n\n{}", body.replacen('\n', "", 2));
+        assert_eq!(parse(ArmorKind::Message, &rewrapped).unwrap().data, data);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let (data, armored) = sample();
+        let crc = crc24(&data);
+        let line = format!(
+            "={}",
+            base64::engine::general_purpose::STANDARD.encode(&crc.to_be_bytes()[1..])
+        );
+        assert!(armored.contains(&line));
+
+        let wrong = crc ^ 1;
+        let bad = armored.replace(
+            &line,
+            &format!(
+                "={}",
+                base64::engine::general_purpose::STANDARD.encode(&wrong.to_be_bytes()[1..])
+            ),
+        );
+        assert_eq!(
+            parse(ArmorKind::Message, &bad),
+            Err(ArmorError::BadChecksum {
+                expected: wrong,
+                actual: crc
+            })
+        );
+
+        // no checksum line is fine
+        let none = armored.replace(&format!("{line}\n"), "");
+        assert_eq!(parse(ArmorKind::Message, &none).unwrap().data, data);
+    }
+
+    #[test]
+    fn bad_base64_reports_its_position() {
+        let armored = "-----BEGIN PGP MESSAGE-----\n\nAAAA\nAA!A\n-----END PGP MESSAGE-----";
+        assert_eq!(
+            parse(ArmorKind::Message, armored),
+            Err(ArmorError::BadBase64 { offset: 6, ch: '!' })
+        );
+        let armored = "-----BEGIN PGP MESSAGE-----\n\nAA=A\n-----END PGP MESSAGE-----";
+        assert_eq!(
+            parse(ArmorKind::Message, armored),
+            Err(ArmorError::BadBase64 { offset: 2, ch: '=' })
+        );
+    }
+
+    #[test]
+    fn multibyte_characters_near_the_checksum() {
+        for body in [
+            "AAAAAAAé",
+            "AAAAAAAAé",
+            "AAAAé=AAA",
+            "AAAA=éAAA",
+            "AAAA=AAAé",
+            "éAAAA",
+        ] {
+            let armored =
+                format!("-----BEGIN PGP MESSAGE-----\n\n{body}\n-----END PGP MESSAGE-----");
+            assert!(
+                matches!(
+                    parse(ArmorKind::Message, &armored),
+                    Err(ArmorError::BadBase64 { .. })
+                ),
+                "{body}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_parts() {
+        let (_, armored) = sample();
+        let (begin, end) = ArmorKind::Message.markers();
+        assert_eq!(
+            parse(ArmorKind::Message, &armored.replace(begin, "")),
+            Err(ArmorError::MissingHeader)
+        );
+        assert_eq!(
+            parse(ArmorKind::Message, &armored.replace(end, "")),
+            Err(ArmorError::MissingFooter)
+        );
+        assert_eq!(
+            parse(ArmorKind::Message, &format!("{begin}\n\n{end}")),
+            Err(ArmorError::Empty)
+        );
+        assert_eq!(
+            parse(ArmorKind::Message, &format!("{begin}\n\nAAAAA\n{end}")),
+            Err(ArmorError::Truncated)
+        );
+    }
+
+    #[test]
+    fn quoted_signed_message() {
+        let sig = armor(
+            ArmorKind::Signature,
+            &Dearmored {
+                headers: Vec::new(),
+                data: vec![1, 2, 3, 4, 5, 6],
+            },
+        );
+        let signed = format!("-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\nhello\n{sig}");
+        let quoted: String = signed.lines().map(|l| format!("> {l}\r\n")).collect();
+        assert_eq!(
+            normalize(ArmorKind::SignedMessage, &quoted).unwrap(),
+            signed
+        );
+    }
+}
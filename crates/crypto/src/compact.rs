@@ -0,0 +1,231 @@
+use std::ops::Range;
+
+use crate::ArmorKind;
+use crate::armor::{self, ArmorError, Dearmored};
+
+/// Starts a compact block, other pgp-disc clients look for it
+pub const MAGIC: &str = "pgp~";
+
+const BASE: u32 = 0x4E00;
+const BITS: u32 = 14;
+// 7 bytes fill 4 characters exactly
+const GROUP_BYTES: usize = 7;
+const GROUP_CHARS: usize = 4;
+
+fn value(c: char) -> Option<u32> {
+    (c as u32).checked_sub(BASE).filter(|v| *v < 1 << BITS)
+}
+
+/// Characters the last `rem` bytes of the data take
+fn tail_chars(rem: usize) -> usize {
+    (rem * 8).div_ceil(BITS as usize)
+}
+
+/// Binary OpenPGP data as `pgp~`, one digit for its length mod 7, then
+/// 14 bits to a character from the CJK ideographs U+4E00..U+8DFF.
+/// 2000 Discord characters hold 3500 bytes where armor holds under 1500,
+/// and chat formatting leaves those characters alone.
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(MAGIC.len() + 1 + data.len().div_ceil(7) * 4 * 3);
+    out.push_str(MAGIC);
+    out.push(char::from(b'0' + (data.len() % GROUP_BYTES) as u8));
+
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for b in data {
+        acc = acc << 8 | *b as u32;
+        bits += 8;
+        if bits >= BITS {
+            bits -= BITS;
+            out.push(char::from_u32(BASE + (acc >> bits)).unwrap_or_default());
+            acc &= (1 << bits) - 1;
+        }
+    }
+    if bits > 0 {
+        out.push(char::from_u32(BASE + (acc << (BITS - bits))).unwrap_or_default());
+    }
+    out
+}
+
+/// Data of a compact block, `s` starting at the magic
+pub fn decode(s: &str) -> Result<Vec<u8>, ArmorError> {
+    let body = s
+        .trim()
+        .strip_prefix(MAGIC)
+        .ok_or(ArmorError::MissingHeader)?;
+    let mut chars = body.char_indices();
+    let rem = match chars.next() {
+        Some((_, c @ '0'..='6')) => (c as u8 - b'0') as usize,
+        Some((_, ch)) => return Err(ArmorError::BadCompact { offset: 0, ch }),
+        None => return Err(ArmorError::Empty),
+    };
+
+    let mut out = Vec::with_capacity(body.len() / 3 * 14 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut count = 0;
+    for (offset, ch) in chars {
+        let v = value(ch).ok_or(ArmorError::BadCompact { offset, ch })?;
+        acc = acc << BITS | v;
+        bits += BITS;
+        while bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+        count += 1;
+    }
+
+    let tail = match rem {
+        0 => 0,
+        rem => tail_chars(rem),
+    };
+    if count == 0 {
+        return Err(ArmorError::Empty);
+    }
+    if count < tail || (count - tail) % GROUP_CHARS != 0 {
+        return Err(ArmorError::Truncated);
+    }
+    out.truncate((count - tail) / GROUP_CHARS * GROUP_BYTES + rem);
+    Ok(out)
+}
+
+/// gpg's armored output in the compact encoding
+pub fn from_armor(armored: &str) -> Result<String, ArmorError> {
+    Ok(encode(&armor::parse(ArmorKind::Message, armored)?.data))
+}
+
+/// A compact block as armor gpg reads
+pub fn to_armor(s: &str) -> Result<String, ArmorError> {
+    let data = decode(s)?;
+    Ok(armor::armor(
+        ArmorKind::Message,
+        &Dearmored {
+            headers: Vec::new(),
+            data,
+        },
+    ))
+}
+
+/// Byte range of the first compact block in `input`
+pub fn find(input: &str) -> Option<Range<usize>> {
+    let mut from = 0;
+    while let Some(at) = input[from..].find(MAGIC) {
+        let start = from + at;
+        let body = &input[start + MAGIC.len()..];
+        if body.starts_with(|c: char| c.is_ascii_digit()) {
+            let data = &body[1..];
+            let len = data.find(|c| value(c).is_none()).unwrap_or(data.len());
+            if len > 0 {
+                return Some(start..start + MAGIC.len() + 1 + len);
+            }
+        }
+        from = start + MAGIC.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + 11) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip_every_length_mod_7() {
+        for len in 1..=3 * GROUP_BYTES {
+            let d = data(len);
+            let s = encode(&d);
+            assert!(s.starts_with(MAGIC));
+            assert_eq!(
+                s.chars().count(),
+                MAGIC.len() + 1 + len / GROUP_BYTES * GROUP_CHARS + tail_chars(len % GROUP_BYTES),
+                "length {len}"
+            );
+            assert_eq!(decode(&s).unwrap(), d, "length {len}");
+        }
+        let d: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&d)).unwrap(), d);
+    }
+
+    #[test]
+    fn armor_round_trip() {
+        let armored = armor::armor(
+            ArmorKind::Message,
+            &Dearmored {
+                headers: Vec::new(),
+                data: data(100),
+            },
+        );
+        let compact = from_armor(&armored).unwrap();
+        assert_eq!(to_armor(&compact).unwrap(), armored);
+    }
+
+    #[test]
+    fn bad_characters() {
+        assert_eq!(
+            decode("pgp~3一a"),
+            Err(ArmorError::BadCompact { offset: 4, ch: 'a' })
+        );
+        assert_eq!(
+            decode("pgp~7一一"),
+            Err(ArmorError::BadCompact { offset: 0, ch: '7' })
+        );
+        assert_eq!(decode("pgp~"), Err(ArmorError::Empty));
+        assert_eq!(decode("pgp~0"), Err(ArmorError::Empty));
+        assert_eq!(decode("pgp 0一"), Err(ArmorError::MissingHeader));
+    }
+
+    #[test]
+    fn truncated_input() {
+        for len in [3, 10, 14, 20] {
+            let s = encode(&data(len));
+            let cut: String = s.chars().take(s.chars().count() - 1).collect();
+            assert_eq!(decode(&cut), Err(ArmorError::Truncated), "length {len}");
+        }
+    }
+
+    #[test]
+    fn found_in_chat_text() {
+        let s = encode(&data(10));
+        let text = format!("look: {s}, right?");
+        let span = find(&text).unwrap();
+        assert_eq!(&text[span], s);
+
+        assert_eq!(find("pgp~ nothing pgp~x here"), None);
+        assert_eq!(find("pgp~s 0123456789abcdef AAAA"), None);
+    }
+
+    #[test]
+    fn detected_next_to_armor() {
+        let armored = armor::armor(
+            ArmorKind::Message,
+            &Dearmored {
+                headers: Vec::new(),
+                data: data(20),
+            },
+        );
+        let first = encode(&data(7));
+        let last = encode(&data(9));
+        let text = format!("{first}\nthen\n{armored}\nand {last}");
+
+        let blocks: Vec<_> = crate::armored_blocks(&text).collect();
+        assert_eq!(blocks.len(), 3);
+        assert!(
+            blocks
+                .iter()
+                .all(|b| b.kind == ArmorKind::Message && b.error.is_none())
+        );
+        assert_eq!(&text[blocks[0].span.clone()], first);
+        assert_eq!(blocks[1].block, armored);
+        assert_eq!(&text[blocks[2].span.clone()], last);
+        assert_eq!(
+            armor::parse(ArmorKind::Message, &blocks[2].block)
+                .unwrap()
+                .data,
+            data(9)
+        );
+    }
+}
@@ -1,13 +1,26 @@
-use anyhow::{Result, anyhow};
+use common::SecretString;
 use std::fmt;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use zeroize::Zeroize;
 
 #[derive(Debug, Clone)]
 pub enum DecryptError {
-    NotForMe { stderr: String },
-    InvalidMessage { stderr: String },
-    GpgFailed { stderr: String },
+    NotForMe {
+        stderr: String,
+    },
+    InvalidMessage {
+        stderr: String,
+    },
+    GpgFailed {
+        stderr: String,
+    },
+    /// Passphrase-encrypted (SKESK) and no known passphrase worked
+    NeedsPassphrase,
+    /// Secret key is passphrase protected and no (valid) passphrase was given
+    KeyLocked {
+        stderr: String,
+    },
+    /// gpg didn't finish in time and was killed
+    Timeout,
     Io(String),
 }
 
@@ -17,6 +30,9 @@ impl fmt::Display for DecryptError {
             DecryptError::NotForMe { .. } => write!(f, "not for me"),
             DecryptError::InvalidMessage { .. } => write!(f, "invalid pgp message"),
             DecryptError::GpgFailed { .. } => write!(f, "gpg failed"),
+            DecryptError::NeedsPassphrase => write!(f, "needs passphrase"),
+            DecryptError::KeyLocked { .. } => write!(f, "secret key locked"),
+            DecryptError::Timeout => write!(f, "gpg timed out"),
             DecryptError::Io(s) => write!(f, "{s}"),
         }
     }
@@ -24,9 +40,19 @@ impl fmt::Display for DecryptError {
 
 impl std::error::Error for DecryptError {}
 
-fn classify_decrypt_failure(stderr: &str) -> DecryptError {
+pub(crate) fn classify_decrypt_failure(stderr: &str) -> DecryptError {
     let s = stderr.to_lowercase();
 
+    // checked first, gpg follows these with "no secret key"
+    if s.contains("no pinentry")
+        || s.contains("bad passphrase")
+        || s.contains("operation cancelled")
+    {
+        return DecryptError::KeyLocked {
+            stderr: stderr.to_string(),
+        };
+    }
+
     if s.contains("no secret key")
         || s.contains("decryption failed: no secret key")
         || s.contains("secret key not available")
@@ -53,192 +79,408 @@ fn classify_decrypt_failure(stderr: &str) -> DecryptError {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct PublicKey {
-    pub fpr: String,
-    pub uid: Option<String>,
+/// Signature on a decrypted message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signature {
+    Good {
+        key_id: String,
+        uid: String,
+    },
+    Bad {
+        key_id: String,
+        uid: String,
+    },
+    /// Signed by a key that isn't in the keyring
+    UnknownKey {
+        key_id: String,
+    },
 }
 
-/// List public keys
-pub fn list_public_keys() -> Result<Vec<PublicKey>> {
-    let out = Command::new("gpg")
-        .args(["--batch", "--with-colons", "--list-keys"])
-        .output()
-        .map_err(|e| anyhow!("Failed to run gpg: {e}"))?;
+impl Signature {
+    pub fn key_id(&self) -> &str {
+        match self {
+            Signature::Good { key_id, .. }
+            | Signature::Bad { key_id, .. }
+            | Signature::UnknownKey { key_id } => key_id,
+        }
+    }
 
-    if !out.status.success() {
-        let err = String::from_utf8_lossy(&out.stderr);
-        return Err(anyhow!("gpg list-keys failed: {err}"));
+    /// Who signed, uid when the key is known
+    pub fn signer(&self) -> &str {
+        match self {
+            Signature::Good { uid, .. } | Signature::Bad { uid, .. } => uid,
+            Signature::UnknownKey { key_id } => key_id,
+        }
     }
+}
 
-    let stdout = String::from_utf8_lossy(&out.stdout);
+/// Plaintext and, if the message was signed, its signature
+#[derive(Debug, Clone)]
+pub struct Decrypted {
+    pub plaintext: SecretString,
+    pub signature: Option<Signature>,
+    /// Primary fingerprint of our key that opened a message with hidden
+    /// recipients
+    pub hidden_to: Option<String>,
+    /// Primary fingerprint of the key behind a good signature
+    pub signer_fpr: Option<String>,
+}
 
-    // Each key has one or more `uid:` lines and one or more `fpr:` lines
-    // first fpr after a pub entry corresponds to that key's primary fingerprint
-    // Capture the first uid seen for a key and the next fpr.
-    let mut res = Vec::new();
-    let mut pending_uid: Option<String> = None;
-    let mut saw_pub = false;
-
-    for line in stdout.lines() {
-        if line.starts_with("pub:") {
-            saw_pub = true;
-            pending_uid = None;
-            continue;
-        }
-        if !saw_pub {
-            continue;
-        }
+/// Primary fingerprint from `[GNUPG:] DECRYPTION_KEY <fpr> <primary fpr> <trust>`
+pub(crate) fn parse_decryption_key(status: &str) -> Option<String> {
+    status.lines().find_map(|l| {
+        let mut f = l.strip_prefix("[GNUPG:] DECRYPTION_KEY ")?.split(' ');
+        let sub = f.next()?;
+        Some(f.next().unwrap_or(sub).to_string())
+    })
+}
 
-        if line.starts_with("uid:") && pending_uid.is_none() {
-            let parts: Vec<&str> = line.split(':').collect();
-            if parts.len() > 9 {
-                let uid = parts[9].trim();
-                if !uid.is_empty() {
-                    pending_uid = Some(uid.to_string());
+/// Primary fingerprints of `--list-secret-keys --with-colons`, in keyring
+/// order, so the first is the one gpg signs with by default
+pub(crate) fn parse_colons_primary_fprs(colons: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut primary = false;
+    for l in colons.lines() {
+        let f: Vec<&str> = l.split(':').collect();
+        match f.first() {
+            Some(&"sec") | Some(&"pub") => primary = true,
+            Some(&"fpr") if primary => {
+                if let Some(fpr) = f.get(9).filter(|f| !f.is_empty()) {
+                    out.push(fpr.to_string());
                 }
+                primary = false;
             }
+            Some(&"ssb") | Some(&"sub") => primary = false,
+            _ => {}
         }
+    }
+    out
+}
 
-        if line.starts_with("fpr:") {
-            let parts: Vec<&str> = line.split(':').collect();
-            if parts.len() > 9 {
-                let fpr = parts[9].trim();
-                if !fpr.is_empty() {
-                    res.push(PublicKey {
-                        fpr: fpr.to_string(),
-                        uid: pending_uid.take(),
-                    });
+/// Primary fingerprint, the last field of `[GNUPG:] VALIDSIG <fpr> ...`
+pub(crate) fn parse_validsig(status: &str) -> Option<String> {
+    status.lines().find_map(|l| {
+        let f = l.strip_prefix("[GNUPG:] VALIDSIG ")?;
+        f.split(' ').next_back().map(str::to_string)
+    })
+}
 
-                    // wait for next pub:
-                    saw_pub = false;
+/// Keygrips of secret subkeys that can encrypt, from
+/// `--list-secret-keys --with-keygrip --with-colons`
+pub(crate) fn parse_colons_encryption_grips(colons: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut can_encrypt = false;
+    for l in colons.lines() {
+        let f: Vec<&str> = l.split(':').collect();
+        match f.first() {
+            Some(&"sec") | Some(&"ssb") => can_encrypt = f.get(11).is_some_and(|c| c.contains('e')),
+            Some(&"grp") if can_encrypt => {
+                if let Some(g) = f.get(9).filter(|g| !g.is_empty()) {
+                    out.push(g.to_string());
                 }
             }
+            _ => {}
         }
     }
+    out
+}
 
-    Ok(res)
+/// Keygrips gpg-agent holds passphrase protected and not unlocked, from
+/// `KEYINFO --list` (`S KEYINFO <grip> <type> <serial> <idstr> <cached> <protection> ...`)
+pub(crate) fn parse_keyinfo_locked(keyinfo: &str) -> Vec<String> {
+    keyinfo
+        .lines()
+        .filter_map(|l| {
+            let f: Vec<&str> = l.strip_prefix("S KEYINFO ")?.split(' ').collect();
+            (f.get(5) == Some(&"P") && f.get(4) != Some(&"1")).then(|| f[0].to_string())
+        })
+        .collect()
 }
 
-/// Encrypt plaintext to a recipient (fingerprint or uid)
-pub fn encrypt_to_recipient(recipient: &str, plaintext: &str) -> Result<String> {
-    // TODO: --trust-model for now
-    // avoids "untrusted key" prompt
-    let mut child = Command::new("gpg")
-        .args([
-            "--batch",
-            "--yes",
-            "--armor",
-            "--encrypt",
-            "--trust-model",
-            "always",
-            "-r",
-            recipient,
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| anyhow!("Failed to spawn gpg: {e}"))?;
+/// Signature from `--status-fd` output (`[GNUPG:] GOODSIG <keyid> <uid>`)
+pub(crate) fn parse_signature_status(status: &str) -> Option<Signature> {
+    status.lines().find_map(|l| {
+        let mut f = l.strip_prefix("[GNUPG:] ")?.splitn(3, ' ');
+        let kw = f.next()?;
+        let key_id = f.next()?.to_string();
+        let uid = f.next().unwrap_or("").to_string();
+        match kw {
+            "GOODSIG" => Some(Signature::Good { key_id, uid }),
+            "BADSIG" => Some(Signature::Bad { key_id, uid }),
+            "ERRSIG" => Some(Signature::UnknownKey { key_id }),
+            _ => None,
+        }
+    })
+}
 
-    {
-        let stdin = child
-            .stdin
-            .as_mut()
-            .ok_or_else(|| anyhow!("Failed to open gpg stdin"))?;
-        stdin
-            .write_all(plaintext.as_bytes())
-            .map_err(|e| anyhow!("Failed writing to gpg stdin: {e}"))?;
+/// Key validity as reported in field 2 of `--with-colons` output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validity {
+    Unknown,
+    Invalid,
+    Disabled,
+    Revoked,
+    Expired,
+    Undefined,
+    Never,
+    Marginal,
+    Full,
+    Ultimate,
+}
+
+impl Validity {
+    fn from_colons(field: &str) -> Self {
+        match field.chars().next().unwrap_or('-') {
+            'i' => Validity::Invalid,
+            'd' => Validity::Disabled,
+            'r' => Validity::Revoked,
+            'e' => Validity::Expired,
+            'q' => Validity::Undefined,
+            'n' => Validity::Never,
+            'm' => Validity::Marginal,
+            'f' => Validity::Full,
+            'u' => Validity::Ultimate,
+            _ => Validity::Unknown,
+        }
     }
 
-    let out = child
-        .wait_with_output()
-        .map_err(|e| anyhow!("Failed to read gpg output: {e}"))?;
-
-    if out.status.success() {
-        let armored =
-            String::from_utf8(out.stdout).map_err(|e| anyhow!("gpg stdout not utf8: {e}"))?;
-        Ok(armored)
-    } else {
-        let err = String::from_utf8_lossy(&out.stderr).to_string();
-        Err(anyhow!("gpg encrypt failed: {err}"))
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Validity::Unknown => "unknown",
+            Validity::Invalid => "invalid",
+            Validity::Disabled => "disabled",
+            Validity::Revoked => "revoked",
+            Validity::Expired => "expired",
+            Validity::Undefined => "undefined",
+            Validity::Never => "never",
+            Validity::Marginal => "marginal",
+            Validity::Full => "full",
+            Validity::Ultimate => "ultimate",
+        }
     }
 }
 
-pub fn decrypt(armored: &str) -> std::result::Result<String, DecryptError> {
-    let mut child = Command::new("gpg")
-        .args(["--batch", "--decrypt"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| DecryptError::Io(format!("Failed to spawn gpg: {e}")))?;
+impl fmt::Display for Validity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-    {
-        let stdin = child
-            .stdin
-            .as_mut()
-            .ok_or_else(|| DecryptError::Io("Failed to open gpg stdin".into()))?;
-        stdin
-            .write_all(armored.as_bytes())
-            .map_err(|e| DecryptError::Io(format!("Failed writing to gpg stdin: {e}")))?;
+/// Key usage flags (field 12)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub encrypt: bool,
+    pub sign: bool,
+    pub certify: bool,
+    pub authenticate: bool,
+}
+
+impl Capabilities {
+    /// Lowercase letters describe the (sub)key itself, uppercase letters on a
+    /// primary key describe what the key as a whole is usable for.
+    fn from_colons(field: &str, whole_key: bool) -> Self {
+        let mut caps = Capabilities::default();
+        for c in field.chars() {
+            let c = match (whole_key, c.is_ascii_uppercase()) {
+                (true, true) | (false, false) => c.to_ascii_lowercase(),
+                _ => continue,
+            };
+            match c {
+                'e' => caps.encrypt = true,
+                's' => caps.sign = true,
+                'c' => caps.certify = true,
+                'a' => caps.authenticate = true,
+                _ => {}
+            }
+        }
+        caps
     }
+}
 
-    let out = child
-        .wait_with_output()
-        .map_err(|e| DecryptError::Io(format!("Failed to read gpg output: {e}")))?;
-
-    if out.status.success() {
-        let plaintext = String::from_utf8(out.stdout)
-            .map_err(|e| DecryptError::Io(format!("gpg stdout not utf8: {e}")))?;
-        Ok(plaintext)
-    } else {
-        let err = String::from_utf8_lossy(&out.stderr).to_string();
-        Err(classify_decrypt_failure(&err))
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (on, c) in [
+            (self.certify, 'C'),
+            (self.sign, 'S'),
+            (self.encrypt, 'E'),
+            (self.authenticate, 'A'),
+        ] {
+            if on {
+                write!(f, "{c}")?;
+            }
+        }
+        Ok(())
     }
 }
 
-/// Check if `gpg` can be executed
-pub fn available() -> Result<bool> {
-    let out = Command::new("gpg").arg("--version").output();
-    match out {
-        Ok(o) => Ok(o.status.success()),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
-        Err(e) => Err(anyhow!("Failed to run gpg: {e}")),
+#[derive(Debug, Clone)]
+pub struct SubKey {
+    pub fpr: String,
+    pub validity: Validity,
+    pub algo: String,
+    pub bits: u32,
+    /// Unix timestamps
+    pub created: Option<i64>,
+    pub expires: Option<i64>,
+    pub caps: Capabilities,
+}
+
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    pub fpr: String,
+    /// First uid, kept for display
+    pub uid: Option<String>,
+    pub uids: Vec<String>,
+    pub validity: Validity,
+    pub algo: String,
+    pub bits: u32,
+    /// Unix timestamps
+    pub created: Option<i64>,
+    pub expires: Option<i64>,
+    /// Usable capabilities of the whole key (primary + subkeys)
+    pub caps: Capabilities,
+    pub subkeys: Vec<SubKey>,
+}
+
+/// Why a key can't be used to encrypt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUnusable {
+    Revoked,
+    Expired,
+    Disabled,
+    Invalid,
+    NoEncryptionKey,
+}
+
+impl fmt::Display for KeyUnusable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyUnusable::Revoked => write!(f, "key is revoked"),
+            KeyUnusable::Expired => write!(f, "key is expired"),
+            KeyUnusable::Disabled => write!(f, "key is disabled"),
+            KeyUnusable::Invalid => write!(f, "key is invalid"),
+            KeyUnusable::NoEncryptionKey => write!(f, "key has no usable encryption subkey"),
+        }
     }
 }
 
-/// Get gpg version
-pub fn version_line() -> Result<String> {
-    let out = Command::new("gpg")
-        .arg("--version")
-        .output()
-        .map_err(|e| anyhow!("Failed to run gpg: {e}"))?;
+impl PublicKey {
+    /// Check whether gpg would accept this key as an encryption recipient
+    pub fn check_encrypt(&self) -> std::result::Result<(), KeyUnusable> {
+        match self.validity {
+            Validity::Revoked => return Err(KeyUnusable::Revoked),
+            Validity::Expired => return Err(KeyUnusable::Expired),
+            Validity::Disabled => return Err(KeyUnusable::Disabled),
+            Validity::Invalid => return Err(KeyUnusable::Invalid),
+            _ => {}
+        }
+        if !self.caps.encrypt {
+            return Err(KeyUnusable::NoEncryptionKey);
+        }
+        Ok(())
+    }
+}
 
-    if !out.status.success() {
-        return Err(anyhow!("gpg --version failed"));
+fn algo_name(id: &str, curve: &str) -> String {
+    if !curve.is_empty() {
+        return curve.to_string();
+    }
+    match id {
+        "1" | "2" | "3" => "rsa",
+        "16" | "20" => "elg",
+        "17" => "dsa",
+        "18" => "ecdh",
+        "19" => "ecdsa",
+        "22" => "eddsa",
+        _ => "unknown",
     }
+    .to_string()
+}
 
-    let s = String::from_utf8_lossy(&out.stdout);
-    Ok(s.lines().next().unwrap_or("").to_string())
+fn parse_ts(field: &str) -> Option<i64> {
+    // 0 or empty means "none"
+    field.parse::<i64>().ok().filter(|t| *t > 0)
 }
 
-pub fn list_secret_fingerprints() -> Result<Vec<String>> {
-    let out = Command::new("gpg")
-        .args(["--batch", "--with-colons", "--list-secret-keys"])
-        .output()
-        .map_err(|e| anyhow!("Failed to run gpg: {e}"))?;
+pub(crate) fn parse_colons_keys(colons: &str) -> Vec<PublicKey> {
+    let mut res: Vec<PublicKey> = Vec::new();
+    // `fpr:` follows the pub/sub line it belongs to
+    let mut want_fpr = false;
 
-    if !out.status.success() {
-        let err = String::from_utf8_lossy(&out.stderr);
-        return Err(anyhow!("gpg list-secret-keys failed: {err}"));
+    for line in colons.lines() {
+        let parts: Vec<&str> = line.split(':').collect();
+        let field = |i: usize| parts.get(i).copied().unwrap_or("").trim();
+
+        match field(0) {
+            "pub" => {
+                res.push(PublicKey {
+                    fpr: String::new(),
+                    uid: None,
+                    uids: Vec::new(),
+                    validity: Validity::from_colons(field(1)),
+                    algo: algo_name(field(3), field(16)),
+                    bits: field(2).parse().unwrap_or(0),
+                    created: parse_ts(field(5)),
+                    expires: parse_ts(field(6)),
+                    caps: Capabilities::from_colons(field(11), true),
+                    subkeys: Vec::new(),
+                });
+                want_fpr = true;
+            }
+            "sub" => {
+                let Some(key) = res.last_mut() else { continue };
+                key.subkeys.push(SubKey {
+                    fpr: String::new(),
+                    validity: Validity::from_colons(field(1)),
+                    algo: algo_name(field(3), field(16)),
+                    bits: field(2).parse().unwrap_or(0),
+                    created: parse_ts(field(5)),
+                    expires: parse_ts(field(6)),
+                    caps: Capabilities::from_colons(field(11), false),
+                });
+                want_fpr = true;
+            }
+            "uid" => {
+                let Some(key) = res.last_mut() else { continue };
+                let uid = field(9);
+                if uid.is_empty() {
+                    continue;
+                }
+                if key.uid.is_none() {
+                    key.uid = Some(uid.to_string());
+                }
+                key.uids.push(uid.to_string());
+            }
+            "fpr" if want_fpr => {
+                let Some(key) = res.last_mut() else { continue };
+                let fpr = field(9).to_string();
+                match key.subkeys.last_mut() {
+                    Some(sub) if sub.fpr.is_empty() => sub.fpr = fpr,
+                    _ if key.fpr.is_empty() => key.fpr = fpr,
+                    _ => {}
+                }
+                want_fpr = false;
+            }
+            _ => {}
+        }
     }
 
-    let stdout = String::from_utf8_lossy(&out.stdout);
-    Ok(parse_colons_fprs(&stdout))
+    res.retain(|k| !k.fpr.is_empty());
+    res
+}
+
+/// Decrypted gpg stdout; the bytes are wiped even when they aren't UTF-8
+pub(crate) fn plaintext(stdout: Vec<u8>) -> std::result::Result<SecretString, DecryptError> {
+    String::from_utf8(stdout)
+        .map(SecretString::new)
+        .map_err(|e| {
+            let msg = format!("gpg stdout not utf8: {e}");
+            e.into_bytes().zeroize();
+            DecryptError::Io(msg)
+        })
 }
 
-fn parse_colons_fprs(colons: &str) -> Vec<String> {
+pub(crate) fn parse_colons_fprs(colons: &str) -> Vec<String> {
     let mut res = Vec::new();
     for line in colons.lines() {
         if line.starts_with("fpr:") {
@@ -256,3 +498,127 @@ fn parse_colons_fprs(colons: &str) -> Vec<String> {
     res.dedup();
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+    const ALICE_SUB: &str = "89ABCDEF0123456789ABCDEF0123456789ABCDEF";
+
+    fn alice() -> String {
+        [
+            "tru::1:1700000000:0:3:1:5",
+            "pub:u:255:22:89ABCDEF01234567:1600000000:::u:::scESC:::::ed25519:::0:",
+            &format!("fpr:::::::::{ALICE}:"),
+            "grp:::::::::AAAA:",
+            "uid:u::::1600000000::HASH1::Alice <alice@example.org>::::::::::0:",
+            "uid:u::::1600000001::HASH2::Alice Work <alice@work.example>::::::::::0:",
+            "sub:u:255:18:0123456789ABCDEF:1600000000:1900000000:::::e:::::cv25519::",
+            &format!("fpr:::::::::{ALICE_SUB}:"),
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn parses_key_with_subkey() {
+        let keys = parse_colons_keys(&alice());
+        assert_eq!(keys.len(), 1);
+        let k = &keys[0];
+        assert_eq!(k.fpr, ALICE);
+        assert_eq!(k.uid.as_deref(), Some("Alice <alice@example.org>"));
+        assert_eq!(k.uids.len(), 2);
+        assert_eq!(k.validity, Validity::Ultimate);
+        assert_eq!((k.algo.as_str(), k.bits), ("ed25519", 255));
+        assert_eq!((k.created, k.expires), (Some(1600000000), None));
+        assert_eq!(
+            k.caps,
+            Capabilities {
+                encrypt: true,
+                sign: true,
+                certify: true,
+                authenticate: false,
+            }
+        );
+        assert_eq!(k.caps.to_string(), "CSE");
+
+        assert_eq!(k.subkeys.len(), 1);
+        let sub = &k.subkeys[0];
+        assert_eq!(sub.fpr, ALICE_SUB);
+        assert_eq!(sub.algo, "cv25519");
+        assert_eq!(sub.expires, Some(1900000000));
+        assert_eq!(sub.caps.to_string(), "E");
+        assert_eq!(k.check_encrypt(), Ok(()));
+    }
+
+    #[test]
+    fn primary_caps_are_lowercase_subkey_caps_ignore_uppercase() {
+        assert_eq!(Capabilities::from_colons("scESC", false).to_string(), "CS");
+        assert_eq!(
+            Capabilities::from_colons("scESCA", true).to_string(),
+            "CSEA"
+        );
+        assert_eq!(Capabilities::from_colons("", true).to_string(), "");
+    }
+
+    #[test]
+    fn revoked_and_expired_keys_are_refused() {
+        let revoked = alice().replace("pub:u:", "pub:r:");
+        let k = &parse_colons_keys(&revoked)[0];
+        assert_eq!(k.validity, Validity::Revoked);
+        assert_eq!(k.check_encrypt(), Err(KeyUnusable::Revoked));
+
+        let expired = alice().replace(
+            "pub:u:255:22:89ABCDEF01234567:1600000000::",
+            "pub:e:255:22:89ABCDEF01234567:1600000000:1650000000:",
+        );
+        let k = &parse_colons_keys(&expired)[0];
+        assert_eq!(k.validity, Validity::Expired);
+        assert_eq!(k.expires, Some(1650000000));
+        assert_eq!(k.check_encrypt(), Err(KeyUnusable::Expired));
+
+        let disabled = alice().replace("pub:u:", "pub:d:");
+        assert_eq!(
+            parse_colons_keys(&disabled)[0].check_encrypt(),
+            Err(KeyUnusable::Disabled)
+        );
+    }
+
+    #[test]
+    fn key_without_usable_encryption_subkey() {
+        // the encryption subkey expired, gpg drops E from the whole key
+        let colons = alice().replace("scESC", "scSC").replace("sub:u:", "sub:e:");
+        let k = &parse_colons_keys(&colons)[0];
+        assert_eq!(k.subkeys[0].validity, Validity::Expired);
+        assert!(k.subkeys[0].caps.encrypt);
+        assert_eq!(k.check_encrypt(), Err(KeyUnusable::NoEncryptionKey));
+    }
+
+    #[test]
+    fn rsa_and_several_keys() {
+        let colons = [
+            // a subkey before any primary is skipped
+            "sub:u:2048:1:AAAA:1600000000:::::e::::::",
+            "pub:f:4096:1:BBBB:1500000000:0::-:::escaESCA::::::::0:",
+            "fpr:::::::::BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB:",
+            "uid:f::::::::Bob::::::::::0:",
+            "uid:f::::::::::::::::::0:",
+            // no fpr line, dropped
+            "pub:u:255:22:CCCC:1600000000:::u:::scESC:::::ed25519:::0:",
+            &alice(),
+        ]
+        .join("\n");
+        let keys = parse_colons_keys(&colons);
+        assert_eq!(
+            keys.iter().map(|k| k.fpr.as_str()).collect::<Vec<_>>(),
+            ["BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB", ALICE]
+        );
+        let bob = &keys[0];
+        assert_eq!((bob.algo.as_str(), bob.bits), ("rsa", 4096));
+        assert_eq!(bob.expires, None);
+        assert_eq!(bob.uids, ["Bob"]);
+        assert_eq!(bob.validity, Validity::Full);
+        assert_eq!(bob.caps.to_string(), "CSEA");
+        assert!(bob.subkeys.is_empty());
+    }
+}
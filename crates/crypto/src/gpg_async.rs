@@ -0,0 +1,603 @@
+use anyhow::{Result, anyhow};
+use common::SecretString;
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use zeroize::Zeroizing;
+
+use crate::gpg::{DecryptError, Decrypted, PublicKey};
+use crate::packet::Esk;
+
+#[derive(Debug)]
+enum RunError {
+    Timeout,
+    /// The program isn't installed
+    NotFound(String),
+    Io(String),
+}
+
+impl From<RunError> for DecryptError {
+    fn from(e: RunError) -> Self {
+        match e {
+            RunError::Timeout => DecryptError::Timeout,
+            RunError::NotFound(s) | RunError::Io(s) => DecryptError::Io(s),
+        }
+    }
+}
+
+impl From<RunError> for anyhow::Error {
+    fn from(e: RunError) -> Self {
+        match e {
+            RunError::Timeout => anyhow!("gpg timed out"),
+            RunError::NotFound(s) | RunError::Io(s) => anyhow!(s),
+        }
+    }
+}
+
+/// Passphrases available to a decrypt attempt
+#[derive(Clone, Debug, Default)]
+pub struct Secrets {
+    /// Tried in order on passphrase-encrypted (SKESK) messages
+    pub passphrases: Vec<SecretString>,
+    /// Unlocks a protected secret key via loopback pinentry
+    pub key_passphrase: Option<SecretString>,
+}
+
+/// How an outgoing message is encrypted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncryptOpts {
+    /// Leave the recipient's key id out of the message (`--throw-keyids`)
+    pub hidden: bool,
+    /// Pad the plaintext to a bucket size ([`crate::pad`])
+    pub pad: bool,
+}
+
+impl EncryptOpts {
+    fn args(&self) -> &'static [&'static str] {
+        match self.pad {
+            // compression would shrink the padding away again
+            true => &["--compress-algo", "none"],
+            false => &[],
+        }
+    }
+
+    fn plaintext(&self, text: &str) -> Result<Zeroizing<Vec<u8>>> {
+        let text = match self.pad {
+            true => crate::pad::pad(text)?,
+            false => SecretString::new(text.to_string()),
+        };
+        Ok(Zeroizing::new(text.as_bytes().to_vec()))
+    }
+}
+
+/// Non-blocking gpg runner.
+///
+/// Every invocation is bounded by `timeout` and a shared concurrency limit.
+/// Dropping a pending future kills the gpg process.
+#[derive(Clone, Debug)]
+pub struct Gpg {
+    timeout: Duration,
+    jobs: Arc<Semaphore>,
+}
+
+impl Default for Gpg {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30), 4)
+    }
+}
+
+impl Gpg {
+    pub fn new(timeout: Duration, max_jobs: usize) -> Self {
+        Self {
+            timeout,
+            jobs: Arc::new(Semaphore::new(max_jobs.max(1))),
+        }
+    }
+
+    async fn run(
+        &self,
+        args: &[&str],
+        // usually plaintext or a passphrase, wiped once written
+        input: Option<Zeroizing<Vec<u8>>>,
+    ) -> std::result::Result<Output, RunError> {
+        self.run_program("gpg", args, input).await
+    }
+
+    async fn run_program(
+        &self,
+        program: &str,
+        args: &[&str],
+        input: Option<Zeroizing<Vec<u8>>>,
+    ) -> std::result::Result<Output, RunError> {
+        let _permit = self
+            .jobs
+            .acquire()
+            .await
+            .map_err(|_| RunError::Io("gpg runner closed".into()))?;
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                let msg = format!("Failed to spawn {program}: {e}");
+                match e.kind() {
+                    std::io::ErrorKind::NotFound => RunError::NotFound(msg),
+                    _ => RunError::Io(msg),
+                }
+            })?;
+
+        let stdin = child.stdin.take();
+        let write = async move {
+            if let (Some(mut stdin), Some(input)) = (stdin, input) {
+                stdin
+                    .write_all(&input)
+                    .await
+                    .map_err(|e| RunError::Io(format!("Failed writing to gpg stdin: {e}")))?;
+            }
+            Ok::<_, RunError>(())
+        };
+
+        let work = async {
+            let (w, out) = tokio::join!(write, child.wait_with_output());
+            w?;
+            out.map_err(|e| RunError::Io(format!("Failed to read gpg output: {e}")))
+        };
+
+        tokio::time::timeout(self.timeout, work)
+            .await
+            .map_err(|_| RunError::Timeout)?
+    }
+
+    async fn run_with_passphrase(
+        &self,
+        args: &[&str],
+        passphrase: &str,
+        input: &[u8],
+    ) -> std::result::Result<Output, RunError> {
+        let mut full = vec!["--pinentry-mode", "loopback", "--passphrase-fd", "0"];
+        full.extend_from_slice(args);
+
+        let mut stdin = Zeroizing::new(Vec::with_capacity(passphrase.len() + 1 + input.len()));
+        stdin.extend_from_slice(passphrase.as_bytes());
+        stdin.push(b'\n');
+        stdin.extend_from_slice(input);
+
+        self.run(&full, Some(stdin)).await
+    }
+
+    /// Check if `gpg` can be executed
+    pub async fn available(&self) -> Result<bool> {
+        match self.run(&["--version"], None).await {
+            Ok(o) => Ok(o.status.success()),
+            Err(RunError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Get gpg version
+    pub async fn version_line(&self) -> Result<String> {
+        let out = self.run(&["--version"], None).await?;
+        if !out.status.success() {
+            return Err(anyhow!("gpg --version failed"));
+        }
+        let s = String::from_utf8_lossy(&out.stdout);
+        Ok(s.lines().next().unwrap_or("").to_string())
+    }
+
+    pub async fn list_public_keys(&self) -> Result<Vec<PublicKey>> {
+        let out = self
+            .run(
+                &[
+                    "--batch",
+                    "--with-colons",
+                    "--fixed-list-mode",
+                    "--list-keys",
+                ],
+                None,
+            )
+            .await?;
+
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr);
+            return Err(anyhow!("gpg list-keys failed: {err}"));
+        }
+
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        Ok(crate::gpg::parse_colons_keys(&stdout))
+    }
+
+    /// Primary fingerprints of our secret keys, the default signing key first
+    pub async fn list_secret_primaries(&self) -> Result<Vec<String>> {
+        let out = self
+            .run(&["--batch", "--with-colons", "--list-secret-keys"], None)
+            .await?;
+
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr);
+            return Err(anyhow!("gpg list-secret-keys failed: {err}"));
+        }
+
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        Ok(crate::gpg::parse_colons_primary_fprs(&stdout))
+    }
+
+    pub async fn list_secret_fingerprints(&self) -> Result<Vec<String>> {
+        let out = self
+            .run(&["--batch", "--with-colons", "--list-secret-keys"], None)
+            .await?;
+
+        if !out.status.success() {
+            let err = String::from_utf8_lossy(&out.stderr);
+            return Err(anyhow!("gpg list-secret-keys failed: {err}"));
+        }
+
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        Ok(crate::gpg::parse_colons_fprs(&stdout))
+    }
+
+    /// Encrypt plaintext to a recipient (fingerprint or uid)
+    pub async fn encrypt_to_recipient(
+        &self,
+        recipient: &str,
+        plaintext: &str,
+        opts: EncryptOpts,
+    ) -> Result<String> {
+        let mut args = vec!["--batch", "--yes", "--armor", "--encrypt"];
+        args.extend(opts.args());
+        args.extend([
+            "--trust-model",
+            "always",
+            if opts.hidden { "-R" } else { "-r" },
+            recipient,
+        ]);
+        let plaintext = opts.plaintext(plaintext)?;
+        let out = self.run(&args, Some(plaintext)).await?;
+
+        if out.status.success() {
+            String::from_utf8(out.stdout).map_err(|e| anyhow!("gpg stdout not utf8: {e}"))
+        } else {
+            let err = String::from_utf8_lossy(&out.stderr).to_string();
+            Err(anyhow!("gpg encrypt failed: {err}"))
+        }
+    }
+
+    /// Encrypt plaintext with a passphrase only (no public keys),
+    /// `opts.hidden` has nothing to hide
+    pub async fn encrypt_symmetric(
+        &self,
+        passphrase: &str,
+        plaintext: &str,
+        opts: EncryptOpts,
+    ) -> Result<String> {
+        let mut args = vec!["--batch", "--yes", "--armor", "--symmetric"];
+        args.extend(opts.args());
+        let plaintext = opts.plaintext(plaintext)?;
+        let out = self
+            .run_with_passphrase(&args, passphrase, &plaintext)
+            .await?;
+
+        if out.status.success() {
+            String::from_utf8(out.stdout).map_err(|e| anyhow!("gpg stdout not utf8: {e}"))
+        } else {
+            let err = String::from_utf8_lossy(&out.stderr).to_string();
+            Err(anyhow!("gpg symmetric encrypt failed: {err}"))
+        }
+    }
+
+    /// Decrypt an armored message, also reporting the signature.
+    /// Passphrase-encrypted messages are tried against
+    /// `secrets.passphrases` in order.
+    /// Never spawns pinentry: a protected secret key without
+    /// `secrets.key_passphrase` fails with [`DecryptError::KeyLocked`].
+    /// Hidden recipients make gpg try every secret key; which one worked
+    /// is in [`Decrypted::hidden_to`].
+    pub async fn decrypt(
+        &self,
+        armored: &str,
+        secrets: &Secrets,
+    ) -> std::result::Result<Decrypted, DecryptError> {
+        let esks = crate::packet::armored_session_key_packets(armored);
+        let symmetric = esks.contains(&Esk::Symmetric);
+        let public = esks.iter().any(|e| matches!(e, Esk::PublicKey { .. }));
+        let hidden = esks.iter().any(Esk::is_hidden);
+
+        const DECRYPT: &[&str] = &["--batch", "--status-fd", "2", "--decrypt"];
+
+        if symmetric {
+            for pass in &secrets.passphrases {
+                let out = self
+                    .run_with_passphrase(DECRYPT, pass, armored.as_bytes())
+                    .await?;
+                if out.status.success() {
+                    return decrypted(out);
+                }
+            }
+            if !public {
+                return Err(DecryptError::NeedsPassphrase);
+            }
+        }
+
+        let out = match &secrets.key_passphrase {
+            Some(pass) => {
+                self.run_with_passphrase(DECRYPT, pass, armored.as_bytes())
+                    .await?
+            }
+            None => {
+                self.run(
+                    &[
+                        "--pinentry-mode",
+                        "error",
+                        "--batch",
+                        "--status-fd",
+                        "2",
+                        "--decrypt",
+                    ],
+                    Some(Zeroizing::new(armored.as_bytes().to_vec())),
+                )
+                .await?
+            }
+        };
+
+        if out.status.success() {
+            return decrypted(out);
+        }
+        let err = String::from_utf8_lossy(&out.stderr).to_string();
+        match crate::gpg::classify_decrypt_failure(&err) {
+            // gpg skips locked keys quietly when it has to guess, so
+            // "no secret key" may only mean one of them wasn't tried
+            DecryptError::NotForMe { stderr }
+                if hidden && secrets.key_passphrase.is_none() && self.has_locked_key().await =>
+            {
+                Err(DecryptError::KeyLocked { stderr })
+            }
+            e => Err(e),
+        }
+    }
+
+    /// Whether a secret key that can decrypt is passphrase protected and
+    /// not unlocked in gpg-agent
+    async fn has_locked_key(&self) -> bool {
+        let Ok(keys) = self
+            .run(
+                &[
+                    "--batch",
+                    "--with-colons",
+                    "--with-keygrip",
+                    "--list-secret-keys",
+                ],
+                None,
+            )
+            .await
+        else {
+            return false;
+        };
+        let Ok(agent) = self
+            .run_program("gpg-connect-agent", &["KEYINFO --list", "/bye"], None)
+            .await
+        else {
+            return false;
+        };
+
+        let grips =
+            crate::gpg::parse_colons_encryption_grips(&String::from_utf8_lossy(&keys.stdout));
+        crate::gpg::parse_keyinfo_locked(&String::from_utf8_lossy(&agent.stdout))
+            .iter()
+            .any(|g| grips.contains(g))
+    }
+
+    /// Verify a cleartext signed message, returning the signed text.
+    /// A signature by a key we don't have is still `Ok`, with
+    /// [`crate::gpg::Signature::UnknownKey`].
+    pub async fn verify_cleartext(
+        &self,
+        armored: &str,
+    ) -> std::result::Result<Decrypted, DecryptError> {
+        // --decrypt prints the text with dash escaping undone, --verify doesn't
+        let out = self
+            .run(
+                &["--batch", "--status-fd", "2", "--decrypt"],
+                Some(Zeroizing::new(armored.as_bytes().to_vec())),
+            )
+            .await?;
+
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        match crate::gpg::parse_signature_status(&stderr) {
+            // gpg exits non-zero on a missing key, the text is there anyway
+            Some(signature) => Ok(Decrypted {
+                plaintext: crate::gpg::plaintext(out.stdout)?,
+                signature: Some(signature),
+                hidden_to: None,
+                signer_fpr: crate::gpg::parse_validsig(&stderr),
+            }),
+            None => Err(crate::gpg::classify_decrypt_failure(&stderr)),
+        }
+    }
+
+    /// Sign with the default secret key and encrypt to all of
+    /// `recipients` in one message. Fails like [`Gpg::clearsign`].
+    pub async fn sign_encrypt_to_recipients(
+        &self,
+        recipients: &[String],
+        plaintext: &str,
+        key_passphrase: Option<&str>,
+    ) -> std::result::Result<String, DecryptError> {
+        let mut args = vec![
+            "--batch",
+            "--yes",
+            "--armor",
+            "--sign",
+            "--encrypt",
+            "--trust-model",
+            "always",
+        ];
+        for r in recipients {
+            args.extend(["-r", r.as_str()]);
+        }
+        let out = match key_passphrase {
+            Some(pass) => {
+                self.run_with_passphrase(&args, pass, plaintext.as_bytes())
+                    .await?
+            }
+            None => {
+                args.splice(0..0, ["--pinentry-mode", "error"]);
+                self.run(&args, Some(Zeroizing::new(plaintext.as_bytes().to_vec())))
+                    .await?
+            }
+        };
+
+        if out.status.success() {
+            String::from_utf8(out.stdout)
+                .map_err(|e| DecryptError::Io(format!("gpg stdout not utf8: {e}")))
+        } else {
+            let err = String::from_utf8_lossy(&out.stderr).to_string();
+            Err(crate::gpg::classify_decrypt_failure(&err))
+        }
+    }
+
+    /// Clearsign `text` with the default secret key. Fails like
+    /// [`Gpg::decrypt`]: [`DecryptError::KeyLocked`] when the key needs
+    /// `key_passphrase`.
+    pub async fn clearsign(
+        &self,
+        text: &str,
+        key_passphrase: Option<&str>,
+    ) -> std::result::Result<String, DecryptError> {
+        const SIGN: &[&str] = &["--batch", "--yes", "--armor", "--clearsign"];
+        let out = match key_passphrase {
+            Some(pass) => {
+                self.run_with_passphrase(SIGN, pass, text.as_bytes())
+                    .await?
+            }
+            None => {
+                self.run(
+                    &[
+                        "--pinentry-mode",
+                        "error",
+                        "--batch",
+                        "--yes",
+                        "--armor",
+                        "--clearsign",
+                    ],
+                    Some(Zeroizing::new(text.as_bytes().to_vec())),
+                )
+                .await?
+            }
+        };
+
+        if out.status.success() {
+            String::from_utf8(out.stdout)
+                .map_err(|e| DecryptError::Io(format!("gpg stdout not utf8: {e}")))
+        } else {
+            let err = String::from_utf8_lossy(&out.stderr).to_string();
+            Err(crate::gpg::classify_decrypt_failure(&err))
+        }
+    }
+}
+
+fn decrypted(out: Output) -> std::result::Result<Decrypted, DecryptError> {
+    let status = String::from_utf8_lossy(&out.stderr);
+    let signature = crate::gpg::parse_signature_status(&status);
+    // gpg names the key on every decryption, only news when the message didn't
+    let hidden_to = status
+        .contains("[GNUPG:] ENC_TO 0000000000000000")
+        .then(|| crate::gpg::parse_decryption_key(&status))
+        .flatten();
+    let mut plaintext = crate::gpg::plaintext(out.stdout)?;
+    if let Some(text) = crate::pad::unpad(&plaintext) {
+        plaintext = SecretString::new(text.to_string());
+    }
+    Ok(Decrypted {
+        plaintext,
+        signature,
+        hidden_to,
+        signer_fpr: crate::gpg::parse_validsig(&status),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // `sleep` and `sh` stand in for gpg, the runner doesn't care what it runs
+
+    #[tokio::test]
+    async fn slow_programs_time_out() {
+        let gpg = Gpg::new(Duration::from_millis(100), 1);
+        let started = Instant::now();
+        let res = gpg.run_program("sleep", &["5"], None).await;
+        assert!(matches!(res, Err(RunError::Timeout)), "{res:?}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn missing_program_is_not_found() {
+        let gpg = Gpg::default();
+        let res = gpg.run_program("pgp-disc-no-such-program", &[], None).await;
+        assert!(matches!(res, Err(RunError::NotFound(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn cancelling_kills_the_process() {
+        let pid_file = std::env::temp_dir().join(format!("pgp-disc-kill-{}", std::process::id()));
+        let _ = std::fs::remove_file(&pid_file);
+        let path = pid_file.to_str().unwrap();
+
+        let gpg = Gpg::new(Duration::from_secs(30), 1);
+        let args = ["-c", "echo $$ > \"$0\"; exec sleep 30", path];
+        tokio::select! {
+            res = gpg.run_program("sh", &args, None) => {
+                panic!("finished before it was cancelled: {res:?}");
+            }
+            _ = tokio::time::sleep(Duration::from_millis(300)) => {}
+        }
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let _ = std::fs::remove_file(&pid_file);
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let dead = || {
+            // gone, or killed and waiting to be reaped
+            std::fs::read_to_string(&stat)
+                .map(|s| s.rsplit(')').next().unwrap().trim_start().starts_with('Z'))
+                .unwrap_or(true)
+        };
+        let started = Instant::now();
+        while !dead() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "{stat} still running"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_wait_for_a_free_slot() {
+        let gpg = Gpg::new(Duration::from_secs(10), 2);
+        let run = || gpg.run_program("sleep", &["0.3"], None);
+
+        let started = Instant::now();
+        let (a, b, c) = tokio::join!(run(), run(), run());
+        for res in [a, b, c] {
+            assert!(res.unwrap().status.success());
+        }
+        // two at once, the third after one of them
+        assert!(
+            started.elapsed() >= Duration::from_millis(600),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(gpg.jobs.available_permits(), 2);
+    }
+}
@@ -0,0 +1,193 @@
+use base64::Engine as _;
+use common::SecretString;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// Starts the plaintext of a group key distribution
+pub const MAGIC: &str = "pgp-disc group";
+
+/// One epoch of a group's shared key. It goes out PGP-encrypted to every
+/// member once, messages are then encrypted under it instead of to each
+/// member, so they don't grow with the group.
+#[derive(Clone)]
+pub struct GroupKey {
+    /// Random id naming the group on the wire
+    pub gid: String,
+    /// Counts rotations, a distribution only replaces an older epoch
+    pub epoch: u32,
+    key: Zeroizing<[u8; 32]>,
+    /// Primary fingerprints, the distributor's included
+    pub members: Vec<String>,
+}
+
+impl GroupKey {
+    pub fn new(members: Vec<String>) -> Self {
+        let mut gid = [0u8; 8];
+        OsRng.fill_bytes(&mut gid);
+        Self::with_fresh_key(hex::encode(gid), 1, members)
+    }
+
+    /// The next epoch with a fresh key, for `members` only
+    pub fn rotate(&self, members: Vec<String>) -> Self {
+        Self::with_fresh_key(self.gid.clone(), self.epoch + 1, members)
+    }
+
+    fn with_fresh_key(gid: String, epoch: u32, members: Vec<String>) -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
+        Self {
+            gid,
+            epoch,
+            key,
+            members: normalize(members),
+        }
+    }
+
+    pub fn is_member(&self, fpr: &str) -> bool {
+        self.members.iter().any(|m| m.eq_ignore_ascii_case(fpr))
+    }
+
+    /// Names the key without giving it away. Two members rotating at once
+    /// both go with the lower id.
+    pub fn key_id(&self) -> String {
+        hex::encode(&Sha256::digest(self.key.as_ref())[..8])
+    }
+
+    /// What messages are encrypted under, as a gpg symmetric passphrase
+    pub fn passphrase(&self) -> SecretString {
+        SecretString::new(base64::engine::general_purpose::STANDARD.encode(self.key.as_ref()))
+    }
+
+    /// Plaintext of the distribution message:
+    /// `pgp-disc group <gid> <epoch> <base64 key> <fpr>...`
+    pub fn distribution(&self) -> SecretString {
+        let mut s = SecretString::new(format!("{MAGIC} {} {} ", self.gid, self.epoch));
+        s.push_str(&self.passphrase());
+        for m in &self.members {
+            s.push(' ');
+            s.push_str(m);
+        }
+        s
+    }
+
+    /// [`GroupKey::distribution`] with the key left out, for the screen and logs
+    pub fn redacted(&self) -> String {
+        format!(
+            "{MAGIC} {} {} <key> {}",
+            self.gid,
+            self.epoch,
+            self.members.join(" ")
+        )
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut f = text.trim().strip_prefix(MAGIC)?.split_whitespace();
+        let gid = f.next()?.to_string();
+        let epoch = f.next()?.parse().ok()?;
+        let key = Zeroizing::new(
+            base64::engine::general_purpose::STANDARD
+                .decode(f.next()?)
+                .ok()?
+                .try_into()
+                .ok()?,
+        );
+        let members = normalize(f.map(str::to_string).collect());
+        if members.is_empty() {
+            return None;
+        }
+        Some(Self {
+            gid,
+            epoch,
+            key,
+            members,
+        })
+    }
+}
+
+impl fmt::Debug for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GroupKey")
+            .field("gid", &self.gid)
+            .field("epoch", &self.epoch)
+            .field("members", &self.members)
+            .finish_non_exhaustive()
+    }
+}
+
+fn normalize(mut members: Vec<String>) -> Vec<String> {
+    for m in &mut members {
+        m.make_ascii_uppercase();
+    }
+    members.sort();
+    members.dedup();
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members() -> Vec<String> {
+        vec![
+            "bbbb2222".to_string(),
+            "AAAA1111".to_string(),
+            "BBBB2222".to_string(),
+        ]
+    }
+
+    #[test]
+    fn distribution_round_trip() {
+        let key = GroupKey::new(members());
+        assert_eq!(key.epoch, 1);
+        assert_eq!(key.members, ["AAAA1111", "BBBB2222"]);
+
+        let parsed = GroupKey::parse(&key.distribution()).unwrap();
+        assert_eq!(parsed.gid, key.gid);
+        assert_eq!(parsed.epoch, key.epoch);
+        assert_eq!(parsed.members, key.members);
+        assert_eq!(*parsed.passphrase(), *key.passphrase());
+        assert_eq!(parsed.key_id(), key.key_id());
+    }
+
+    #[test]
+    fn rotation_keeps_the_group() {
+        let key = GroupKey::new(members());
+        let next = key.rotate(vec!["AAAA1111".to_string()]);
+        assert_eq!(next.gid, key.gid);
+        assert_eq!(next.epoch, 2);
+        assert_ne!(*next.passphrase(), *key.passphrase());
+        assert_ne!(next.key_id(), key.key_id());
+        assert!(next.is_member("aaaa1111"));
+        assert!(!next.is_member("BBBB2222"));
+    }
+
+    #[test]
+    fn redacted_leaves_the_key_out() {
+        let key = GroupKey::new(members());
+        let redacted = key.redacted();
+        assert!(!redacted.contains(key.passphrase().as_str()));
+        assert!(redacted.contains(&key.gid));
+        assert!(GroupKey::parse(&redacted).is_none());
+    }
+
+    #[test]
+    fn parse_failures() {
+        let key = base64::engine::general_purpose::STANDARD.encode([1u8; 32]);
+        let short = base64::engine::general_purpose::STANDARD.encode([1u8; 31]);
+        for text in [
+            String::new(),
+            format!("pgp-disc groups 0011 1 {key} AAAA"),
+            format!("{MAGIC} 0011 x {key} AAAA"),
+            format!("{MAGIC} 0011 -1 {key} AAAA"),
+            format!("{MAGIC} 0011 1 {short} AAAA"),
+            format!("{MAGIC} 0011 1 not*base64 AAAA"),
+            format!("{MAGIC} 0011 1 {key}"),
+            format!("{MAGIC} 0011"),
+        ] {
+            assert!(GroupKey::parse(&text).is_none(), "{text}");
+        }
+        assert!(GroupKey::parse(&format!("{MAGIC} 0011 1 {key} AAAA")).is_some());
+    }
+}
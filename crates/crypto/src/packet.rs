@@ -0,0 +1,230 @@
+use anyhow::{Result, anyhow};
+
+/// Session key packet preceding the encrypted data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Esk {
+    /// PKESK (tag 1), key id of the recipient, all zero when hidden
+    PublicKey { key_id: [u8; 8] },
+    /// SKESK (tag 3), passphrase encrypted
+    Symmetric,
+}
+
+impl Esk {
+    /// Encrypted to a key it doesn't name (`--throw-keyids`)
+    pub fn is_hidden(&self) -> bool {
+        matches!(self, Esk::PublicKey { key_id } if *key_id == [0; 8])
+    }
+}
+
+/// Decode the base64 body of an armored message
+pub fn dearmor(armored: &str) -> Result<Vec<u8>> {
+    crate::armor::parse(crate::ArmorKind::Message, armored)
+        .map(|d| d.data)
+        .map_err(|e| anyhow!("invalid armor: {e}"))
+}
+
+/// Read one packet header at `buf[pos..]`, returns (tag, body start, body len).
+/// None unless the whole body is within `buf`.
+fn packet_header(buf: &[u8], pos: usize) -> Option<(u8, usize, usize)> {
+    let (tag, start, len) = packet_header_unchecked(buf, pos)?;
+    (start.checked_add(len)? <= buf.len()).then_some((tag, start, len))
+}
+
+fn packet_header_unchecked(buf: &[u8], pos: usize) -> Option<(u8, usize, usize)> {
+    let b = *buf.get(pos)?;
+    if b & 0x80 == 0 {
+        return None;
+    }
+
+    if b & 0x40 != 0 {
+        // new format
+        let tag = b & 0x3f;
+        let l0 = *buf.get(pos + 1)? as usize;
+        match l0 {
+            0..=191 => Some((tag, pos + 2, l0)),
+            192..=223 => {
+                let l1 = *buf.get(pos + 2)? as usize;
+                Some((tag, pos + 3, ((l0 - 192) << 8) + l1 + 192))
+            }
+            255 => {
+                let b = buf.get(pos + 2..pos + 6)?;
+                let len = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+                Some((tag, pos + 6, len))
+            }
+            // partial body lengths never occur on session key packets
+            _ => None,
+        }
+    } else {
+        // old format
+        let tag = (b >> 2) & 0x0f;
+        match b & 0x03 {
+            0 => Some((tag, pos + 2, *buf.get(pos + 1)? as usize)),
+            1 => {
+                let b = buf.get(pos + 1..pos + 3)?;
+                Some((tag, pos + 3, u16::from_be_bytes([b[0], b[1]]) as usize))
+            }
+            2 => {
+                let b = buf.get(pos + 1..pos + 5)?;
+                let len = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+                Some((tag, pos + 5, len))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// List the session key packets at the start of an OpenPGP message
+pub fn session_key_packets(bin: &[u8]) -> Vec<Esk> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+
+    while let Some((tag, start, len)) = packet_header(bin, pos) {
+        let body = &bin[start..start + len];
+        match (tag, body.first()) {
+            // version(1) then key id(8) for v3 PKESK
+            (1, Some(3)) => {
+                if let Some(id) = body.get(1..9) {
+                    let mut key_id = [0u8; 8];
+                    key_id.copy_from_slice(id);
+                    out.push(Esk::PublicKey { key_id });
+                }
+            }
+            (3, Some(4 | 5)) => out.push(Esk::Symmetric),
+            // v6 (RFC 9580) and unknown versions are skipped, gpg reports
+            // what it makes of them
+            (1 | 3, _) => {}
+            _ => break,
+        }
+        pos = start + len;
+    }
+
+    out
+}
+
+/// Session key packets of an armored message, empty if it can't be parsed
+pub fn armored_session_key_packets(armored: &str) -> Vec<Esk> {
+    dearmor(armored)
+        .map(|bin| session_key_packets(&bin))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_ID: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    /// v3 PKESK body: version, key id, algorithm, a stand-in for the key
+    fn pkesk_body(version: u8) -> Vec<u8> {
+        let mut body = vec![version];
+        body.extend_from_slice(&KEY_ID);
+        body.extend_from_slice(&[18, 0xaa, 0xbb]);
+        body
+    }
+
+    fn new_format(tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut p = vec![0xc0 | tag];
+        match body.len() {
+            len @ 0..=191 => p.push(len as u8),
+            len @ 192..=8383 => {
+                let len = len - 192;
+                p.extend_from_slice(&[(len >> 8) as u8 + 192, len as u8]);
+            }
+            len => {
+                p.push(255);
+                p.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+        }
+        p.extend_from_slice(body);
+        p
+    }
+
+    fn old_format(tag: u8, body: &[u8]) -> Vec<u8> {
+        let mut p = vec![0x80 | (tag << 2) | 1];
+        p.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        p.extend_from_slice(body);
+        p
+    }
+
+    #[test]
+    fn new_format_lengths() {
+        for len in [0, 191, 192, 8383, 8384] {
+            let body = vec![0; len];
+            let p = new_format(3, &body);
+            let (tag, start, got) = packet_header(&p, 0).unwrap();
+            assert_eq!((tag, got), (3, len));
+            assert_eq!(start + got, p.len(), "len {len}");
+        }
+    }
+
+    #[test]
+    fn old_format_lengths() {
+        let (tag, start, len) = packet_header(&[0x84, 2, 0, 0], 0).unwrap();
+        assert_eq!((tag, start, len), (1, 2, 2));
+        let p = old_format(3, &[4; 300]);
+        assert_eq!(packet_header(&p, 0), Some((3, 3, 300)));
+        let mut p = vec![0x86, 0, 0, 0, 5];
+        p.extend_from_slice(&[0; 5]);
+        assert_eq!(packet_header(&p, 0), Some((1, 5, 5)));
+        // indeterminate length
+        assert_eq!(packet_header(&[0x87, 0, 0], 0), None);
+    }
+
+    #[test]
+    fn truncated_headers_and_bodies() {
+        let p = new_format(1, &pkesk_body(3));
+        for cut in 0..p.len() {
+            assert_eq!(packet_header(&p[..cut], 0), None, "cut at {cut}");
+            assert_eq!(session_key_packets(&p[..cut]), []);
+        }
+        // lengths running past the end
+        assert_eq!(packet_header(&[0xc1, 255, 0xff, 0xff, 0xff, 0xff], 0), None);
+        assert_eq!(packet_header(&[0x86, 0xff, 0xff, 0xff, 0xff], 0), None);
+        // not a packet tag
+        assert_eq!(packet_header(&[0x01, 0], 0), None);
+    }
+
+    #[test]
+    fn session_keys_in_order() {
+        let mut bin = new_format(1, &pkesk_body(3));
+        bin.extend(old_format(1, &{
+            let mut hidden = pkesk_body(3);
+            hidden[1..9].fill(0);
+            hidden
+        }));
+        bin.extend(new_format(3, &[4, 9, 2, 0xcc]));
+        // encrypted data ends the list
+        bin.extend(new_format(18, &[1, 2, 3]));
+        bin.extend(new_format(3, &[4, 9, 2, 0xcc]));
+
+        let esks = session_key_packets(&bin);
+        assert_eq!(
+            esks,
+            [
+                Esk::PublicKey { key_id: KEY_ID },
+                Esk::PublicKey { key_id: [0; 8] },
+                Esk::Symmetric,
+            ]
+        );
+        assert!(!esks[0].is_hidden() && esks[1].is_hidden());
+    }
+
+    #[test]
+    fn v6_packets_are_skipped() {
+        let mut bin = new_format(1, &pkesk_body(6));
+        bin.extend(new_format(3, &[6, 9, 2, 0xcc]));
+        bin.extend(new_format(1, &pkesk_body(3)));
+        assert_eq!(
+            session_key_packets(&bin),
+            [Esk::PublicKey { key_id: KEY_ID }]
+        );
+    }
+
+    #[test]
+    fn short_body_is_not_read_past() {
+        // the key id would run into the next packet
+        let mut bin = new_format(1, &[3, 1, 2, 3]);
+        bin.extend(new_format(3, &[4, 9, 2, 0xcc]));
+        assert_eq!(session_key_packets(&bin), [Esk::Symmetric]);
+    }
+}
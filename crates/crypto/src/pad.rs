@@ -0,0 +1,128 @@
+use common::SecretString;
+use std::fmt;
+
+/// Padded plaintext sizes in bytes. The largest still fits one Discord
+/// message as armor with an RSA-4096 recipient's key packet on top.
+const BUCKETS: &[usize] = &[128, 256, 512, 768];
+
+/// Starts padded plaintext, the text length and a newline follow, then
+/// the text and filler up to the bucket size. Other pgp-disc clients
+/// strip it, plain gpg users see one more line and the filler.
+pub const MAGIC: &str = "pgp-disc padded ";
+
+const FILLER: char = '.';
+
+/// Text too long for the largest bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLong {
+    /// Longest text that can be padded, in bytes
+    pub max: usize,
+}
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message too long to pad, at most {} bytes fit one Discord message; send it without padding",
+            self.max
+        )
+    }
+}
+
+impl std::error::Error for TooLong {}
+
+fn header(len: usize) -> String {
+    format!("{MAGIC}{len}\n")
+}
+
+/// Longest text [`pad`] takes
+pub fn max_len() -> usize {
+    let last = BUCKETS[BUCKETS.len() - 1];
+    let mut len = last;
+    while header(len).len() + len > last {
+        len -= 1;
+    }
+    len
+}
+
+/// `text` padded to the next bucket size
+pub fn pad(text: &str) -> Result<SecretString, TooLong> {
+    let header = header(text.len());
+    let size = BUCKETS
+        .iter()
+        .copied()
+        .find(|b| *b >= header.len() + text.len())
+        .ok_or(TooLong { max: max_len() })?;
+    let mut out = SecretString::new(String::with_capacity(size));
+    out.push_str(&header);
+    out.push_str(text);
+    let fill = size - out.len();
+    out.extend(std::iter::repeat_n(FILLER, fill));
+    Ok(out)
+}
+
+/// The text inside padded plaintext, `None` when `text` wasn't padded
+pub fn unpad(text: &str) -> Option<&str> {
+    let (len, rest) = text.strip_prefix(MAGIC)?.split_once('\n')?;
+    if len.is_empty() || !len.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let len: usize = len.parse().ok()?;
+    let inner = rest.get(..len)?;
+    rest[len..].chars().all(|c| c == FILLER).then_some(inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_to_buckets_and_back() {
+        for text in [
+            "",
+            "hi",
+            "héllo\nwörld",
+            &"x".repeat(200),
+            &"y".repeat(max_len()),
+        ] {
+            let padded = pad(text).unwrap();
+            assert!(BUCKETS.contains(&padded.len()), "{}", padded.len());
+            assert_eq!(unpad(&padded), Some(text));
+        }
+        assert_eq!(pad("hi").unwrap().len(), 128);
+        assert_eq!(pad(&"x".repeat(200)).unwrap().len(), 256);
+    }
+
+    #[test]
+    fn too_long_fails_before_encrypting() {
+        let max = max_len();
+        assert!(pad(&"x".repeat(max)).is_ok());
+        assert_eq!(pad(&"x".repeat(max + 1)).unwrap_err(), TooLong { max });
+    }
+
+    #[test]
+    fn unframed_text_is_left_alone() {
+        for text in [
+            "",
+            "plain message",
+            "ends like old padding\n-- pgp-disc padding --....",
+            "trailing dots...",
+            "pgp-disc padded",
+            "pgp-disc padded \nx",
+            "pgp-disc padded x\nabc",
+            "pgp-disc padded +3\nabc",
+            "pgp-disc padded 10\nabc",
+            "pgp-disc padded 3\nabcdef",
+            "pgp-disc padded 3\nabc.. and more",
+        ] {
+            assert_eq!(unpad(text), None, "{text:?}");
+        }
+        assert_eq!(unpad("pgp-disc padded 3\nabc"), Some("abc"));
+        assert_eq!(unpad("pgp-disc padded 3\nab..."), Some("ab."));
+    }
+
+    #[test]
+    fn length_inside_a_character() {
+        assert_eq!(unpad("pgp-disc padded 1\né...."), None);
+    }
+}
@@ -0,0 +1,595 @@
+use base64::Engine as _;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Starts a session message on the wire: `pgp~s <sid> <base64>`
+pub const MAGIC: &str = "pgp~s ";
+
+/// Starts a key exchange line, clearsigned by the sender's PGP key
+pub const KEY_EXCHANGE: &str = "pgp-disc session";
+
+/// Message keys kept for messages that arrive out of order
+const MAX_SKIP: u32 = 100;
+
+// sending ratchet keys remembered to recognize our own messages
+const OWN_KEYS: usize = 8;
+
+const HEADER_LEN: usize = 32 + 4 + 4;
+
+type Key = Zeroizing<[u8; 32]>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RatchetError {
+    /// Not a session message
+    Malformed,
+    /// Tampered with, or for keys that are gone
+    Undecryptable,
+    /// More than `MAX_SKIP` messages missing in between
+    TooFarAhead,
+    /// Sent by us, a sending chain can't be read back
+    Own,
+}
+
+impl fmt::Display for RatchetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RatchetError::Malformed => write!(f, "malformed session message"),
+            RatchetError::Undecryptable => write!(f, "can't decrypt, keys already used or gone"),
+            RatchetError::TooFarAhead => write!(f, "too many messages missing"),
+            RatchetError::Own => write!(f, "sent by you"),
+        }
+    }
+}
+
+impl std::error::Error for RatchetError {}
+
+const SID_LEN: usize = 8;
+
+/// Random id naming a session on the wire
+pub fn new_session_id() -> String {
+    let mut id = [0u8; SID_LEN];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+/// Whether `sid` looks like one from [`new_session_id`], anything else
+/// off the wire is dropped before it gets near the screen
+pub fn is_session_id(sid: &str) -> bool {
+    sid.len() == SID_LEN * 2 && sid.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Our X25519 key of a key exchange in progress
+#[derive(Clone)]
+pub struct Handshake {
+    secret: StaticSecret,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        Self {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    pub fn public(&self) -> [u8; 32] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handshake({})", hex::encode(self.public()))
+    }
+}
+
+/// Key exchange line, `to` is the primary fingerprint of the peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyExchange {
+    Init {
+        sid: String,
+        to: String,
+        key: [u8; 32],
+    },
+    Accept {
+        sid: String,
+        to: String,
+        key: [u8; 32],
+    },
+}
+
+impl KeyExchange {
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut f = text.trim().strip_prefix(KEY_EXCHANGE)?.split_whitespace();
+        let kind = f.next()?;
+        let sid = f.next().filter(|s| is_session_id(s))?.to_string();
+        let to = f.next()?.to_uppercase();
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(f.next()?)
+            .ok()?
+            .try_into()
+            .ok()?;
+        if f.next().is_some() {
+            return None;
+        }
+        match kind {
+            "init" => Some(KeyExchange::Init { sid, to, key }),
+            "accept" => Some(KeyExchange::Accept { sid, to, key }),
+            _ => None,
+        }
+    }
+
+    pub fn sid(&self) -> &str {
+        match self {
+            KeyExchange::Init { sid, .. } | KeyExchange::Accept { sid, .. } => sid,
+        }
+    }
+}
+
+impl fmt::Display for KeyExchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, sid, to, key) = match self {
+            KeyExchange::Init { sid, to, key } => ("init", sid, to, key),
+            KeyExchange::Accept { sid, to, key } => ("accept", sid, to, key),
+        };
+        let key = base64::engine::general_purpose::STANDARD.encode(key);
+        write!(f, "{KEY_EXCHANGE} {kind} {sid} {to} {key}")
+    }
+}
+
+/// `data` as posted in chat
+pub fn encode(sid: &str, data: &[u8]) -> String {
+    let data = base64::engine::general_purpose::STANDARD.encode(data);
+    format!("{MAGIC}{sid} {data}")
+}
+
+/// Session id and data of a session message, `None` for anything else
+pub fn decode(text: &str) -> Option<(&str, Vec<u8>)> {
+    let (sid, data) = text.trim().strip_prefix(MAGIC)?.split_once(' ')?;
+    if !is_session_id(sid) {
+        return None;
+    }
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(data.trim())
+        .ok()?;
+    Some((sid, data))
+}
+
+fn kdf_rk(rk: &[u8; 32], dh: &[u8; 32]) -> (Key, Key) {
+    let mut out = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(Some(rk), dh)
+        .expand(b"pgp-disc ratchet", out.as_mut())
+        .expect("64 bytes is a valid HKDF-SHA256 length");
+    let (mut rk, mut ck) = (Key::default(), Key::default());
+    rk.copy_from_slice(&out[..32]);
+    ck.copy_from_slice(&out[32..]);
+    (rk, ck)
+}
+
+/// Message key and next chain key
+fn kdf_ck(ck: &[u8; 32]) -> (Key, Key) {
+    let step = |b: u8| {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(ck).expect("HMAC takes any key length");
+        mac.update(&[b]);
+        let mut k = Key::default();
+        k.copy_from_slice(&mac.finalize().into_bytes());
+        k
+    };
+    (step(1), step(2))
+}
+
+fn dh(ours: &StaticSecret, theirs: &PublicKey) -> Key {
+    Zeroizing::new(ours.diffie_hellman(theirs).to_bytes())
+}
+
+/// One end of a double ratchet: every message has its own key, keys are
+/// dropped once used and every reply mixes in fresh X25519 keys, so
+/// neither a later leak of the PGP keys nor of this state opens older
+/// messages.
+#[derive(Clone)]
+pub struct Ratchet {
+    sid: String,
+    root: Key,
+    dhs: StaticSecret,
+    dhr: PublicKey,
+    cks: Key,
+    ckr: Option<Key>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: HashMap<([u8; 32], u32), Key>,
+    own: VecDeque<[u8; 32]>,
+}
+
+impl fmt::Debug for Ratchet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ratchet({}, sent {}, received {})",
+            self.sid, self.ns, self.nr
+        )
+    }
+}
+
+fn session_key(sid: &str, ours: &StaticSecret, theirs: &PublicKey) -> Key {
+    let mut sk = Key::default();
+    Hkdf::<Sha256>::new(Some(sid.as_bytes()), dh(ours, theirs).as_ref())
+        .expand(b"pgp-disc session", sk.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    sk
+}
+
+impl Ratchet {
+    /// Our side after sending `init` and getting `accept` with `theirs`
+    pub fn initiator(sid: &str, hs: Handshake, theirs: [u8; 32]) -> Self {
+        let theirs = PublicKey::from(theirs);
+        let sk = session_key(sid, &hs.secret, &theirs);
+        // the accept key is the peer's first ratchet key
+        let (root, ckr) = kdf_rk(&sk, &dh(&hs.secret, &theirs));
+        let dhs = StaticSecret::random_from_rng(OsRng);
+        let (root, cks) = kdf_rk(&root, &dh(&dhs, &theirs));
+        let mut r = Self {
+            sid: sid.to_string(),
+            root,
+            dhs,
+            dhr: theirs,
+            cks,
+            ckr: Some(ckr),
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: HashMap::new(),
+            own: VecDeque::new(),
+        };
+        r.remember_own();
+        r
+    }
+
+    /// Our side after getting `init` with `theirs` and accepting with `hs`
+    pub fn responder(sid: &str, hs: Handshake, theirs: [u8; 32]) -> Self {
+        let theirs = PublicKey::from(theirs);
+        let sk = session_key(sid, &hs.secret, &theirs);
+        let (root, cks) = kdf_rk(&sk, &dh(&hs.secret, &theirs));
+        let mut r = Self {
+            sid: sid.to_string(),
+            root,
+            dhs: hs.secret,
+            dhr: theirs,
+            cks,
+            ckr: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: HashMap::new(),
+            own: VecDeque::new(),
+        };
+        r.remember_own();
+        r
+    }
+
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    fn remember_own(&mut self) {
+        self.own.push_back(PublicKey::from(&self.dhs).to_bytes());
+        while self.own.len() > OWN_KEYS {
+            self.own.pop_front();
+        }
+    }
+
+    /// Header and ciphertext of the next message
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let (mk, next) = kdf_ck(&self.cks);
+        self.cks = next;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(PublicKey::from(&self.dhs).as_bytes());
+        header.extend_from_slice(&self.pn.to_be_bytes());
+        header.extend_from_slice(&self.ns.to_be_bytes());
+        self.ns += 1;
+
+        let ct = seal(&mk, &self.ad(&header), plaintext);
+        header.extend_from_slice(&ct);
+        header
+    }
+
+    /// Plaintext of a message, the state only moves on when it decrypts
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>, RatchetError> {
+        let mut next = self.clone();
+        let pt = next.decrypt_in_place(data)?;
+        *self = next;
+        Ok(pt)
+    }
+
+    fn decrypt_in_place(&mut self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>, RatchetError> {
+        if data.len() < HEADER_LEN {
+            return Err(RatchetError::Malformed);
+        }
+        let (header, ct) = data.split_at(HEADER_LEN);
+        let mut dh_pub = [0u8; 32];
+        dh_pub.copy_from_slice(&header[..32]);
+        let pn = u32::from_be_bytes([header[32], header[33], header[34], header[35]]);
+        let n = u32::from_be_bytes([header[36], header[37], header[38], header[39]]);
+        let ad = self.ad(header);
+
+        if self.own.contains(&dh_pub) {
+            return Err(RatchetError::Own);
+        }
+        if let Some(mk) = self.skipped.remove(&(dh_pub, n)) {
+            return open(&mk, &ad, ct);
+        }
+
+        if dh_pub != self.dhr.to_bytes() {
+            self.skip(pn)?;
+            self.step(PublicKey::from(dh_pub));
+        }
+        self.skip(n)?;
+        let ckr = self.ckr.as_ref().ok_or(RatchetError::Undecryptable)?;
+        let (mk, next) = kdf_ck(ckr);
+        self.ckr = Some(next);
+        self.nr += 1;
+        open(&mk, &ad, ct)
+    }
+
+    /// Keep the keys of receiving chain messages before `until`
+    fn skip(&mut self, until: u32) -> Result<(), RatchetError> {
+        if self.nr + MAX_SKIP < until {
+            return Err(RatchetError::TooFarAhead);
+        }
+        if let Some(mut ck) = self.ckr.clone() {
+            while self.nr < until {
+                let (mk, next) = kdf_ck(&ck);
+                self.skipped.insert((self.dhr.to_bytes(), self.nr), mk);
+                ck = next;
+                self.nr += 1;
+            }
+            self.ckr = Some(ck);
+        }
+        // oldest first would be nicer, but any bound keeps this small
+        while self.skipped.len() > MAX_SKIP as usize {
+            let Some(k) = self.skipped.keys().next().copied() else {
+                break;
+            };
+            self.skipped.remove(&k);
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, theirs: PublicKey) {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dhr = theirs;
+        let (root, ckr) = kdf_rk(&self.root, &dh(&self.dhs, &self.dhr));
+        self.dhs = StaticSecret::random_from_rng(OsRng);
+        let (root, cks) = kdf_rk(&root, &dh(&self.dhs, &self.dhr));
+        self.root = root;
+        self.ckr = Some(ckr);
+        self.cks = cks;
+        self.remember_own();
+    }
+
+    fn ad(&self, header: &[u8]) -> Vec<u8> {
+        let mut ad = self.sid.as_bytes().to_vec();
+        ad.extend_from_slice(header);
+        ad
+    }
+}
+
+// every message key is used once, so a fixed nonce is safe
+const NONCE: [u8; 12] = [0; 12];
+
+fn seal(mk: &[u8; 32], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(mk.into())
+        .encrypt(
+            &NONCE.into(),
+            Payload {
+                msg: plaintext,
+                aad: ad,
+            },
+        )
+        .expect("ChaCha20-Poly1305 encryption doesn't fail")
+}
+
+fn open(mk: &[u8; 32], ad: &[u8], ct: &[u8]) -> Result<Zeroizing<Vec<u8>>, RatchetError> {
+    ChaCha20Poly1305::new(mk.into())
+        .decrypt(&NONCE.into(), Payload { msg: ct, aad: ad })
+        .map(Zeroizing::new)
+        .map_err(|_| RatchetError::Undecryptable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SID: &str = "0123456789abcdef";
+
+    /// Initiator and responder of a fresh session
+    fn pair() -> (Ratchet, Ratchet) {
+        let (a, b) = (Handshake::new(), Handshake::new());
+        let (pa, pb) = (a.public(), b.public());
+        (
+            Ratchet::initiator(SID, a, pb),
+            Ratchet::responder(SID, b, pa),
+        )
+    }
+
+    fn read(r: &mut Ratchet, data: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        r.decrypt(data).map(|pt| pt.to_vec())
+    }
+
+    #[test]
+    fn round_trip_both_directions() {
+        let (mut a, mut b) = pair();
+
+        // the responder can talk before hearing back
+        let m = b.encrypt(b"hi from b");
+        assert_eq!(read(&mut a, &m).unwrap(), b"hi from b");
+
+        for i in 0..3 {
+            let m = a.encrypt(format!("a {i}").as_bytes());
+            assert_eq!(read(&mut b, &m).unwrap(), format!("a {i}").as_bytes());
+            let m = b.encrypt(format!("b {i}").as_bytes());
+            assert_eq!(read(&mut a, &m).unwrap(), format!("b {i}").as_bytes());
+        }
+    }
+
+    #[test]
+    fn initiator_can_talk_first() {
+        let (mut a, mut b) = pair();
+        let m = a.encrypt(b"first");
+        assert_eq!(read(&mut b, &m).unwrap(), b"first");
+    }
+
+    #[test]
+    fn out_of_order_and_skipped_messages() {
+        let (mut a, mut b) = pair();
+        let m: Vec<_> = (0..4).map(|i| b.encrypt(&[i])).collect();
+
+        assert_eq!(read(&mut a, &m[2]).unwrap(), [2]);
+        assert_eq!(read(&mut a, &m[0]).unwrap(), [0]);
+        assert_eq!(read(&mut a, &m[3]).unwrap(), [3]);
+        assert_eq!(read(&mut a, &m[1]).unwrap(), [1]);
+
+        // a late message of the chain before a ratchet step
+        let late = a.encrypt(b"late");
+        let m = a.encrypt(b"early");
+        assert_eq!(read(&mut b, &m).unwrap(), b"early");
+        let reply = b.encrypt(b"reply");
+        assert_eq!(read(&mut a, &reply).unwrap(), b"reply");
+        let next = a.encrypt(b"next");
+        assert_eq!(read(&mut b, &next).unwrap(), b"next");
+        assert_eq!(read(&mut b, &late).unwrap(), b"late");
+    }
+
+    #[test]
+    fn too_many_missing_messages() {
+        let (mut a, mut b) = pair();
+        let m: Vec<_> = (0..MAX_SKIP + 2).map(|_| b.encrypt(b"x")).collect();
+        assert_eq!(
+            read(&mut a, m.last().unwrap()),
+            Err(RatchetError::TooFarAhead)
+        );
+        assert_eq!(read(&mut a, &m[0]).unwrap(), b"x");
+    }
+
+    #[test]
+    fn tampered_message_leaves_state_alone() {
+        let (mut a, mut b) = pair();
+        let m = b.encrypt(b"secret");
+
+        let mut bad = m.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        assert_eq!(read(&mut a, &bad), Err(RatchetError::Undecryptable));
+        let mut bad = m.clone();
+        bad[HEADER_LEN - 1] ^= 1;
+        assert_eq!(read(&mut a, &bad), Err(RatchetError::Undecryptable));
+        assert_eq!(
+            read(&mut a, &m[..HEADER_LEN - 1]),
+            Err(RatchetError::Malformed)
+        );
+
+        assert_eq!(read(&mut a, &m).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn replay_is_rejected() {
+        let (mut a, mut b) = pair();
+        let m0 = b.encrypt(b"once");
+        let m1 = b.encrypt(b"twice");
+        assert_eq!(read(&mut a, &m1).unwrap(), b"twice");
+        assert_eq!(read(&mut a, &m0).unwrap(), b"once");
+
+        assert_eq!(read(&mut a, &m0), Err(RatchetError::Undecryptable));
+        assert_eq!(read(&mut a, &m1), Err(RatchetError::Undecryptable));
+    }
+
+    #[test]
+    fn own_messages_are_recognized() {
+        let (mut a, mut b) = pair();
+        let m = b.encrypt(b"mine");
+        assert_eq!(read(&mut b, &m), Err(RatchetError::Own));
+        assert_eq!(read(&mut a, &m).unwrap(), b"mine");
+    }
+
+    #[test]
+    fn other_session_id_doesnt_decrypt() {
+        let (mut a, _) = pair();
+        let (_, mut b) = pair();
+        let m = b.encrypt(b"elsewhere");
+        assert!(read(&mut a, &m).is_err());
+    }
+
+    #[test]
+    fn key_exchange_round_trip() {
+        let key = Handshake::new().public();
+        let init = KeyExchange::Init {
+            sid: SID.to_string(),
+            to: "ABCDEF0123".to_string(),
+            key,
+        };
+        assert_eq!(KeyExchange::parse(&init.to_string()), Some(init));
+
+        let accept = format!(
+            "{KEY_EXCHANGE} accept {SID} abcdef0123 {}\n",
+            base64::engine::general_purpose::STANDARD.encode(key)
+        );
+        assert_eq!(
+            KeyExchange::parse(&accept),
+            Some(KeyExchange::Accept {
+                sid: SID.to_string(),
+                to: "ABCDEF0123".to_string(),
+                key,
+            })
+        );
+    }
+
+    #[test]
+    fn key_exchange_parse_failures() {
+        let key = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        let short = base64::engine::general_purpose::STANDARD.encode([7u8; 31]);
+        for text in [
+            format!("pgp-disc sessions init {SID} ABCD {key}"),
+            format!("{KEY_EXCHANGE} offer {SID} ABCD {key}"),
+            format!("{KEY_EXCHANGE} init 日日日 ABCD {key}"),
+            format!("{KEY_EXCHANGE} init {SID} ABCD {short}"),
+            format!("{KEY_EXCHANGE} init {SID} ABCD not*base64"),
+            format!("{KEY_EXCHANGE} init {SID} ABCD {key} extra"),
+            format!("{KEY_EXCHANGE} init {SID} ABCD"),
+            format!("{KEY_EXCHANGE} init"),
+            String::new(),
+        ] {
+            assert_eq!(KeyExchange::parse(&text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn decode_rejects_malformed_session_ids() {
+        let data = encode("0123456789abcdef", b"x");
+        assert_eq!(decode(&data).map(|(sid, _)| sid), Some("0123456789abcdef"));
+
+        for sid in [
+            "日日日",
+            "0123456789ABCDEF",
+            "0123456789abcde",
+            "0123456789abcdef0",
+            "0123456789abcdeg",
+        ] {
+            assert_eq!(decode(&format!("{MAGIC}{sid} AAAA")), None, "{sid}");
+        }
+    }
+}
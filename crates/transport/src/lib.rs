@@ -13,10 +13,15 @@ use twilight_model::id::{
 
 #[derive(Clone, Debug)]
 pub struct ChatEvent {
+    pub message_id: u64,
     pub channel_id: u64,
     pub author_id: u64,
     pub author: String,
     pub content: String,
+    /// Unix timestamp (seconds) the message was sent at
+    pub timestamp: i64,
+    /// Ids of mentioned users
+    pub mentions: Vec<u64>,
 }
 
 /// Starts a Discord Gateway connection.
@@ -97,23 +102,44 @@ pub async fn fetch_messages(token: &str, channel_id: u64, limit: usize) -> Resul
     Ok(out
         .into_iter()
         .map(|m| ChatEvent {
+            message_id: m.id.get(),
             channel_id: m.channel_id.get(),
             author_id: m.author.id.get(),
             author: m.author.name,
             content: m.content,
+            timestamp: m.timestamp.as_secs(),
+            mentions: m.mentions.iter().map(|u| u.id.get()).collect(),
         })
         .collect())
 }
 
 fn convert_message_create(msg: MessageCreate) -> ChatEvent {
     ChatEvent {
+        message_id: msg.id.get(),
         channel_id: msg.channel_id.get(),
         author_id: msg.author.id.get(),
         author: msg.author.name.clone(),
         content: msg.content.clone(),
+        timestamp: msg.timestamp.as_secs(),
+        mentions: msg.mentions.iter().map(|u| u.id.get()).collect(),
     }
 }
 
+/// Id of the user `token` logs in as
+pub async fn current_user_id(token: &str) -> Result<u64> {
+    let http = HttpClient::new(token.to_string());
+
+    let me = http
+        .current_user()
+        .await
+        .map_err(|e| anyhow!("Discord HTTP error: {e}"))?
+        .model()
+        .await
+        .map_err(|e| anyhow!("Discord HTTP model error: {e}"))?;
+
+    Ok(me.id.get())
+}
+
 /// Send a message to a channel using the Discord REST API.
 pub async fn send_message(token: &str, channel_id: u64, content: &str) -> Result<()> {
     let http = HttpClient::new(token.to_string());